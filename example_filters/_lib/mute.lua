@@ -0,0 +1,12 @@
+-- Helpers shared among the mute filters. Load with `require "mute"`.
+local mute = {}
+
+-- Returns the first word found in the text, ignoring the case, the width and the kind of kana.
+function mute.find_word(text, words)
+  if not text then
+    return nil
+  end
+  return binchotan.contains_any(text, words, { ignore_case = true, normalize = true })
+end
+
+return mute
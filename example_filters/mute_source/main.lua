@@ -1,12 +1,5 @@
-if post.source:find "Peing" or
-   post.source:find "ツイ廃あらーと" or
-   post.source:find "今日のツイライフ" or
-   post.source:find "ツイ廃ジャー" or
-   post.source:find "相談箱" or
-   post.source:find "contributter" or
-   post.source:find "twttbot.net" or
-   post.source:find "Githubiter" then
+if post.source and binchotan.contains_any(post.source, config.sources) then
   return nil
-else
-  return post
 end
+
+return post
@@ -1,6 +1,7 @@
-if post.text:find "大学" or post.text:find "ツイッター" then
+local mute = require "mute"
+
+if mute.find_word(post.text, config.words) then
   return nil
-else
-  return post
 end
 
+return post
@@ -0,0 +1,6 @@
+-- e.g. 090-1234-5678, 03 1234 5678
+local text = request.body.text or ""
+if text:find "%d%d%d?%d?[-%s]%d%d%d%d?[-%s]%d%d%d%d" then
+  hook.confirm "This tweet seems to contain a phone number. Post it anyway?"
+end
+return request.body
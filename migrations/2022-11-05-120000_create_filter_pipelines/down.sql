@@ -0,0 +1 @@
+drop table filter_pipelines
\ No newline at end of file
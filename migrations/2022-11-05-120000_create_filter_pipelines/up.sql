@@ -0,0 +1,7 @@
+create table filter_pipelines (
+  account_id integer not null references accounts (id) on delete cascade,
+  filter_id text not null,
+  position integer not null,
+  enabled boolean not null,
+  primary key (account_id, filter_id)
+);
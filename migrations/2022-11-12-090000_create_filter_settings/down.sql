@@ -0,0 +1 @@
+drop table filter_settings
\ No newline at end of file
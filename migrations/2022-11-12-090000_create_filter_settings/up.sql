@@ -0,0 +1,6 @@
+create table filter_settings (
+  account_id integer not null references accounts (id) on delete cascade,
+  filter_id text not null,
+  settings jsonb not null,
+  primary key (account_id, filter_id)
+);
@@ -0,0 +1 @@
+drop table filter_storage
\ No newline at end of file
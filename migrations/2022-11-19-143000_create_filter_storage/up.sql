@@ -0,0 +1,8 @@
+create table filter_storage (
+  account_id integer not null references accounts (id) on delete cascade,
+  filter_id text not null,
+  key text not null,
+  value jsonb not null,
+  expires_at bigint,
+  primary key (account_id, filter_id, key)
+);
@@ -0,0 +1 @@
+drop table filter_rules
\ No newline at end of file
@@ -0,0 +1 @@
+drop table installed_filters
\ No newline at end of file
@@ -0,0 +1,7 @@
+create table installed_filters (
+  filter_id text primary key,
+  version text,
+  hash text not null,
+  scopes text[] not null,
+  installed_at timestamptz not null default now()
+);
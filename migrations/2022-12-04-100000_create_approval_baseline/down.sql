@@ -0,0 +1 @@
+drop table approval_baseline
\ No newline at end of file
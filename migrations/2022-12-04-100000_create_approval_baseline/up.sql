@@ -0,0 +1,4 @@
+-- has a row once the filters present before approvals were introduced have been approved as they were
+create table approval_baseline (
+  recorded_at timestamptz not null default now()
+);
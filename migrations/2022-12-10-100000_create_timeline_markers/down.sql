@@ -0,0 +1 @@
+drop table timeline_markers
\ No newline at end of file
@@ -0,0 +1,8 @@
+create table timeline_markers (
+  account_id integer not null references accounts (id) on delete cascade,
+  timeline text not null,
+  newest_id text,
+  read_id text,
+  unread text[] not null default '{}',
+  primary key (account_id, timeline)
+);
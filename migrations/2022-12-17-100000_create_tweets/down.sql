@@ -0,0 +1,3 @@
+drop table timeline_entries;
+drop table tweet_includes;
+drop table tweets;
\ No newline at end of file
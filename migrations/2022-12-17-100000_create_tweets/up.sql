@@ -0,0 +1,21 @@
+create table tweets (
+  id bigint primary key,
+  data jsonb not null,
+  fetched_at timestamptz not null default now()
+);
+
+create table tweet_includes (
+  kind text not null,
+  key text not null,
+  data jsonb not null,
+  fetched_at timestamptz not null default now(),
+  primary key (kind, key)
+);
+
+create table timeline_entries (
+  account_id integer not null references accounts (id) on delete cascade,
+  timeline text not null,
+  tweet_id bigint not null references tweets (id) on delete cascade,
+  seen_at timestamptz not null default now(),
+  primary key (account_id, timeline, tweet_id)
+);
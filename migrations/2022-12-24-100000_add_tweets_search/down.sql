@@ -0,0 +1,3 @@
+drop index tweets_search;
+
+alter table tweets drop column search;
\ No newline at end of file
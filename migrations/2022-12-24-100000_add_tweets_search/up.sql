@@ -0,0 +1,3 @@
+alter table tweets add column search tsvector;
+
+create index tweets_search on tweets using gin (search);
@@ -0,0 +1,122 @@
+use mlua::prelude::*;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use crate::modules::Modules;
+
+/// What a filter tells about a post, other than whether to keep it. Frontends can render these, e.g. by hiding the post behind a content warning.
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct Annotation {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub labels: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub score: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// Annotations given by a script during a run, keyed by post ids.
+#[derive(Debug, Default)]
+pub struct Annotations(HashMap<String, Annotation>);
+
+impl Annotations {
+    pub fn take(&mut self, post_id: &str) -> Option<Annotation> {
+        self.0.remove(post_id)
+    }
+
+    /// Creates the `annotate` table exposed to Lua scripts. Each function takes the post to annotate as an optional last argument, which defaults to `post`.
+    pub fn create_lua_table(
+        lua: &Lua,
+        annotations: Arc<Mutex<Annotations>>,
+    ) -> LuaResult<LuaTable<'_>> {
+        let table = lua.create_table()?;
+
+        let a = annotations.clone();
+        table.set(
+            "label",
+            lua.create_function(move |lua, (label, post): (String, Option<LuaTable>)| {
+                let id = post_id(lua, post)?;
+                let mut annotations = a.lock().unwrap();
+                let labels = &mut annotations.0.entry(id).or_default().labels;
+                if !labels.contains(&label) {
+                    labels.push(label);
+                }
+                Ok(())
+            })?,
+        )?;
+
+        let a = annotations.clone();
+        table.set(
+            "score",
+            lua.create_function(move |lua, (score, post): (f64, Option<LuaTable>)| {
+                let id = post_id(lua, post)?;
+                a.lock().unwrap().0.entry(id).or_default().score = Some(score);
+                Ok(())
+            })?,
+        )?;
+
+        table.set(
+            "reason",
+            lua.create_function(move |lua, (reason, post): (String, Option<LuaTable>)| {
+                let id = post_id(lua, post)?;
+                annotations.lock().unwrap().0.entry(id).or_default().reason = Some(reason);
+                Ok(())
+            })?,
+        )?;
+
+        Ok(table)
+    }
+}
+
+fn post_id(lua: &Lua, post: Option<LuaTable>) -> LuaResult<String> {
+    let post = match post {
+        Some(post) => post,
+        None => Modules::env(lua)?
+            .get::<_, LuaTable>("post")
+            .map_err(|_| LuaError::RuntimeError("annotate: no post is given to annotate".into()))?,
+    };
+    post.get("id")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn annotate_from_lua() -> LuaResult<()> {
+        let annotations = Arc::new(Mutex::new(Annotations::default()));
+        let lua = Lua::new();
+        let table = Annotations::create_lua_table(&lua, annotations.clone())?;
+        lua.globals().set("annotate", table)?;
+
+        lua.load(
+            r#"
+            post = { id = "1" }
+            annotate.label("cw")
+            annotate.label("cw")
+            annotate.score(0.5)
+            annotate.reason("spoiler", { id = "2" })
+            "#,
+        )
+        .exec()?;
+
+        let mut annotations = annotations.lock().unwrap();
+        assert_eq!(
+            annotations.take("1"),
+            Some(Annotation {
+                labels: vec!["cw".into()],
+                score: Some(0.5),
+                reason: None,
+            })
+        );
+        assert_eq!(
+            annotations.take("2").and_then(|a| a.reason),
+            Some("spoiler".into())
+        );
+
+        Ok(())
+    }
+}
@@ -12,8 +12,12 @@ use tracing::debug;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct HomeTimelineResponseBody {
+    // absent when nothing matched, e.g. in search results
+    #[serde(default)]
     pub data: Vec<Tweet>,
     pub includes: Option<serde_json::Value>,
+    // some endpoints such as `tweets` do not return this
+    #[serde(default)]
     pub meta: serde_json::Value,
 }
 
@@ -33,6 +37,17 @@ pub enum ApiClientError {
     Http(#[from] reqwest::Error),
 }
 
+impl ApiClientError {
+    /// Whether the API could not be reached or refused to respond for now, e.g. when offline or the rate limit has been exhausted.
+    pub fn is_unavailable(&self) -> bool {
+        match self {
+            ApiClientError::Http(err) => !err.is_status(),
+            ApiClientError::RespStatus(status, _) => *status == 429 || *status >= 500,
+            _ => false,
+        }
+    }
+}
+
 pub struct ApiClient {
     client: Client,
     pub user_id: String,
@@ -85,15 +100,14 @@ impl ApiClient {
         Ok(id)
     }
 
-    /// Calls `users/:id/timelines/reverse_chronological` endpoint to fetch the home timeline of the user. Returns the response body, the remaining calls (`x-rate-limit-remaining`), and the end of the current rate-limiting time window in epoch seconds (`x-rate-limit-reset`), in this order.
-    pub async fn timeline(
+    /// Calls an endpoint which returns a list of tweets in `data` with GET, e.g. `users/:id/timelines/reverse_chronological` to fetch the home timeline of the user. `:id` in `users/:id/...` is replaced with the id of the authenticating user, while other paths such as `lists/:id/tweets` are sent as they are. Returns the response body, the remaining calls (`x-rate-limit-remaining`), and the end of the current rate-limiting time window in epoch seconds (`x-rate-limit-reset`), in this order.
+    pub async fn tweets(
         &self,
-        params: &mut HashMap<String, serde_json::Value>,
+        endpoint_path: &str,
+        params: &HashMap<String, serde_json::Value>,
     ) -> Result<(HomeTimelineResponseBody, usize, usize), ApiClientError> {
-        let endpoint = format!(
-            "https://api.twitter.com/2/users/{}/timelines/reverse_chronological",
-            self.user_id
-        );
+        let path = user_path(endpoint_path, &self.user_id);
+        let endpoint = format!("https://api.twitter.com/2/{}", path.trim_matches('/'));
 
         let resp = self
             .client
@@ -116,6 +130,12 @@ impl ApiClient {
                 let content: serde_json::Value =
                     serde_json::from_str(&json).map_err(ApiClientError::RespParse)?;
                 debug!("{:?}", content);
+                if !content["data"].is_array() && !content["data"].is_null() {
+                    return Err(ApiClientError::RespParamNotFound(
+                        "data (a list of tweets)".into(),
+                        content,
+                    ));
+                }
                 let body: HomeTimelineResponseBody =
                     serde_json::value::from_value(content).map_err(ApiClientError::RespParse)?;
                 Ok((body, remaining, reset))
@@ -124,14 +144,14 @@ impl ApiClient {
         }
     }
 
-    /// Calls an arbitrary endpoint with the method and the parameters given in the arguments. Path parameters such as `:id` are replace with those of the authenticating user. Returns the response body, the remaining calls (`x-rate-limit-remaining`), and the end of the current rate-limiting time window in epoch seconds (`x-rate-limit-reset`), in this order.
+    /// Calls an arbitrary endpoint with the method and the parameters given in the arguments. `:id` in `users/:id/...` is replaced with the id of the authenticating user, while other paths such as `lists/:id/tweets` are sent as they are. Returns the response body, the remaining calls (`x-rate-limit-remaining`), and the end of the current rate-limiting time window in epoch seconds (`x-rate-limit-reset`), in this order.
     pub async fn call(
         &self,
         method: &HttpMethod,
         endpoint_path: &str,
         body: String,
     ) -> Result<(serde_json::Value, usize, usize), ApiClientError> {
-        let path = endpoint_path.replace(":id", &self.user_id);
+        let path = user_path(endpoint_path, &self.user_id);
         let endpoint = format!("https://api.twitter.com/2/{}", path);
         let resp = self
             .client
@@ -160,6 +180,66 @@ impl ApiClient {
         }
     }
 
+    /// Fetches a single tweet with the expansions needed to hydrate it. Returns the tweet and the `includes` object, if any.
+    pub async fn tweet(
+        &self,
+        id: &str,
+    ) -> Result<(Tweet, Option<serde_json::Value>), ApiClientError> {
+        let params = [
+            (
+                "expansions".to_owned(),
+                "author_id,attachments.media_keys,referenced_tweets.id".to_owned(),
+            ),
+            (
+                "tweet.fields".to_owned(),
+                "created_at,entities,lang,source".to_owned(),
+            ),
+        ];
+        let (mut content, _, _) = self.get(&format!("tweets/{}", id), &params).await?;
+
+        let data = content["data"].take();
+        if data.is_null() {
+            return Err(ApiClientError::RespParamNotFound("data".into(), content));
+        }
+        let tweet = serde_json::value::from_value(data).map_err(ApiClientError::RespParse)?;
+        let includes = content.get_mut("includes").map(serde_json::Value::take);
+        Ok((tweet, includes))
+    }
+
+    /// Calls an endpoint with GET, passing the parameters as the query string. `:id` in `users/:id/...` is replaced with the id of the authenticating user, while other paths such as `lists/:id/tweets` are sent as they are. Returns the response body, the remaining calls (`x-rate-limit-remaining`), and the end of the current rate-limiting time window in epoch seconds (`x-rate-limit-reset`), in this order.
+    pub async fn get(
+        &self,
+        endpoint_path: &str,
+        params: &[(String, String)],
+    ) -> Result<(serde_json::Value, usize, usize), ApiClientError> {
+        let path = user_path(endpoint_path, &self.user_id);
+        let endpoint = format!("https://api.twitter.com/2/{}", path);
+        let resp = self
+            .client
+            .get(endpoint)
+            .query(params)
+            .bearer_auth(self.access_token.to_owned())
+            .send()
+            .await?;
+        let status = resp.status();
+
+        let remaining = Self::get_header(&resp, "x-rate-limit-remaining")
+            .map_err(ApiClientError::RespHeader)?;
+        let reset =
+            Self::get_header(&resp, "x-rate-limit-reset").map_err(ApiClientError::RespHeader)?;
+        let json = resp.text().await?;
+
+        match status {
+            x if x.is_success() => {
+                let val: serde_json::Value =
+                    serde_json::from_str(&json).map_err(ApiClientError::RespParse)?;
+                debug!("{:?}", val);
+                Ok((val, remaining, reset))
+            }
+            x => Err(ApiClientError::RespStatus(x.as_u16(), json)),
+        }
+    }
+
     fn get_header(resp: &Response, key: &str) -> Result<usize, anyhow::Error> {
         let value = resp.headers().get(key).ok_or(anyhow!("no header"))?;
         let st = value.to_str()?;
@@ -167,3 +247,34 @@ impl ApiClient {
         Ok(num)
     }
 }
+
+// Replaces `:id` in the paths where it stands for the authenticating user, i.e. `users/:id` and `users/:id/...`.
+fn user_path(endpoint_path: &str, user_id: &str) -> String {
+    let path = endpoint_path.trim_start_matches('/');
+    match path.strip_prefix("users/:id") {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => {
+            format!("users/{}{}", user_id, rest)
+        }
+        _ => path.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replace_only_user_id() {
+        assert_eq!(
+            user_path("users/:id/timelines/reverse_chronological", "42"),
+            "users/42/timelines/reverse_chronological"
+        );
+        assert_eq!(user_path("/users/:id", "42"), "users/42");
+        assert_eq!(user_path("lists/:id/tweets", "42"), "lists/:id/tweets");
+        assert_eq!(
+            user_path("tweets/:id/quote_tweets", "42"),
+            "tweets/:id/quote_tweets"
+        );
+        assert_eq!(user_path("users/:idx", "42"), "users/:idx");
+    }
+}
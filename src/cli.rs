@@ -0,0 +1,240 @@
+use std::{
+    collections::HashSet,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    filter_test::{self, FilterTestError},
+    package::{Installer, PackageError, PackageInfo, PendingReason},
+};
+
+const USAGE: &str = "usage:
+    binchotan-backend                             start the backend
+    binchotan-backend filter test DIR             run the tests of the filter (or every filter) in DIR
+    binchotan-backend filter install [-y] PATH    install the filter in PATH (a .tar.gz archive or a directory)
+    binchotan-backend filter upgrade [-y] PATH    replace the installed filter with the one in PATH
+    binchotan-backend filter uninstall ID         remove the filter
+    binchotan-backend filter approve [-y] ID...   approve the filters as they are now, e.g. after they have been modified
+
+    -y, --yes   approve the scopes requested by the filter without asking";
+
+/// What to do, given by the command line arguments.
+pub enum Command {
+    Serve,
+    FilterTest(Vec<PathBuf>),
+    Package(PackageCommand),
+}
+
+pub enum PackageCommand {
+    Install { path: PathBuf, yes: bool },
+    Upgrade { path: PathBuf, yes: bool },
+    Uninstall(String),
+    Approve { ids: Vec<String>, yes: bool },
+}
+
+impl Command {
+    /// Parses the arguments, excluding the program name. Returns the usage on failure.
+    pub fn parse(args: &[String]) -> Result<Self, &'static str> {
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        match args.as_slice() {
+            [] => Ok(Command::Serve),
+            ["filter", "test", dirs @ ..] if !dirs.is_empty() => Ok(Command::FilterTest(
+                dirs.iter().map(PathBuf::from).collect(),
+            )),
+            ["filter", "install", rest @ ..] => {
+                let (path, yes) = Self::parse_path(rest)?;
+                Ok(Command::Package(PackageCommand::Install { path, yes }))
+            }
+            ["filter", "upgrade", rest @ ..] => {
+                let (path, yes) = Self::parse_path(rest)?;
+                Ok(Command::Package(PackageCommand::Upgrade { path, yes }))
+            }
+            ["filter", "approve", rest @ ..] => {
+                let yes = rest.iter().any(|arg| matches!(*arg, "-y" | "--yes"));
+                let ids: Vec<String> = rest
+                    .iter()
+                    .filter(|arg| !matches!(**arg, "-y" | "--yes"))
+                    .map(|id| id.to_string())
+                    .collect();
+                if ids.is_empty() {
+                    return Err(USAGE);
+                }
+                Ok(Command::Package(PackageCommand::Approve { ids, yes }))
+            }
+            ["filter", "uninstall", id] => {
+                Ok(Command::Package(PackageCommand::Uninstall(id.to_string())))
+            }
+            _ => Err(USAGE),
+        }
+    }
+
+    fn parse_path(args: &[&str]) -> Result<(PathBuf, bool), &'static str> {
+        match args {
+            [path] => Ok((PathBuf::from(path), false)),
+            ["-y" | "--yes", path] | [path, "-y" | "--yes"] => Ok((PathBuf::from(path), true)),
+            _ => Err(USAGE),
+        }
+    }
+}
+
+/// Runs the tests of the filters and prints the results. Each directory is either a filter or a directory of filters. Returns whether all the tests have passed.
+pub fn filter_test(dirs: &[PathBuf]) -> Result<bool, FilterTestError> {
+    let mut passed = 0;
+    let mut failed = 0;
+    for dir in dirs {
+        for filter_dir in filter_dirs(dir)? {
+            let report = filter_test::run(&filter_dir)?;
+            println!("filter {}", report.filter);
+            for case in report.cases {
+                match case.failure {
+                    None => {
+                        passed += 1;
+                        println!("  ok      {}: {}", case.file, case.name);
+                    }
+                    Some(failure) => {
+                        failed += 1;
+                        println!(
+                            "  FAILED  {}: {}\n          {}",
+                            case.file, case.name, failure
+                        );
+                    }
+                }
+            }
+        }
+    }
+    println!("{} passed, {} failed", passed, failed);
+
+    Ok(failed == 0)
+}
+
+/// Installs, upgrades or uninstalls a filter. Unless `yes` is given, the scopes requested by the filter are shown and the user is asked to approve them. Returns whether the command has been done.
+pub async fn package(installer: &Installer, command: PackageCommand) -> Result<bool, PackageError> {
+    let (path, yes, upgrade) = match command {
+        PackageCommand::Install { path, yes } => (path, yes, false),
+        PackageCommand::Upgrade { path, yes } => (path, yes, true),
+        PackageCommand::Uninstall(id) => {
+            installer.uninstall(&id).await?;
+            println!("uninstalled {}", id);
+            return Ok(true);
+        }
+        PackageCommand::Approve { ids, yes } => return approve(installer, &ids, yes).await,
+    };
+
+    let package = installer.open(&path)?;
+    let info = installer.inspect(&package);
+    print_package(&info);
+    match (upgrade, info.installed) {
+        (false, true) => return Err(PackageError::AlreadyInstalled(info.id)),
+        (true, false) => return Err(PackageError::NotInstalled(info.id)),
+        _ => {}
+    }
+    if !yes && !confirm(if upgrade { "upgrade?" } else { "install?" })? {
+        println!("cancelled");
+        return Ok(false);
+    }
+
+    let approved: HashSet<String> = info.scopes.iter().cloned().collect();
+    let info = if upgrade {
+        installer.upgrade(package, &approved).await?
+    } else {
+        installer.install(package, &approved).await?
+    };
+    match (upgrade, &info.installed_version) {
+        (true, Some(old)) => println!(
+            "upgraded {} from {} to {}",
+            info.id,
+            old,
+            info.version.as_deref().unwrap_or("unknown")
+        ),
+        (true, None) => println!("upgraded {}", info.id),
+        (false, _) => println!("installed {}", info.id),
+    }
+
+    Ok(true)
+}
+
+// Approves each filter after showing what has changed. Returns whether all of them have been approved.
+async fn approve(installer: &Installer, ids: &[String], yes: bool) -> Result<bool, PackageError> {
+    let mut done = true;
+    for id in ids {
+        let Some(pending) = installer.review(id).await? else {
+            println!("{} is already approved", id);
+            continue;
+        };
+        match pending.reason {
+            PendingReason::Unapproved => {
+                println!("filter {} ({}) has never been approved", id, pending.name)
+            }
+            PendingReason::Modified => println!(
+                "filter {} ({}) has been modified since approved",
+                id, pending.name
+            ),
+        }
+        println!("  requested scopes:");
+        for scope in &pending.scopes {
+            if pending.reason == PendingReason::Modified && pending.new_scopes.contains(scope) {
+                println!("    {} (new)", scope);
+            } else {
+                println!("    {}", scope);
+            }
+        }
+        if !yes && !confirm("approve?")? {
+            println!("skipped {}", id);
+            done = false;
+            continue;
+        }
+
+        let approved: HashSet<String> = pending.scopes.into_iter().collect();
+        installer.approve(id, &approved).await?;
+        println!("approved {}", id);
+    }
+
+    Ok(done)
+}
+
+fn print_package(info: &PackageInfo) {
+    println!(
+        "filter {} ({} {}, by {})",
+        info.id,
+        info.name,
+        info.version.as_deref().unwrap_or("(no version)"),
+        info.author
+    );
+    println!("  {}", info.description);
+    if let Some(version) = &info.installed_version {
+        println!("  installed version: {}", version);
+    }
+    println!("  requested scopes:");
+    for scope in &info.scopes {
+        if info.installed && info.new_scopes.contains(scope) {
+            println!("    {} (new)", scope);
+        } else {
+            println!("    {}", scope);
+        }
+    }
+}
+
+fn confirm(question: &str) -> io::Result<bool> {
+    print!("{} [y/N] ", question);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+// Returns the directory itself if it is a filter, otherwise the filters in it which have tests.
+fn filter_dirs(dir: &Path) -> Result<Vec<PathBuf>, FilterTestError> {
+    if dir.join("binchotan.toml").is_file() {
+        return Ok(vec![dir.to_owned()]);
+    }
+
+    let mut dirs: Vec<PathBuf> = dir
+        .read_dir()?
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| path.join("binchotan.toml").is_file() && path.join("tests").is_dir())
+        .collect();
+    dirs.sort();
+
+    Ok(dirs)
+}
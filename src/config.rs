@@ -1,8 +1,11 @@
 use serde::Deserialize;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
 
-use crate::error::AppError;
+use crate::{
+    error::AppError, history::HistoryConfig, pipeline::PipelineConfig, settings::Settings,
+    timeline::FillConfig,
+};
 
 #[derive(Deserialize)]
 pub struct Config {
@@ -12,8 +15,26 @@ pub struct Config {
     pub socket_path: String,
     pub cache_path: String,
     pub filter_dir: PathBuf,
+    // The directory from which filter packages can be installed over RPC. Packages elsewhere can be installed only by the CLI.
+    pub package_dir: Option<PathBuf>,
     pub scopes: HashSet<String>,
     pub database_url: String,
+    // The default filter pipeline.
+    pub pipeline: Option<PipelineConfig>,
+    // Filter pipelines for specific accounts, keyed by session keys or Twitter user ids.
+    #[serde(default)]
+    pub pipelines: HashMap<String, PipelineConfig>,
+    // Values for the settings declared by filters, keyed by filter ids.
+    #[serde(default)]
+    pub filter_settings: HashMap<String, Settings>,
+    // The number of threads applying filters to posts in parallel.
+    pub filter_workers: Option<usize>,
+    // Limits on fetching more pages to fill a timeline.
+    #[serde(default)]
+    pub fill: FillConfig,
+    // How long fetched tweets are kept for `v0.history.timeline`.
+    #[serde(default)]
+    pub history: HistoryConfig,
 }
 
 impl Config {
@@ -26,4 +47,12 @@ impl Config {
 
         Ok(config)
     }
+
+    /// The number of threads applying filters, which defaults to the number of CPUs.
+    pub fn filter_workers(&self) -> usize {
+        self.filter_workers
+            .or_else(|| std::thread::available_parallelism().ok().map(|n| n.get()))
+            .unwrap_or(1)
+            .max(1)
+    }
 }
@@ -1,16 +1,34 @@
 use crate::{
-    api::HomeTimelineResponseBody,
+    api::{ApiClient, HomeTimelineResponseBody},
     credential::CredentialStore,
     error::AppError,
-    filter::{Filter, FilterError},
+    filter::{Filter, FilterError, FilterFailure, FilterKind},
+    filter_api::{ApiCache, FilterApi},
+    history::{self, HistoryError, HistoryPage, HistoryStore},
+    hooks::{self, HookError, HookSpec, PlainRequest},
+    marker::{self, Marker, MarkerError, MarkerStore, Since},
     methods::HttpMethod,
+    models::Account,
+    package::{self, Installer, PackageError, PackageInfo, PendingFilter},
+    pipeline::{self, PipelineEntry, PipelineError, PipelineStore},
+    rules::{Rule, RulesError, RulesStore},
+    search::SearchQuery,
+    settings::{SettingSpec, Settings, SettingsError, SettingsStore},
+    storage::StorageStore,
+    timeline::{
+        self, AccountPage, Fill, FillConfig, Pages, TimelineContext, TimelineKind, HOME_ENDPOINT,
+    },
+    trace::TraceStep,
+    tweet::Tweet,
     VERSION,
 };
+use futures_util::future::join_all;
 use serde::{Deserialize, Serialize};
 use std::{
     cell::RefCell,
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     path::PathBuf,
+    sync::{Arc, Mutex},
 };
 use thiserror::Error;
 use tracing::{info, warn};
@@ -34,12 +52,48 @@ pub enum Method {
     Plain(PlainParams),
     #[serde(rename = "v0.home_timeline")]
     HomeTimeline(HomeTimelineParams),
+    #[serde(rename = "v0.timeline")]
+    Timeline(TimelineParams),
+    #[serde(rename = "v0.merged_timeline")]
+    MergedTimeline(MergedTimelineParams),
+    #[serde(rename = "v0.timeline.markers")]
+    TimelineMarkers(TimelineMarkersParams),
+    #[serde(rename = "v0.timeline.mark_read")]
+    TimelineMarkRead(TimelineMarkReadParams),
+    #[serde(rename = "v0.history.timeline")]
+    HistoryTimeline(HistoryTimelineParams),
+    #[serde(rename = "v0.history.search")]
+    HistorySearch(HistorySearchParams),
     #[serde(rename = "v0.status")]
     Status(#[serde(default)] EmptyParams),
     #[serde(rename = "v0.account.list")]
     AccountList(AccountListParams),
     #[serde(rename = "v0.account.add")]
     AccountAdd(AccountAddParams),
+    #[serde(rename = "v0.filter.list")]
+    FilterList(FilterListParams),
+    #[serde(rename = "v0.filter.set_enabled")]
+    FilterSetEnabled(FilterSetEnabledParams),
+    #[serde(rename = "v0.filter.reorder")]
+    FilterReorder(FilterReorderParams),
+    #[serde(rename = "v0.filter.configure")]
+    FilterConfigure(FilterConfigureParams),
+    #[serde(rename = "v0.filter.rules")]
+    FilterRules(FilterRulesParams),
+    #[serde(rename = "v0.filter.set_rules")]
+    FilterSetRules(FilterSetRulesParams),
+    #[serde(rename = "v0.filter.explain")]
+    FilterExplain(FilterExplainParams),
+    #[serde(rename = "v0.filter.inspect")]
+    FilterInspect(FilterInspectParams),
+    #[serde(rename = "v0.filter.install")]
+    FilterInstall(FilterInstallParams),
+    #[serde(rename = "v0.filter.upgrade")]
+    FilterUpgrade(FilterInstallParams),
+    #[serde(rename = "v0.filter.uninstall")]
+    FilterUninstall(FilterUninstallParams),
+    #[serde(rename = "v0.filter.approve")]
+    FilterApprove(FilterApproveParams),
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -49,6 +103,9 @@ pub struct PlainParams {
     endpoint: String,
     #[serde(default)]
     api_params: HashMap<String, serde_json::Value>,
+    // Tokens of the prompts of hooks which the user has confirmed, taken from the `token` of the errors asking for confirmation.
+    #[serde(default)]
+    confirmed: HashSet<String>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -56,6 +113,116 @@ pub struct HomeTimelineParams {
     session_key: String,
     #[serde(default)]
     api_params: HashMap<String, serde_json::Value>,
+    // Return posts dropped by filters too, marked with the filter and the reason.
+    #[serde(default)]
+    include_dropped: bool,
+    // Record what each filter did to each post, returned in `meta.trace`.
+    #[serde(default)]
+    trace: bool,
+    // Fetch more pages until this many posts survive the filters.
+    min_results: Option<usize>,
+    // Same as `min_results` set to `max_results` of `api_params`.
+    #[serde(default)]
+    fill: bool,
+    // The most pages to fetch, up to `fill.max_pages` in the config.
+    max_pages: Option<usize>,
+    // Sets `since_id` from the marker of the timeline.
+    since: Option<Since>,
+    // Serve the stored timeline when the API is unavailable. Defaults to `history.offline_fallback` in the config.
+    offline_fallback: Option<bool>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TimelineParams {
+    session_key: String,
+    // An endpoint which returns tweets in `data`, e.g. `tweets/search/recent`. `:id` in `users/:id/...` is replaced with the id of the user.
+    endpoint: String,
+    #[serde(default)]
+    api_params: HashMap<String, serde_json::Value>,
+    #[serde(default)]
+    include_dropped: bool,
+    #[serde(default)]
+    trace: bool,
+    min_results: Option<usize>,
+    #[serde(default)]
+    fill: bool,
+    max_pages: Option<usize>,
+    since: Option<Since>,
+    offline_fallback: Option<bool>,
+}
+
+impl From<HomeTimelineParams> for TimelineParams {
+    fn from(params: HomeTimelineParams) -> Self {
+        Self {
+            session_key: params.session_key,
+            endpoint: HOME_ENDPOINT.to_owned(),
+            api_params: params.api_params,
+            include_dropped: params.include_dropped,
+            trace: params.trace,
+            min_results: params.min_results,
+            fill: params.fill,
+            max_pages: params.max_pages,
+            since: params.since,
+            offline_fallback: params.offline_fallback,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MergedTimelineParams {
+    #[serde(default)]
+    session_keys: Vec<String>,
+    // Also merges the timelines of this account and the accounts it owns.
+    owner: Option<String>,
+    // Passed to the API for every account.
+    #[serde(default)]
+    api_params: HashMap<String, serde_json::Value>,
+    // `next_token` of each account in the previous response, keyed by account ids.
+    #[serde(default)]
+    pagination_tokens: HashMap<String, String>,
+    // The ids of the accounts marked as `exhausted` in the previous responses, which are not fetched again.
+    #[serde(default)]
+    exhausted: HashSet<String>,
+    #[serde(default)]
+    include_dropped: bool,
+    offline_fallback: Option<bool>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TimelineMarkersParams {
+    session_key: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TimelineMarkReadParams {
+    session_key: String,
+    // The timeline as returned in `meta.timeline` of `v0.timeline`.
+    timeline: String,
+    // The newest post the user has read.
+    post_id: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct HistoryTimelineParams {
+    session_key: String,
+    // The timeline as returned in `meta.timeline` of `v0.timeline`. Defaults to the home timeline.
+    timeline: Option<String>,
+    max_results: Option<usize>,
+    // `meta.next_token` of the previous page.
+    pagination_token: Option<String>,
+    #[serde(default)]
+    include_dropped: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct HistorySearchParams {
+    session_key: String,
+    #[serde(flatten)]
+    query: SearchQuery,
+    max_results: Option<usize>,
+    pagination_token: Option<String>,
+    #[serde(default)]
+    include_dropped: bool,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -68,6 +235,89 @@ pub struct AccountAddParams {
     session_key: Option<String>,
 }
 
+#[derive(Debug, Clone, Deserialize)]
+pub struct FilterListParams {
+    session_key: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FilterSetEnabledParams {
+    session_key: String,
+    filter: String,
+    enabled: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FilterReorderParams {
+    session_key: String,
+    // Filter ids to be moved to the head of the pipeline, in this order.
+    filters: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FilterConfigureParams {
+    session_key: String,
+    filter: String,
+    // Values to be saved. `null` resets the setting to the one in the config file or the default.
+    #[serde(default)]
+    settings: Settings,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FilterRulesParams {
+    session_key: String,
+    filter: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FilterSetRulesParams {
+    session_key: String,
+    filter: String,
+    // Replaces the rules saved for the account. Rules in binchotan.toml are not affected.
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FilterExplainParams {
+    session_key: String,
+    // Either the post itself or its id must be given. The id is looked up via the API.
+    tweet: Option<Tweet>,
+    tweet_id: Option<String>,
+    // Used to hydrate `tweet`.
+    includes: Option<serde_json::Value>,
+    // The endpoint the post is shown in, which tells filters the kind of the timeline. Defaults to the home timeline.
+    endpoint: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FilterInspectParams {
+    session_key: String,
+    // A .tar.gz archive or a directory on the machine running the backend.
+    path: PathBuf,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FilterInstallParams {
+    session_key: String,
+    path: PathBuf,
+    // Scopes the user has approved, which must cover every scope requested by the filter.
+    approved_scopes: HashSet<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FilterUninstallParams {
+    session_key: String,
+    filter: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FilterApproveParams {
+    session_key: String,
+    filter: String,
+    // Scopes the user has approved, which must cover every scope requested by the filter.
+    approved_scopes: HashSet<String>,
+}
+
 // TODO: ensure params are empty in a smarter way
 #[derive(Debug, Default, Clone, Deserialize)]
 pub struct EmptyParams {
@@ -111,7 +361,19 @@ pub enum ResponseContent {
     },
     #[serde(rename = "result")]
     HomeTimeline {
-        meta: ResponsePlainMeta,
+        meta: ResponseTimelineMeta,
+        body: HomeTimelineResponseBody,
+    },
+    #[serde(rename = "result")]
+    MergedTimeline {
+        meta: ResponseMergedMeta,
+        body: HomeTimelineResponseBody,
+    },
+    #[serde(rename = "result")]
+    TimelineMarkers { markers: Vec<MarkerInfo> },
+    #[serde(rename = "result")]
+    HistoryTimeline {
+        meta: ResponseHistoryMeta,
         body: HomeTimelineResponseBody,
     },
     #[serde(rename = "result")]
@@ -131,16 +393,132 @@ pub enum ResponseContent {
         // once the user has authenciated on Twitter and the redirect server receives an access token.
         session_key: String,
     },
+    #[serde(rename = "result")]
+    FilterList {
+        // Installed filters in the order of the pipeline.
+        filters: Vec<FilterInfo>,
+        // Filters which are not loaded until approved with `v0.filter.approve`.
+        pending: Vec<PendingFilter>,
+    },
+    #[serde(rename = "result")]
+    FilterConfigure {
+        filter: String,
+        // Setting values which will be used for the account.
+        settings: Settings,
+    },
+    #[serde(rename = "result")]
+    FilterRules {
+        filter: String,
+        // Rules in binchotan.toml, which cannot be changed via RPC.
+        builtin: Vec<Rule>,
+        // Rules saved for the account, evaluated after the builtin ones.
+        rules: Vec<Rule>,
+    },
+    #[serde(rename = "result")]
+    FilterExplain {
+        post_id: Option<String>,
+        // What each filter did to the post, in the order of the pipeline.
+        steps: Vec<TraceStep>,
+        // The post after all the filters, or null if dropped.
+        result: Option<Tweet>,
+    },
+    #[serde(rename = "result")]
+    FilterPackage(PackageInfo),
+    #[serde(rename = "result")]
+    FilterUninstall { filter: String },
     #[serde(rename = "error")]
     Error(ResponseError),
 }
 
+#[derive(Debug, Serialize)]
+pub struct FilterInfo {
+    pub id: String,
+    pub name: String,
+    pub description: String,
+    pub author: String,
+    pub version: Option<String>,
+    pub kind: FilterKind,
+    pub scopes: HashSet<String>,
+    // Settings declared by the filter.
+    pub settings: BTreeMap<String, SettingSpec>,
+    // Endpoints the filter hooks into via `v0.plain`.
+    pub hooks: Vec<HookSpec>,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MarkerInfo {
+    #[serde(flatten)]
+    pub marker: Marker,
+    // The number of posts kept by the filters which are newer than the read marker.
+    pub unread: usize,
+}
+
+impl From<Marker> for MarkerInfo {
+    fn from(marker: Marker) -> Self {
+        Self {
+            unread: marker.unread(),
+            marker,
+        }
+    }
+}
+
 #[derive(Debug, Serialize)]
 pub struct ResponsePlainMeta {
     pub api_calls_remaining: usize,
     pub api_calls_reset: usize, // in epoch sec
 }
 
+#[derive(Debug, Serialize)]
+pub struct ResponseTimelineMeta {
+    pub api_calls_remaining: usize,
+    pub api_calls_reset: usize, // in epoch sec
+    // Errors raised by filters which were skipped (or whose posts were dropped) instead of failing the request.
+    pub filter_errors: Vec<FilterFailure>,
+    // The number of pages fetched from the API, which is more than 1 when filling the response.
+    pub pages: usize,
+    // The timeline for `v0.timeline.mark_read`.
+    pub timeline: String,
+    // The number of unread posts in the timeline, including the ones returned.
+    pub unread: usize,
+    // Set when the API was unavailable and the stored timeline is returned instead.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub stale: bool,
+    // When the stored timeline was fetched, in epoch sec. Only present when `stale`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub captured_at: Option<i64>,
+    // What each filter did to each post, keyed by post ids. Only present when `trace` is requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trace: Option<HashMap<String, Vec<TraceStep>>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ResponseMergedMeta {
+    // The meta of the timeline of each account, in the order of the request.
+    pub accounts: Vec<MergedAccountMeta>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MergedAccountMeta {
+    // The id of the account.
+    pub account: String,
+    // Passed in `pagination_tokens` to fetch the next page of the account.
+    pub next_token: Option<String>,
+    // Set when the account has no more posts. Passed in `exhausted` so that it is not fetched again from the newest post.
+    pub exhausted: bool,
+    #[serde(flatten)]
+    pub timeline: Option<ResponseTimelineMeta>,
+    // Set when the timeline of the account could not be fetched. The other timelines are still merged.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ResponseError>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ResponseHistoryMeta {
+    // Errors raised by filters which were skipped (or whose posts were dropped) instead of failing the request.
+    pub filter_errors: Vec<FilterFailure>,
+}
+
 #[derive(Debug, Serialize)]
 pub struct ResponseError {
     pub code: isize,
@@ -174,6 +552,9 @@ enum RpcServerError {
     Api,
     ApiStatus,
     Lua,
+    Rejected,
+    ConfirmationRequired,
+    Forbidden,
     Other,
 }
 
@@ -183,6 +564,9 @@ impl From<RpcServerError> for isize {
             RpcServerError::Api => -32000,
             RpcServerError::ApiStatus => -32001,
             RpcServerError::Lua => -32002,
+            RpcServerError::Rejected => -32003,
+            RpcServerError::ConfirmationRequired => -32004,
+            RpcServerError::Forbidden => -32005,
             RpcServerError::Other => -32099,
         }
     }
@@ -191,6 +575,10 @@ impl From<RpcServerError> for isize {
 // TODO: include concrete error types (CacheManager, ApiClient etc.) under HandlerErrors, and use HandlerErrors instead to get rid of unreachables?
 impl From<AppError> for ResponseError {
     fn from(err: AppError) -> Self {
+        let data = match &err {
+            AppError::Hook(e) => e.data(),
+            _ => None,
+        };
         let code = match err {
             AppError::Config(_) => unreachable!(),
             AppError::Listener(_) => unreachable!(),
@@ -203,14 +591,72 @@ impl From<AppError> for ResponseError {
                 HandlerError::Version => RpcError::InvalidRequest,
                 HandlerError::UnknownAccount(_) => RpcError::InvalidParams,
                 HandlerError::ParamsMismatch(_) => RpcError::InvalidParams,
+                HandlerError::NotAdministrator => RpcError::Server(RpcServerError::Forbidden),
             },
             AppError::Filter(ref e) => match e {
                 FilterError::PathNotDir(_) => RpcError::Server(RpcServerError::Other),
+                FilterError::Symlink(_) => RpcError::Server(RpcServerError::Other),
                 FilterError::MetaParse(_) => RpcError::Server(RpcServerError::Other),
+                FilterError::NoEntrypoint(_) => RpcError::Server(RpcServerError::Other),
                 FilterError::InsufficientScopes(_, _) => RpcError::Server(RpcServerError::Other),
+                FilterError::InvalidSettings(_, _) => RpcError::Server(RpcServerError::Other),
+                FilterError::InvalidVersion(_, _) => RpcError::Server(RpcServerError::Other),
+                FilterError::Incompatible(_, _) => RpcError::Server(RpcServerError::Other),
+                FilterError::InvalidRules(_, _) => RpcError::Server(RpcServerError::Other),
+                FilterError::Run(_, _) => RpcError::Server(RpcServerError::Lua),
                 FilterError::Io(_) => RpcError::Server(RpcServerError::Other),
                 FilterError::Lua(_) => RpcError::Server(RpcServerError::Lua),
+                FilterError::InvalidWasm(_, _) => RpcError::Server(RpcServerError::Other),
+                FilterError::Wasm(_) => RpcError::Server(RpcServerError::Other),
+                FilterError::Aborted => RpcError::Server(RpcServerError::Other),
+            },
+            // only raised by the `filter test` command, but mapped in case it ever reaches a request
+            AppError::FilterTest(_) => RpcError::Server(RpcServerError::Other),
+            AppError::Package(ref e) => match e {
+                PackageError::Archive(_, _) => RpcError::InvalidParams,
+                PackageError::UnsupportedEntry(_, _) => RpcError::InvalidParams,
+                PackageError::NotFilter(_) => RpcError::InvalidParams,
+                PackageError::InvalidId(_) => RpcError::InvalidParams,
+                PackageError::AlreadyInstalled(_) => RpcError::InvalidParams,
+                PackageError::NotInstalled(_) => RpcError::InvalidParams,
+                PackageError::ScopesNotApproved(_, _) => RpcError::InvalidParams,
+                PackageError::OutsidePackageDir(_) => RpcError::InvalidParams,
+                PackageError::Filter(_) => RpcError::Server(RpcServerError::Other),
+                PackageError::Io(_) => RpcError::Server(RpcServerError::Other),
+                PackageError::Database(_) => RpcError::Server(RpcServerError::Other),
             },
+            AppError::Pipeline(ref e) => match e {
+                PipelineError::UnknownFilter(_) => RpcError::InvalidParams,
+                PipelineError::Database(_) => RpcError::Server(RpcServerError::Other),
+            },
+            AppError::Settings(ref e) => match e {
+                SettingsError::UnknownKey(_) => RpcError::InvalidParams,
+                SettingsError::Mismatch(_, _) => RpcError::InvalidParams,
+                SettingsError::Database(_) => RpcError::Server(RpcServerError::Other),
+            },
+            AppError::Rules(ref e) => match e {
+                RulesError::NotRules(_) => RpcError::InvalidParams,
+                RulesError::InvalidRegex(_, _) => RpcError::InvalidParams,
+                RulesError::InvalidValue(_, _) => RpcError::InvalidParams,
+                RulesError::Database(_) => RpcError::Server(RpcServerError::Other),
+            },
+            AppError::History(ref e) => match e {
+                HistoryError::InvalidToken(_) => RpcError::InvalidParams,
+                HistoryError::InvalidTime(_, _) => RpcError::InvalidParams,
+                HistoryError::Database(_) => RpcError::Server(RpcServerError::Other),
+            },
+            AppError::Hook(ref e) => match e {
+                HookError::Rejected(_, _) => RpcError::Server(RpcServerError::Rejected),
+                HookError::ConfirmationRequired(_, _, _) => {
+                    RpcError::Server(RpcServerError::ConfirmationRequired)
+                }
+                HookError::Run(_, _) => RpcError::Server(RpcServerError::Lua),
+                HookError::Aborted(_) => RpcError::Server(RpcServerError::Other),
+            },
+            AppError::Marker(ref e) => match e {
+                MarkerError::Database(_) => RpcError::Server(RpcServerError::Other),
+            },
+            AppError::Storage(_) => RpcError::Server(RpcServerError::Other),
             AppError::Lua(_) => RpcError::Server(RpcServerError::Lua),
             AppError::Io(_) => RpcError::Server(RpcServerError::Other),
             AppError::Other(_) => RpcError::Server(RpcServerError::Other),
@@ -220,11 +666,27 @@ impl From<AppError> for ResponseError {
         ResponseError {
             code,
             message: err.to_string(),
-            data: None,
+            data,
         }
     }
 }
 
+// `max_results` of the parameters for the API, which may be given either as a number or a string.
+fn max_results(api_params: &HashMap<String, serde_json::Value>) -> Option<usize> {
+    match api_params.get("max_results")? {
+        serde_json::Value::String(s) => s.parse().ok(),
+        value => value.as_u64().map(|n| n as usize),
+    }
+}
+
+// A tweet id in the parameters, given either as a string or a number.
+fn id_param(api_params: &HashMap<String, serde_json::Value>, key: &str) -> Option<String> {
+    match api_params.get(key)? {
+        serde_json::Value::String(s) => Some(s.clone()),
+        value => value.as_u64().map(|n| n.to_string()),
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum HandlerError {
     #[error("could not parse the parameters in the JSON-RPC request: {0}")]
@@ -235,12 +697,28 @@ pub enum HandlerError {
     UnknownAccount(String),
     #[error("wrong parameters in request (id = {0})")]
     ParamsMismatch(String),
+    #[error("only the account which owns every other account can manage installed filters")]
+    NotAdministrator,
 }
 
 pub struct Handler {
     pub store: CredentialStore,
+    pub pipelines: PipelineStore,
+    pub settings: SettingsStore,
+    pub storage: StorageStore,
+    pub rules: RulesStore,
+    pub markers: MarkerStore,
+    pub history: HistoryStore,
+    pub installer: Installer,
+    pub api_cache: Arc<ApiCache>,
     pub filter_path: PathBuf,
+    /// The directory from which filter packages can be installed over RPC. If unset, they can be installed only by the CLI.
+    pub package_dir: Option<PathBuf>,
     pub scopes: HashSet<String>,
+    pub filter_settings: HashMap<String, Settings>,
+    /// Threads applying filters, shared by every request.
+    pub filter_pool: Arc<rayon::ThreadPool>,
+    pub fill: FillConfig,
 }
 
 impl Handler {
@@ -269,10 +747,36 @@ impl Handler {
 
         let resp = match req.method {
             Method::Plain(params) => self.handle_plain(req.id, params).await?,
-            Method::HomeTimeline(params) => self.handle_timeline(req.id, params).await?,
+            Method::HomeTimeline(params) => self.handle_timeline(req.id, params.into()).await?,
+            Method::Timeline(params) => self.handle_timeline(req.id, params).await?,
+            Method::MergedTimeline(params) => self.handle_merged_timeline(req.id, params).await?,
+            Method::TimelineMarkers(params) => self.handle_timeline_markers(req.id, params).await?,
+            Method::TimelineMarkRead(params) => {
+                self.handle_timeline_mark_read(req.id, params).await?
+            }
+            Method::HistoryTimeline(params) => self.handle_history_timeline(req.id, params).await?,
+            Method::HistorySearch(params) => self.handle_history_search(req.id, params).await?,
             Method::Status(params) => self.handle_status(req.id, params).await?,
             Method::AccountList(params) => self.handle_account_list(req.id, params).await?,
             Method::AccountAdd(params) => self.handle_account_add(req.id, params).await?,
+            Method::FilterList(params) => self.handle_filter_list(req.id, params).await?,
+            Method::FilterSetEnabled(params) => {
+                self.handle_filter_set_enabled(req.id, params).await?
+            }
+            Method::FilterReorder(params) => self.handle_filter_reorder(req.id, params).await?,
+            Method::FilterConfigure(params) => self.handle_filter_configure(req.id, params).await?,
+            Method::FilterRules(params) => self.handle_filter_rules(req.id, params).await?,
+            Method::FilterSetRules(params) => self.handle_filter_set_rules(req.id, params).await?,
+            Method::FilterExplain(params) => self.handle_filter_explain(req.id, params).await?,
+            Method::FilterInspect(params) => self.handle_filter_inspect(req.id, params).await?,
+            Method::FilterInstall(params) => {
+                self.handle_filter_install(req.id, params, false).await?
+            }
+            Method::FilterUpgrade(params) => {
+                self.handle_filter_install(req.id, params, true).await?
+            }
+            Method::FilterUninstall(params) => self.handle_filter_uninstall(req.id, params).await?,
+            Method::FilterApprove(params) => self.handle_filter_approve(req.id, params).await?,
         };
 
         Ok(resp)
@@ -284,12 +788,44 @@ impl Handler {
             http_method,
             endpoint,
             api_params,
+            confirmed,
         } = params;
 
-        let client = self.store.client_for(&session_key).await?;
-        let api_params = serde_json::to_string(&api_params).map_err(HandlerError::ParamsParse)?;
-        let (resp, remaining, reset) = client.call(&http_method, &endpoint, api_params).await?;
+        let client = Arc::new(self.store.client_for(&session_key).await?);
+        let request = PlainRequest {
+            method: http_method,
+            endpoint,
+            body: serde_json::to_value(&api_params).map_err(HandlerError::ParamsParse)?,
+        };
+        // most calls are not hooked, and are passed through without touching filters
+        let hooking = Filter::hooking(self.filter_path.as_ref(), &request)?;
+        let hooked = if hooking.is_empty() {
+            None
+        } else {
+            let account = self.store.account_for(&session_key).await?;
+            let filters = self
+                .hook_pipeline_for(&account, client.clone(), &hooking)
+                .await?;
+            Some((account, Arc::new(filters))).filter(|(_, filters)| !filters.is_empty())
+        };
+
+        let request = match &hooked {
+            Some((_, filters)) => hooks::on_request(filters.clone(), request, confirmed).await?,
+            None => request,
+        };
+        let api_params = serde_json::to_string(&request.body).map_err(HandlerError::ParamsParse)?;
+        let (resp, remaining, reset) = client
+            .call(&request.method, &request.endpoint, api_params)
+            .await?;
         info!("got response for plain request with id {}", id);
+        let resp = match &hooked {
+            Some((account, filters)) => {
+                let resp = hooks::on_response(filters.clone(), request, resp).await?;
+                self.save_storage(account, filters).await?;
+                resp
+            }
+            None => resp,
+        };
 
         let content = ResponseContent::Plain {
             meta: ResponsePlainMeta {
@@ -305,59 +841,518 @@ impl Handler {
         })
     }
 
+    /// Fetches tweets from the endpoint and applies the pipeline of the account to them. When the API is unavailable, the stored timeline may be returned instead.
     async fn handle_timeline(
         &self,
         id: String,
-        params: HomeTimelineParams,
+        params: TimelineParams,
     ) -> Result<Response, AppError> {
-        let HomeTimelineParams {
+        let (meta, body) = self.timeline(params).await?;
+
+        Ok(Response {
+            jsonrpc: JSONRPC_VERSION.to_string(),
+            content: ResponseContent::HomeTimeline { meta, body },
+            id,
+        })
+    }
+
+    async fn timeline(
+        &self,
+        params: TimelineParams,
+    ) -> Result<(ResponseTimelineMeta, HomeTimelineResponseBody), AppError> {
+        // the cursors of the API cannot be told in the stored timeline, so only the first page and the pages given by ids are served from it
+        let paginated = ["pagination_token", "next_token"]
+            .iter()
+            .any(|key| params.api_params.contains_key(*key));
+        let fallback = self.history.falls_back(params.offline_fallback) && !paginated;
+        let session_key = params.session_key.clone();
+        let context = TimelineContext::for_endpoint(&params.endpoint);
+        let api_params = params.api_params.clone();
+        let max_results = max_results(&params.api_params);
+        let until_id = id_param(&params.api_params, "until_id");
+        let since_id = id_param(&params.api_params, "since_id");
+        let since = params.since;
+        let include_dropped = params.include_dropped;
+
+        match self.fetch_timeline(params).await {
+            Err(AppError::ApiClient(err)) if fallback && err.is_unavailable() => {
+                let account = self.store.account_for(&session_key).await?;
+                let timeline = marker::timeline_key(&context, &api_params, &account.twitter_id);
+                warn!("serving the stored timeline {}: {}", timeline, err);
+                let marker = self.markers.load(&account, &timeline).await?;
+                let since_id = match since {
+                    Some(Since::Last) => marker.newest_id.clone().or(since_id),
+                    None => since_id,
+                };
+                let mut page = self
+                    .history
+                    .timeline(
+                        &account,
+                        &timeline,
+                        max_results.unwrap_or(history::MAX_RESULTS),
+                        until_id.as_deref(),
+                        since_id.as_deref(),
+                    )
+                    .await?;
+                // it is a tweet id, which the API would not take as a token. `until_id` set to `oldest_id` gives the next page instead
+                if let Some(meta) = page.meta.as_object_mut() {
+                    meta.remove("next_token");
+                }
+                let captured_at = self.history.captured_at(&account, &timeline).await?;
+                let (filter_errors, body) = self
+                    .filter_stored(&account, page, context, include_dropped)
+                    .await?;
+
+                let meta = ResponseTimelineMeta {
+                    api_calls_remaining: 0,
+                    api_calls_reset: 0,
+                    filter_errors,
+                    pages: 0,
+                    timeline,
+                    unread: marker.unread(),
+                    stale: true,
+                    captured_at,
+                    trace: None,
+                };
+                Ok((meta, body))
+            }
+            result => result,
+        }
+    }
+
+    async fn fetch_timeline(
+        &self,
+        params: TimelineParams,
+    ) -> Result<(ResponseTimelineMeta, HomeTimelineResponseBody), AppError> {
+        let TimelineParams {
             session_key,
+            endpoint,
             mut api_params,
+            include_dropped,
+            trace,
+            min_results,
+            fill,
+            max_pages,
+            since,
+            ..
         } = params;
+        let context = TimelineContext::for_endpoint(&endpoint);
+        // without either option, only the page requested is fetched
+        let min_results = min_results.or_else(|| max_results(&api_params).filter(|_| fill));
+        let limits = Fill::new(&self.fill, min_results, max_pages);
 
-        let client = self.store.client_for(&session_key).await?;
-        let (
-            HomeTimelineResponseBody {
-                data: tweets,
-                includes,
-                meta,
-            },
-            remaining,
-            reset,
-        ) = client.timeline(&mut api_params).await?;
-        info!(
-            "successfully retrieved {} tweets (reverse_chronological)",
-            tweets.len(),
-        );
+        let client = Arc::new(self.store.client_for(&session_key).await?);
+        let account = self.store.account_for(&session_key).await?;
+        let timeline = marker::timeline_key(&context, &api_params, &account.twitter_id);
+        let filters = Arc::new(self.pipeline_for(&account, Some(client.clone())).await?);
+        let mut marker = self.markers.load(&account, &timeline).await?;
+        if let (Some(Since::Last), Some(newest_id)) = (since, &marker.newest_id) {
+            api_params.insert("since_id".to_owned(), newest_id.clone().into());
+        }
+
+        let mut pages = Pages::default();
+        let mut posts = vec![];
+        let mut kept = 0;
+        let mut filter_errors = vec![];
+        let mut traces = trace.then(HashMap::new);
+        let (remaining, reset) = loop {
+            let (
+                HomeTimelineResponseBody {
+                    data: tweets,
+                    includes,
+                    meta,
+                },
+                remaining,
+                reset,
+            ) = client.tweets(&endpoint, &api_params).await?;
+            info!(
+                "successfully retrieved {} tweets ({})",
+                tweets.len(),
+                endpoint
+            );
+            self.history
+                .save(&account, &timeline, &tweets, includes.as_ref())
+                .await?;
 
-        let filters = Filter::load(self.filter_path.as_ref(), &self.scopes)?;
+            let mut applied = Filter::apply_blocking(
+                filters.clone(),
+                tweets,
+                includes.clone(),
+                meta.clone(),
+                context.clone(),
+                trace,
+                self.filter_pool.clone(),
+            )
+            .await?;
+            filter_errors.append(&mut applied.failures);
+            if let (Some(traces), Some(trace)) = (&mut traces, applied.trace.take()) {
+                traces.extend(trace);
+            }
+            kept += applied.posts.len();
+            marker.fetched(
+                meta["newest_id"].as_str(),
+                applied.posts.iter().filter_map(Tweet::id),
+            );
+            posts.extend(applied.into_posts(include_dropped));
+
+            let next_token = meta["next_token"].as_str().map(String::from);
+            pages.push(includes, meta);
+            let Some(next_token) = next_token else {
+                break (remaining, reset);
+            };
+            if !limits.wants_more(pages.count, kept, remaining) {
+                break (remaining, reset);
+            }
+            api_params.insert(
+                context.kind.pagination_param().to_owned(),
+                next_token.into(),
+            );
+        };
+        self.save_storage(&account, &filters).await?;
+        self.markers.save(&account, &marker).await?;
 
-        let mut filtered_tweets = vec![];
-        'outer: for tweet in tweets {
-            let mut result = tweet;
-            for filter in &filters {
-                match filter.run(&result)? {
-                    Some(t) => result = t,
-                    None => continue 'outer,
+        let meta = ResponseTimelineMeta {
+            api_calls_remaining: remaining,
+            api_calls_reset: reset,
+            filter_errors,
+            pages: pages.count,
+            timeline,
+            unread: marker.unread(),
+            stale: false,
+            captured_at: None,
+            trace: traces,
+        };
+        let body = HomeTimelineResponseBody {
+            data: posts,
+            includes: pages.includes,
+            meta: pages.meta,
+        };
+        Ok((meta, body))
+    }
+
+    /// Fetches the home timelines of the accounts concurrently, each filtered by the pipeline of the account, and merges them into one.
+    async fn handle_merged_timeline(
+        &self,
+        id: String,
+        params: MergedTimelineParams,
+    ) -> Result<Response, AppError> {
+        let MergedTimelineParams {
+            mut session_keys,
+            owner,
+            api_params,
+            pagination_tokens,
+            exhausted,
+            include_dropped,
+            offline_fallback,
+        } = params;
+        if let Some(owner) = owner {
+            let mut owned: Vec<(String, String)> =
+                self.store.accounts(&owner).await?.into_iter().collect();
+            owned.sort();
+            session_keys.extend(
+                owned
+                    .into_iter()
+                    .map(|(_, key)| key)
+                    .filter(|key| !key.is_empty()),
+            );
+        }
+        let mut seen = HashSet::new();
+        session_keys.retain(|key| seen.insert(key.clone()));
+        if session_keys.is_empty() {
+            return Err(HandlerError::ParamsMismatch(id).into());
+        }
+
+        let mut accounts = vec![];
+        for session_key in session_keys {
+            let account = self.store.account_for(&session_key).await?.twitter_id;
+            accounts.push((session_key, account));
+        }
+        // accounts without a token (e.g. added to the owner since) start where the previous page ended
+        let start = timeline::start_token(&pagination_tokens);
+        let fetched: Vec<_> = accounts
+            .iter()
+            .filter(|(_, account)| !exhausted.contains(account))
+            .collect();
+        let tokens: Vec<_> = fetched
+            .iter()
+            .map(|(_, account)| pagination_tokens.get(account).or(start.as_ref()))
+            .collect();
+        let mut results = join_all(
+            fetched
+                .iter()
+                .zip(&tokens)
+                .map(|((session_key, _), token)| {
+                    let mut api_params = api_params.clone();
+                    if let Some(token) = token {
+                        let (key, value) = timeline::resume_param(token);
+                        api_params.insert(key.to_owned(), value.into());
+                    }
+                    self.timeline(TimelineParams {
+                        session_key: session_key.clone(),
+                        endpoint: HOME_ENDPOINT.to_owned(),
+                        api_params,
+                        include_dropped,
+                        trace: false,
+                        min_results: None,
+                        fill: false,
+                        max_pages: None,
+                        since: None,
+                        offline_fallback,
+                    })
+                }),
+        )
+        .await;
+        // fail only when no timeline is available
+        if !results.is_empty() && results.iter().all(Result::is_err) {
+            results.swap_remove(0)?;
+        }
+        let mut results: HashMap<&String, _> = fetched
+            .iter()
+            .map(|(_, account)| account)
+            .zip(results.into_iter().zip(tokens))
+            .collect();
+
+        let mut metas = vec![];
+        let mut pages = vec![];
+        let mut includes = None;
+        for (_, account) in &accounts {
+            let Some((result, token)) = results.remove(account) else {
+                metas.push(MergedAccountMeta {
+                    account: account.clone(),
+                    next_token: None,
+                    exhausted: true,
+                    timeline: None,
+                    error: None,
+                });
+                continue;
+            };
+            match result {
+                Ok((meta, body)) => {
+                    metas.push(MergedAccountMeta {
+                        account: account.clone(),
+                        next_token: None,
+                        exhausted: false,
+                        timeline: Some(meta),
+                        error: None,
+                    });
+                    timeline::merge_includes(&mut includes, body.includes);
+                    pages.push(AccountPage {
+                        account: account.clone(),
+                        posts: body.data,
+                        oldest_id: body.meta["oldest_id"].as_str().map(String::from),
+                        has_next: body.meta["next_token"].is_string(),
+                    });
+                }
+                Err(err) => {
+                    warn!("could not fetch the timeline of {}: {:?}", account, err);
+                    metas.push(MergedAccountMeta {
+                        account: account.clone(),
+                        // fetched again from where it was, unless the merged page ends somewhere (below)
+                        next_token: token.cloned(),
+                        exhausted: false,
+                        timeline: None,
+                        error: Some(err.into()),
+                    });
                 }
             }
-            filtered_tweets.push(result);
         }
 
-        let content = ResponseContent::HomeTimeline {
-            meta: ResponsePlainMeta {
-                api_calls_remaining: remaining,
-                api_calls_reset: reset,
+        let mut merged = timeline::merge_account_pages(pages);
+        for meta in &mut metas {
+            if meta.error.is_some() {
+                // resume at the end of the merged page like the others, instead of from the newest post
+                if let Some(cut) = &merged.cut {
+                    meta.next_token = Some(cut.clone());
+                }
+            } else if meta.timeline.is_some() {
+                meta.next_token = merged.tokens.remove(&meta.account);
+                meta.exhausted = merged.exhausted.contains(&meta.account);
+            }
+        }
+        let posts = merged.posts;
+        let mut meta = serde_json::json!({ "result_count": posts.len() });
+        if let (Some(newest), Some(oldest)) = (posts.first(), posts.last()) {
+            meta["newest_id"] = newest.id().into();
+            meta["oldest_id"] = oldest.id().into();
+        }
+
+        Ok(Response {
+            jsonrpc: JSONRPC_VERSION.to_string(),
+            content: ResponseContent::MergedTimeline {
+                meta: ResponseMergedMeta { accounts: metas },
+                body: HomeTimelineResponseBody {
+                    data: posts,
+                    includes,
+                    meta,
+                },
+            },
+            id,
+        })
+    }
+
+    /// Applies the pipeline to the tweets stored from the past responses, without calling the API.
+    async fn handle_history_timeline(
+        &self,
+        id: String,
+        params: HistoryTimelineParams,
+    ) -> Result<Response, AppError> {
+        let HistoryTimelineParams {
+            session_key,
+            timeline,
+            max_results,
+            pagination_token,
+            include_dropped,
+        } = params;
+        let timeline = timeline.unwrap_or_else(|| HOME_ENDPOINT.to_owned());
+        let account = self.store.account_for(&session_key).await?;
+        let page = self
+            .history
+            .timeline(
+                &account,
+                &timeline,
+                max_results.unwrap_or(history::MAX_RESULTS),
+                pagination_token.as_deref(),
+                None,
+            )
+            .await?;
+
+        let context = TimelineContext::for_endpoint(timeline.split('?').next().unwrap_or_default());
+
+        self.history_response(id, &account, page, context, include_dropped)
+            .await
+    }
+
+    /// Searches the stored tweets, and applies the pipeline to the results.
+    async fn handle_history_search(
+        &self,
+        id: String,
+        params: HistorySearchParams,
+    ) -> Result<Response, AppError> {
+        let HistorySearchParams {
+            session_key,
+            query,
+            max_results,
+            pagination_token,
+            include_dropped,
+        } = params;
+        let account = self.store.account_for(&session_key).await?;
+        let page = self
+            .history
+            .search(
+                &account,
+                &query,
+                max_results.unwrap_or(history::MAX_RESULTS),
+                pagination_token.as_deref(),
+            )
+            .await?;
+        // results can come from any timeline, so filters see them as search results
+        let context = TimelineContext::new(TimelineKind::Search);
+
+        self.history_response(id, &account, page, context, include_dropped)
+            .await
+    }
+
+    async fn history_response(
+        &self,
+        id: String,
+        account: &Account,
+        page: HistoryPage,
+        context: TimelineContext,
+        include_dropped: bool,
+    ) -> Result<Response, AppError> {
+        let (filter_errors, body) = self
+            .filter_stored(account, page, context, include_dropped)
+            .await?;
+
+        Ok(Response {
+            jsonrpc: JSONRPC_VERSION.to_string(),
+            content: ResponseContent::HistoryTimeline {
+                meta: ResponseHistoryMeta { filter_errors },
+                body,
             },
-            body: HomeTimelineResponseBody {
-                data: filtered_tweets,
+            id,
+        })
+    }
+
+    // Applies the pipeline to stored tweets. Filters cannot call the API here.
+    async fn filter_stored(
+        &self,
+        account: &Account,
+        page: HistoryPage,
+        context: TimelineContext,
+        include_dropped: bool,
+    ) -> Result<(Vec<FilterFailure>, HomeTimelineResponseBody), AppError> {
+        let HistoryPage {
+            tweets,
+            includes,
+            meta,
+        } = page;
+        let filters = Arc::new(self.pipeline_for(account, None).await?);
+        let mut applied = Filter::apply_blocking(
+            filters.clone(),
+            tweets,
+            includes.clone(),
+            meta.clone(),
+            context,
+            false,
+            self.filter_pool.clone(),
+        )
+        .await?;
+        self.save_storage(account, &filters).await?;
+        let filter_errors = std::mem::take(&mut applied.failures);
+
+        Ok((
+            filter_errors,
+            HomeTimelineResponseBody {
+                data: applied.into_posts(include_dropped),
                 includes,
                 meta,
             },
-        };
+        ))
+    }
+
+    async fn handle_timeline_markers(
+        &self,
+        id: String,
+        params: TimelineMarkersParams,
+    ) -> Result<Response, AppError> {
+        let TimelineMarkersParams { session_key } = params;
+        let account = self.store.account_for(&session_key).await?;
+        let markers = self.markers.all(&account).await?;
+
         Ok(Response {
             jsonrpc: JSONRPC_VERSION.to_string(),
-            content,
+            content: ResponseContent::TimelineMarkers {
+                markers: markers.into_iter().map(MarkerInfo::from).collect(),
+            },
+            id,
+        })
+    }
+
+    async fn handle_timeline_mark_read(
+        &self,
+        id: String,
+        params: TimelineMarkReadParams,
+    ) -> Result<Response, AppError> {
+        let TimelineMarkReadParams {
+            session_key,
+            timeline,
+            post_id,
+        } = params;
+        let account = self.store.account_for(&session_key).await?;
+        let mut marker = self.markers.load(&account, &timeline).await?;
+        marker.mark_read(post_id);
+        self.markers.save(&account, &marker).await?;
+        info!(
+            "marked {} as read up to {:?} for {}",
+            timeline, marker.read_id, account.twitter_id
+        );
+
+        Ok(Response {
+            jsonrpc: JSONRPC_VERSION.to_string(),
+            content: ResponseContent::TimelineMarkers {
+                markers: vec![marker.into()],
+            },
             id,
         })
     }
@@ -421,4 +1416,449 @@ impl Handler {
             id,
         })
     }
+
+    async fn handle_filter_list(
+        &self,
+        id: String,
+        params: FilterListParams,
+    ) -> Result<Response, AppError> {
+        let FilterListParams { session_key } = params;
+        let account = self.store.account_for(&session_key).await?;
+        let (filters, pending) = self.load_all_filters().await?;
+        let entries = self.pipelines.entries(&account, &filters).await?;
+
+        Ok(Self::filter_list(id, entries, filters, pending))
+    }
+
+    async fn handle_filter_set_enabled(
+        &self,
+        id: String,
+        params: FilterSetEnabledParams,
+    ) -> Result<Response, AppError> {
+        let FilterSetEnabledParams {
+            session_key,
+            filter,
+            enabled,
+        } = params;
+        let account = self.store.account_for(&session_key).await?;
+        let (filters, pending) = self.load_all_filters().await?;
+        let entries = self
+            .pipelines
+            .set_enabled(&account, &filters, &filter, enabled)
+            .await?;
+        info!(
+            "set filter {} enabled = {} for {}",
+            filter, enabled, account.twitter_id
+        );
+
+        Ok(Self::filter_list(id, entries, filters, pending))
+    }
+
+    async fn handle_filter_reorder(
+        &self,
+        id: String,
+        params: FilterReorderParams,
+    ) -> Result<Response, AppError> {
+        let FilterReorderParams {
+            session_key,
+            filters: order,
+        } = params;
+        let account = self.store.account_for(&session_key).await?;
+        let (filters, pending) = self.load_all_filters().await?;
+        let entries = self.pipelines.reorder(&account, &filters, &order).await?;
+
+        Ok(Self::filter_list(id, entries, filters, pending))
+    }
+
+    async fn handle_filter_configure(
+        &self,
+        id: String,
+        params: FilterConfigureParams,
+    ) -> Result<Response, AppError> {
+        let FilterConfigureParams {
+            session_key,
+            filter: filter_id,
+            settings: values,
+        } = params;
+        let account = self.store.account_for(&session_key).await?;
+        let mut filter = self
+            .load_filters()
+            .await?
+            .into_iter()
+            .find(|f| f.id == filter_id)
+            .ok_or_else(|| PipelineError::UnknownFilter(filter_id.clone()))?;
+
+        let mut saved = self
+            .settings
+            .all(&account)
+            .await?
+            .remove(&filter_id)
+            .unwrap_or_default();
+        for (key, value) in values {
+            if value.is_null() {
+                saved.remove(&key);
+            } else {
+                saved.insert(key, value);
+            }
+        }
+        crate::settings::validate(&filter.meta.settings, &saved)?;
+
+        filter.configure(&saved)?;
+        self.settings.save(&account, &filter_id, saved).await?;
+        info!(
+            "saved settings of filter {} for {}",
+            filter_id, account.twitter_id
+        );
+
+        Ok(Response {
+            jsonrpc: JSONRPC_VERSION.to_string(),
+            content: ResponseContent::FilterConfigure {
+                filter: filter_id,
+                settings: filter.settings,
+            },
+            id,
+        })
+    }
+
+    async fn handle_filter_rules(
+        &self,
+        id: String,
+        params: FilterRulesParams,
+    ) -> Result<Response, AppError> {
+        let FilterRulesParams {
+            session_key,
+            filter: filter_id,
+        } = params;
+        let account = self.store.account_for(&session_key).await?;
+        let filter = self.rules_filter(&filter_id).await?;
+        let rules = self.rules.load(&account, &filter_id).await?;
+
+        Ok(Response {
+            jsonrpc: JSONRPC_VERSION.to_string(),
+            content: ResponseContent::FilterRules {
+                filter: filter_id,
+                builtin: filter.meta.rules,
+                rules,
+            },
+            id,
+        })
+    }
+
+    async fn handle_filter_set_rules(
+        &self,
+        id: String,
+        params: FilterSetRulesParams,
+    ) -> Result<Response, AppError> {
+        let FilterSetRulesParams {
+            session_key,
+            filter: filter_id,
+            mut rules,
+        } = params;
+        let account = self.store.account_for(&session_key).await?;
+        let filter = self.rules_filter(&filter_id).await?;
+        for rule in &mut rules {
+            rule.when.prepare()?;
+        }
+        self.rules.save(&account, &filter_id, &rules).await?;
+        info!(
+            "saved {} rules of filter {} for {}",
+            rules.len(),
+            filter_id,
+            account.twitter_id
+        );
+
+        Ok(Response {
+            jsonrpc: JSONRPC_VERSION.to_string(),
+            content: ResponseContent::FilterRules {
+                filter: filter_id,
+                builtin: filter.meta.rules,
+                rules,
+            },
+            id,
+        })
+    }
+
+    /// Runs the pipeline on a single post and tells what each filter did. This is a dry run; changes to the storage are not saved.
+    async fn handle_filter_explain(
+        &self,
+        id: String,
+        params: FilterExplainParams,
+    ) -> Result<Response, AppError> {
+        let FilterExplainParams {
+            session_key,
+            tweet,
+            tweet_id,
+            includes,
+            endpoint,
+        } = params;
+
+        let client = Arc::new(self.store.client_for(&session_key).await?);
+        let (tweet, includes) = match (tweet, tweet_id) {
+            (Some(tweet), _) => (tweet, includes),
+            (None, Some(tweet_id)) => client.tweet(&tweet_id).await?,
+            (None, None) => return Err(HandlerError::ParamsMismatch(id).into()),
+        };
+        let post_id = tweet.id().map(String::from);
+
+        let account = self.store.account_for(&session_key).await?;
+        let filters = Arc::new(self.pipeline_for(&account, Some(client)).await?);
+        let mut applied = Filter::apply_blocking(
+            filters,
+            vec![tweet],
+            includes,
+            serde_json::json!({}),
+            endpoint
+                .map(|endpoint| TimelineContext::for_endpoint(&endpoint))
+                .unwrap_or_default(),
+            true,
+            self.filter_pool.clone(),
+        )
+        .await?;
+
+        let steps = post_id
+            .as_ref()
+            .and_then(|post_id| applied.trace.as_mut()?.remove(post_id))
+            .unwrap_or_default();
+        Ok(Response {
+            jsonrpc: JSONRPC_VERSION.to_string(),
+            content: ResponseContent::FilterExplain {
+                post_id,
+                steps,
+                result: applied.posts.pop(),
+            },
+            id,
+        })
+    }
+
+    /// Describes a filter package without installing it, so that the frontend can show the requested scopes to the user.
+    async fn handle_filter_inspect(
+        &self,
+        id: String,
+        params: FilterInspectParams,
+    ) -> Result<Response, AppError> {
+        let FilterInspectParams { session_key, path } = params;
+        self.administrator(&session_key).await?;
+        let path = package::resolve(self.package_dir.as_deref(), &path)?;
+        let package = self.installer.open(&path)?;
+
+        Ok(Response {
+            jsonrpc: JSONRPC_VERSION.to_string(),
+            content: ResponseContent::FilterPackage(self.installer.inspect(&package)),
+            id,
+        })
+    }
+
+    async fn handle_filter_install(
+        &self,
+        id: String,
+        params: FilterInstallParams,
+        upgrade: bool,
+    ) -> Result<Response, AppError> {
+        let FilterInstallParams {
+            session_key,
+            path,
+            approved_scopes,
+        } = params;
+        self.administrator(&session_key).await?;
+        let path = package::resolve(self.package_dir.as_deref(), &path)?;
+        let package = self.installer.open(&path)?;
+        let info = if upgrade {
+            self.installer.upgrade(package, &approved_scopes).await?
+        } else {
+            self.installer.install(package, &approved_scopes).await?
+        };
+
+        Ok(Response {
+            jsonrpc: JSONRPC_VERSION.to_string(),
+            content: ResponseContent::FilterPackage(info),
+            id,
+        })
+    }
+
+    async fn handle_filter_uninstall(
+        &self,
+        id: String,
+        params: FilterUninstallParams,
+    ) -> Result<Response, AppError> {
+        let FilterUninstallParams {
+            session_key,
+            filter,
+        } = params;
+        self.administrator(&session_key).await?;
+        self.installer.uninstall(&filter).await?;
+
+        Ok(Response {
+            jsonrpc: JSONRPC_VERSION.to_string(),
+            content: ResponseContent::FilterUninstall { filter },
+            id,
+        })
+    }
+
+    /// Approves the filter as it is now in the filter directory, so that it is loaded again after modified.
+    async fn handle_filter_approve(
+        &self,
+        id: String,
+        params: FilterApproveParams,
+    ) -> Result<Response, AppError> {
+        let FilterApproveParams {
+            session_key,
+            filter,
+            approved_scopes,
+        } = params;
+        self.administrator(&session_key).await?;
+        let account = self.store.account_for(&session_key).await?;
+        self.installer.approve(&filter, &approved_scopes).await?;
+
+        let (filters, pending) = self.load_all_filters().await?;
+        let entries = self.pipelines.entries(&account, &filters).await?;
+        Ok(Self::filter_list(id, entries, filters, pending))
+    }
+
+    /// Fails unless the session is of the administrator, as installed filters are shared by every account on the backend.
+    async fn administrator(&self, session_key: &str) -> Result<(), AppError> {
+        if !self.store.administers(session_key).await? {
+            return Err(HandlerError::NotAdministrator.into());
+        }
+        Ok(())
+    }
+
+    /// Loads the filters which have been approved as they are. See `load_all_filters`.
+    async fn load_filters(&self) -> Result<Vec<Filter>, AppError> {
+        Ok(self.load_all_filters().await?.0)
+    }
+
+    /// Loads the filters in the filter directory. Filters which need approval are returned separately, and should not be run.
+    async fn load_all_filters(&self) -> Result<(Vec<Filter>, Vec<PendingFilter>), AppError> {
+        let filters = Filter::load(
+            self.filter_path.as_ref(),
+            &self.scopes,
+            &self.filter_settings,
+        )?;
+        Ok(self.installer.verify(filters).await?)
+    }
+
+    async fn rules_filter(&self, filter_id: &str) -> Result<Filter, AppError> {
+        let filter = self
+            .load_filters()
+            .await?
+            .into_iter()
+            .find(|f| f.id == filter_id)
+            .ok_or_else(|| PipelineError::UnknownFilter(filter_id.to_owned()))?;
+        if filter.meta.kind != FilterKind::Rules {
+            return Err(RulesError::NotRules(filter_id.to_owned()).into());
+        }
+        Ok(filter)
+    }
+
+    /// Loads the filters enabled for the account, in the order of its pipeline, with setting values and storage saved for the account. If `client` is given, filters can call the API through it.
+    async fn pipeline_for(
+        &self,
+        account: &Account,
+        client: Option<Arc<ApiClient>>,
+    ) -> Result<Vec<Filter>, AppError> {
+        let filters = self.load_filters().await?;
+        let entries = self.pipelines.entries(account, &filters).await?;
+        self.arrange_pipeline(account, client, &entries, filters)
+            .await
+    }
+
+    /// Same as `pipeline_for`, but loads only the filters in `dirs`, which are the ones hooking a `v0.plain` call.
+    async fn hook_pipeline_for(
+        &self,
+        account: &Account,
+        client: Arc<ApiClient>,
+        dirs: &[PathBuf],
+    ) -> Result<Vec<Filter>, AppError> {
+        let filters = dirs
+            .iter()
+            .map(|dir| {
+                let configured = self.filter_settings.get(&Filter::id_for(dir));
+                Filter::load_single(dir, &self.scopes, configured)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let (filters, _) = self.installer.verify(filters).await?;
+        // the other filters are not loaded, but still count in the pipeline
+        let installed: Vec<String> = Filter::dirs(self.filter_path.as_ref())?
+            .iter()
+            .map(|dir| Filter::id_for(dir))
+            .collect();
+        let installed: Vec<&str> = installed.iter().map(String::as_str).collect();
+        let entries = self.pipelines.entries_of(account, &installed).await?;
+        self.arrange_pipeline(account, Some(client), &entries, filters)
+            .await
+    }
+
+    // Orders the filters as in the pipeline of the account, leaving out the ones not enabled in it, and gives them the settings, the storage and the rules of the account.
+    async fn arrange_pipeline(
+        &self,
+        account: &Account,
+        client: Option<Arc<ApiClient>>,
+        entries: &[PipelineEntry],
+        filters: Vec<Filter>,
+    ) -> Result<Vec<Filter>, AppError> {
+        let mut filters = pipeline::arrange(entries, filters);
+
+        let saved = self.settings.all(account).await?;
+        for filter in &mut filters {
+            if let Some(values) = saved.get(&filter.id) {
+                // the declared settings might have been changed after the values were saved
+                if let Err(err) = filter.configure(values) {
+                    warn!("ignoring saved settings: {}", err);
+                }
+            }
+            filter.storage = Arc::new(Mutex::new(self.storage.load(account, &filter.id).await?));
+            if filter.meta.kind == FilterKind::Rules {
+                filter.rules = self.rules.load(account, &filter.id).await?;
+            }
+            filter.api = client
+                .clone()
+                .map(|client| FilterApi::new(client, self.api_cache.clone()));
+        }
+
+        Ok(filters)
+    }
+
+    async fn save_storage(&self, account: &Account, filters: &[Filter]) -> Result<(), AppError> {
+        for filter in filters {
+            // SAFETY: the lock is poisoned only when a storage function has panicked, which does not happen
+            let mut storage = std::mem::take(&mut *filter.storage.lock().unwrap());
+            self.storage.save(account, &filter.id, &mut storage).await?;
+        }
+
+        Ok(())
+    }
+
+    fn filter_list(
+        id: String,
+        entries: Vec<PipelineEntry>,
+        filters: Vec<Filter>,
+        pending: Vec<PendingFilter>,
+    ) -> Response {
+        let mut filters: HashMap<String, Filter> =
+            filters.into_iter().map(|f| (f.id.clone(), f)).collect();
+        let filters = entries
+            .into_iter()
+            .filter_map(|entry| {
+                let filter = filters.remove(&entry.filter)?;
+                Some(FilterInfo {
+                    id: filter.id,
+                    name: filter.meta.name,
+                    description: filter.meta.description,
+                    author: filter.meta.author,
+                    version: filter.meta.version,
+                    kind: filter.meta.kind,
+                    scopes: filter.meta.scopes,
+                    settings: filter.meta.settings,
+                    hooks: filter.meta.hooks,
+                    enabled: entry.enabled,
+                })
+            })
+            .collect();
+
+        Response {
+            jsonrpc: JSONRPC_VERSION.to_string(),
+            content: ResponseContent::FilterList { filters, pending },
+            id,
+        }
+    }
 }
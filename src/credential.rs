@@ -10,6 +10,7 @@ use crate::{
     auth::Auth,
     cache::{Cache, CacheManager, CacheManagerError, Credential, CredentialState},
     error::AppError,
+    models::Account,
 };
 
 #[derive(Debug, Error)]
@@ -66,6 +67,12 @@ impl CredentialStore {
         Ok(rec.twitter_id)
     }
 
+    pub async fn account_for(&self, session_key: &str) -> Result<Account, CredentialStoreError> {
+        Account::find_by_session_key(session_key, self.conn.as_ref())
+            .await
+            .map_err(maybe_notfound(session_key.into()))
+    }
+
     // Returns Twitter accounts (id and session key) available to the current user (the account which they were authenticated and ones they own).
     pub async fn accounts(
         &self,
@@ -82,6 +89,21 @@ impl CredentialStore {
         Ok(accounts)
     }
 
+    // Whether the account administers the backend, that is, it is owned by no one and owns every other account.
+    // Changes affecting all accounts (e.g. installing filters) are allowed only to the administrator.
+    pub async fn administers(&self, session_key: &str) -> Result<bool, CredentialStoreError> {
+        let account = self.account_for(session_key).await?;
+        let others = sqlx::query!(
+            r#"select count(*) as "count!" from accounts where id <> $1 and owned_by is distinct from $1"#,
+            account.id
+        )
+        .fetch_one(self.conn.as_ref())
+        .await?
+        .count;
+
+        Ok(account.owned_by.is_none() && others == 0)
+    }
+
     pub async fn client_for(&self, session_key: &str) -> Result<ApiClient, AppError> {
         let rec = sqlx::query!(
             r#"
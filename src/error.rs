@@ -1,6 +1,9 @@
 use crate::{
     api::ApiClientError, auth::AuthError, cache::CacheManagerError, connection::HandlerError,
-    credential::CredentialStoreError, filter::FilterError, ListenerError,
+    credential::CredentialStoreError, filter::FilterError, filter_test::FilterTestError,
+    history::HistoryError, hooks::HookError, marker::MarkerError, package::PackageError,
+    pipeline::PipelineError, rules::RulesError, settings::SettingsError, storage::StorageError,
+    ListenerError,
 };
 use thiserror::Error;
 
@@ -23,6 +26,24 @@ pub enum AppError {
     Handler(#[from] HandlerError),
     #[error("filter error: {0}")]
     Filter(#[from] FilterError),
+    #[error("filter test error: {0}")]
+    FilterTest(#[from] FilterTestError),
+    #[error("history error: {0}")]
+    History(#[from] HistoryError),
+    #[error("hook error: {0}")]
+    Hook(#[from] HookError),
+    #[error("marker error: {0}")]
+    Marker(#[from] MarkerError),
+    #[error("package error: {0}")]
+    Package(#[from] PackageError),
+    #[error("pipeline error: {0}")]
+    Pipeline(#[from] PipelineError),
+    #[error("rules error: {0}")]
+    Rules(#[from] RulesError),
+    #[error("settings error: {0}")]
+    Settings(#[from] SettingsError),
+    #[error("storage error: {0}")]
+    Storage(#[from] StorageError),
     #[error("mlua error: {0}")]
     Lua(#[from] mlua::Error),
     #[error("other IO error: {0}")]
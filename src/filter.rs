@@ -1,29 +1,420 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashSet,
+    collections::{hash_map::Entry, BTreeMap, HashMap, HashSet},
     fs::File,
-    io::Read,
+    io::{ErrorKind, Read},
+    panic::AssertUnwindSafe,
     path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
 };
 use thiserror::Error;
-use tracing::error;
+use tracing::{debug, error, warn};
 
-use crate::{error::AppError, tweet::Tweet};
+use crate::{
+    annotation::{Annotation, Annotations},
+    filter_api::FilterApi,
+    hooks::{self, Decision, Hook, HookError, HookSpec, PlainRequest},
+    modules::{Modules, LIB_DIR},
+    package::FilterFiles,
+    rules::{self, Rule, RulesError},
+    settings::{self, SettingSpec, Settings, SettingsError},
+    stdlib,
+    storage::Storage,
+    timeline::TimelineContext,
+    trace::{self, StepResult, TraceStep},
+    tweet::{Page, Tweet},
+    wasm::{WasmError, WasmRuntime},
+    VERSION,
+};
 use mlua::prelude::*;
+use rayon::ThreadPool;
+use semver::Version;
 
 #[derive(Debug)]
 pub struct Filter {
-    pub src: String,
+    /// The name of the directory which the filter resides in. This is used to refer to the filter in pipelines.
+    pub id: String,
+    /// Applies the filter, depending on its kind.
+    pub runtime: Box<dyn FilterRuntime>,
     pub meta: FilterMeta,
+    /// Values for the settings declared in the metadata. These are exposed to the script as `config`.
+    pub settings: Settings,
+    /// Key-value pairs the filter keeps between runs. These are exposed to the script as `storage`.
+    pub storage: Arc<Mutex<Storage>>,
+    /// Access to the API on behalf of the account, exposed to the script as `api`. Calls fail if this is not set.
+    pub api: Option<FilterApi>,
+    /// Modules available to the scripts via `require`.
+    pub modules: Arc<Modules>,
+    /// Rules added via RPC for the account, evaluated after the ones in the metadata. Only used by rules filters.
+    pub rules: Vec<Rule>,
+    /// Hooks declared in the metadata, along with their scripts.
+    pub hooks: Vec<Hook>,
+    /// The hash of the files the filter was loaded from, including the shared modules. See `FilterFiles::hash`.
+    pub hash: String,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct FilterMeta {
-    name: String,
-    description: String,
-    author: String,
-    entrypoint: String,
-    scopes: HashSet<String>,
+    pub name: String,
+    pub description: String,
+    pub author: String,
+    /// The version of the filter, in semver.
+    pub version: Option<String>,
+    /// The oldest version of the backend which the filter works with.
+    pub min_backend_version: Option<String>,
+    #[serde(default, alias = "runtime")]
+    pub kind: FilterKind,
+    entrypoint: Option<String>,
+    batch_entrypoint: Option<String>,
+    pub scopes: HashSet<String>,
+    #[serde(default)]
+    pub on_error: ErrorPolicy,
+    #[serde(default)]
+    pub settings: BTreeMap<String, SettingSpec>,
+    /// Rules of a rules filter.
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+    /// Scripts run on `v0.plain` calls to the matching endpoints.
+    #[serde(default)]
+    pub hooks: Vec<HookSpec>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterKind {
+    /// Lua scripts given by `entrypoint` and `batch_entrypoint`.
+    #[default]
+    Lua,
+    /// Rules declared in `binchotan.toml`, evaluated without Lua.
+    Rules,
+    /// A WebAssembly module given by `entrypoint`, run in a sandbox.
+    Wasm,
+}
+
+/// Posts returned by a batch script, and the posts which are not in them along with the reasons given by the script.
+pub type Batch = (Vec<Tweet>, Vec<(Tweet, Option<String>)>);
+
+/// State of a task applying filters to posts in turn. A Lua state is prepared once for each filter and reused for the following posts, while each post gets fresh globals.
+#[derive(Default)]
+pub struct Worker {
+    // keyed by filter ids
+    lua: HashMap<String, LuaVm>,
+    // lines printed by the filter being run, until taken after the run
+    output: Arc<Mutex<Vec<String>>>,
+}
+
+impl Worker {
+    /// Returns where the lines printed by the filter are kept. Runtimes other than Lua push lines here.
+    pub fn output(&self) -> Arc<Mutex<Vec<String>>> {
+        self.output.clone()
+    }
+
+    fn take_output(&self) -> Vec<String> {
+        std::mem::take(&mut *self.output.lock().unwrap())
+    }
+}
+
+// A Lua state prepared for a filter, along with the annotations made by the script in it.
+struct LuaVm {
+    lua: Lua,
+    annotations: Arc<Mutex<Annotations>>,
+}
+
+/// Applies filters of a kind. The filter is given to each call so that the runtime can use its settings, storage and so on.
+pub trait FilterRuntime: std::fmt::Debug + Send + Sync {
+    /// Whether the filter is applied to each post with `run`.
+    fn per_post(&self) -> bool;
+
+    /// Whether the filter is applied to the whole list of posts with `run_batch`.
+    fn batch(&self) -> bool {
+        false
+    }
+
+    fn run(
+        &self,
+        filter: &Filter,
+        tweet: &Tweet,
+        page: &Page,
+        worker: &mut Worker,
+    ) -> Result<Outcome, FilterError>;
+
+    fn run_batch(
+        &self,
+        _filter: &Filter,
+        tweets: &[Tweet],
+        _page: &Page,
+        _worker: &mut Worker,
+    ) -> Result<Batch, FilterError> {
+        Ok((tweets.to_vec(), vec![]))
+    }
+}
+
+/// Lua scripts given by `entrypoint` and `batch_entrypoint`. Batch scripts get a new Lua state for each run, and per-post scripts a new environment in the state of the worker.
+#[derive(Debug, Default)]
+pub struct LuaRuntime {
+    /// The script run on each post.
+    pub src: Option<String>,
+    /// The script run on the whole list of posts.
+    pub batch_src: Option<String>,
+}
+
+impl FilterRuntime for LuaRuntime {
+    fn per_post(&self) -> bool {
+        self.src.is_some()
+    }
+
+    fn batch(&self) -> bool {
+        self.batch_src.is_some()
+    }
+
+    /// The script returns a Tweet or null.
+    fn run(
+        &self,
+        filter: &Filter,
+        tweet: &Tweet,
+        page: &Page,
+        worker: &mut Worker,
+    ) -> Result<Outcome, FilterError> {
+        let Some(src) = &self.src else {
+            return Ok(Outcome::Keep(tweet.clone()));
+        };
+
+        let LuaVm { lua, annotations } = match worker.lua.entry(filter.id.clone()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let annotations = Arc::new(Mutex::new(Annotations::default()));
+                let lua = filter.prepare_lua(page, annotations.clone(), worker.output.clone())?;
+                entry.insert(LuaVm { lua, annotations })
+            }
+        };
+        let env = filter.fresh_env(lua, page)?;
+        env.set("post", lua.to_value(&page.hydrate(tweet))?)?;
+        let ret = lua
+            .load(src)
+            .set_name(filter.chunk_name(&filter.meta.entrypoint))?
+            .set_environment(env)?
+            .eval()?;
+        let v: Option<Tweet> = lua.from_value(ret)?;
+
+        // annotations made on other posts are discarded, as they are when the state is not reused
+        let annotation = tweet
+            .id()
+            .and_then(|id| std::mem::take(&mut *annotations.lock().unwrap()).take(id));
+        Ok(match v {
+            Some(mut t) => {
+                filter.finish(&mut t, Some(tweet), annotation);
+                Outcome::Keep(t)
+            }
+            None => Outcome::Drop(annotation.and_then(|a| a.reason)),
+        })
+    }
+
+    /// The script receives the hydrated posts as `posts` and returns a new list of posts.
+    fn run_batch(
+        &self,
+        filter: &Filter,
+        tweets: &[Tweet],
+        page: &Page,
+        worker: &mut Worker,
+    ) -> Result<Batch, FilterError> {
+        let Some(src) = &self.batch_src else {
+            return Ok((tweets.to_vec(), vec![]));
+        };
+
+        let annotations = Arc::new(Mutex::new(Annotations::default()));
+        let lua = filter.prepare_lua(page, annotations.clone(), worker.output())?;
+        let hydrated: Vec<Tweet> = tweets.iter().map(|t| page.hydrate(t)).collect();
+        lua.globals().set("posts", lua.to_value(&hydrated)?)?;
+        let ret = lua
+            .load(src)
+            .set_name(filter.chunk_name(&filter.meta.batch_entrypoint))?
+            .eval()?;
+        let mut result: Vec<Tweet> = lua.from_value(ret)?;
+
+        let mut originals: HashMap<&str, &Tweet> =
+            tweets.iter().filter_map(|t| Some((t.id()?, t))).collect();
+        let mut annotations = annotations.lock().unwrap();
+        for t in &mut result {
+            let id = t.id().map(String::from);
+            let original = id.as_deref().and_then(|id| originals.remove(id));
+            let annotation = id.and_then(|id| annotations.take(&id));
+            filter.finish(t, original, annotation);
+        }
+        let dropped = tweets
+            .iter()
+            .filter_map(|t| {
+                let id = t.id()?;
+                originals.contains_key(id).then(|| {
+                    let reason = annotations.take(id).and_then(|a| a.reason);
+                    (t.clone(), reason)
+                })
+            })
+            .collect();
+
+        Ok((result, dropped))
+    }
+}
+
+/// Rules in the metadata, followed by the ones saved for the account.
+#[derive(Debug)]
+pub struct RulesRuntime;
+
+impl FilterRuntime for RulesRuntime {
+    fn per_post(&self) -> bool {
+        true
+    }
+
+    fn run(
+        &self,
+        filter: &Filter,
+        tweet: &Tweet,
+        page: &Page,
+        _worker: &mut Worker,
+    ) -> Result<Outcome, FilterError> {
+        let hydrated = page.hydrate(tweet);
+        let verdict = rules::evaluate(
+            filter.meta.rules.iter().chain(&filter.rules),
+            hydrated.as_value(),
+        );
+        Ok(match verdict.drop {
+            Some(reason) => Outcome::Drop(reason),
+            None => {
+                let mut t = tweet.clone();
+                if let Some(annotation) = verdict.annotation {
+                    t.annotate(&filter.id, annotation);
+                }
+                Outcome::Keep(t)
+            }
+        })
+    }
+}
+
+impl FilterMeta {
+    /// Reads `binchotan.toml` in the directory.
+    pub fn read(dir: &Path) -> Result<Self, FilterError> {
+        let mut buf = String::new();
+        File::open(dir.join("binchotan.toml"))?.read_to_string(&mut buf)?;
+        toml::from_str(&buf).map_err(FilterError::MetaParse)
+    }
+
+    fn parse(src: &[u8]) -> Result<Self, FilterError> {
+        let src = std::str::from_utf8(src)
+            .map_err(|err| FilterError::Io(std::io::Error::new(ErrorKind::InvalidData, err)))?;
+        toml::from_str(src).map_err(FilterError::MetaParse)
+    }
+
+    /// Ensures that the versions are valid and that the filter works with this backend.
+    pub fn check_versions(&self) -> Result<(), FilterError> {
+        let parse = |version: &str| {
+            Version::parse(version)
+                .map_err(|err| FilterError::InvalidVersion(self.name.clone(), err))
+        };
+        if let Some(version) = &self.version {
+            parse(version)?;
+        }
+        if let Some(required) = &self.min_backend_version {
+            // SAFETY: VERSION is a valid version
+            if Version::parse(VERSION).unwrap() < parse(required)? {
+                return Err(FilterError::Incompatible(
+                    self.name.clone(),
+                    required.clone(),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What to do with a post when a filter raises an error on it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorPolicy {
+    /// Pass the post to the next filter as if the failing filter were not there.
+    #[default]
+    SkipFilter,
+    /// Remove the post from the timeline.
+    DropPost,
+    /// Abort the whole request.
+    Fail,
+}
+
+/// The result of applying a filter on a post.
+#[derive(Debug)]
+pub enum Outcome {
+    Keep(Tweet),
+    /// The post is dropped, with the reason given by the filter if any.
+    Drop(Option<String>),
+}
+
+/// Posts which survived the filters, along with the ones dropped and errors raised on the way.
+#[derive(Debug, Default)]
+pub struct Applied {
+    pub posts: Vec<Tweet>,
+    /// Dropped posts, marked with the filter which dropped them.
+    pub dropped: Vec<Tweet>,
+    pub failures: Vec<FilterFailure>,
+    /// What each filter did to each post, keyed by post ids. This is recorded only when tracing is enabled.
+    pub trace: Option<HashMap<String, Vec<TraceStep>>>,
+    // positions of the posts in the original list, keyed by ids
+    positions: HashMap<String, usize>,
+}
+
+impl Applied {
+    fn record(&mut self, post_id: Option<&str>, step: TraceStep) {
+        if let (Some(trace), Some(id)) = (&mut self.trace, post_id) {
+            trace.entry(id.to_owned()).or_default().push(step);
+        }
+    }
+
+    /// Returns the surviving posts. If `include_dropped` is set, dropped posts are put back around their original positions.
+    pub fn into_posts(self, include_dropped: bool) -> Vec<Tweet> {
+        let Applied {
+            posts,
+            mut dropped,
+            positions,
+            ..
+        } = self;
+        if !include_dropped {
+            return posts;
+        }
+
+        let position = |t: &Tweet| t.id().and_then(|id| positions.get(id)).copied();
+        dropped.sort_by_key(|t| position(t).unwrap_or(usize::MAX));
+        let mut dropped = dropped.into_iter().peekable();
+        let mut merged = vec![];
+        for post in posts {
+            if let Some(p) = position(&post) {
+                while let Some(d) = dropped.next_if(|d| position(d).is_some_and(|dp| dp < p)) {
+                    merged.push(d);
+                }
+            }
+            merged.push(post);
+        }
+        merged.extend(dropped);
+
+        merged
+    }
+}
+
+// What the per-post filters did to a single post.
+struct Chain {
+    post: Tweet,
+    dropped: bool,
+    // trace steps along with the ids of the posts given to the filters
+    steps: Vec<(Option<String>, TraceStep)>,
+    failures: Vec<FilterFailure>,
+}
+
+/// An error raised by a filter which did not abort the request. These are returned to the frontend so that it can point out the broken filter.
+#[derive(Debug, Serialize)]
+pub struct FilterFailure {
+    pub filter: String,
+    pub post_id: Option<String>,
+    pub message: String,
 }
 
 // TODO: use struct?
@@ -31,68 +422,1267 @@ pub struct FilterMeta {
 pub enum FilterError {
     #[error("the given path ({0}) is not a directory")]
     PathNotDir(PathBuf),
+    #[error("{0} is a symlink, which filters cannot contain")]
+    Symlink(PathBuf),
     #[error("could not parse binchotan.toml")]
     MetaParse(toml::de::Error),
+    #[error("filter `{0}` has neither `entrypoint` nor `batch_entrypoint`")]
+    NoEntrypoint(String),
     #[error("Filter `{0}` requires an additional API scopes (permissions): {}. Review the filter and add scopes in your config if you want to.", .1.join(","))]
     InsufficientScopes(String, Vec<String>),
+    #[error("invalid settings for filter `{0}`: {1}")]
+    InvalidSettings(String, SettingsError),
+    #[error("invalid version in filter `{0}`: {1}")]
+    InvalidVersion(String, semver::Error),
+    #[error(
+        "filter `{0}` requires binchotan-backend {1} or later, but this is {}",
+        VERSION
+    )]
+    Incompatible(String, String),
+    #[error("invalid WebAssembly module in filter `{0}`: {1}")]
+    InvalidWasm(String, WasmError),
+    #[error("invalid rules for filter `{0}`: {1}")]
+    InvalidRules(String, RulesError),
+    #[error("filter `{0}` failed: {1}")]
+    Run(String, Box<FilterError>),
     #[error(transparent)]
     Io(#[from] std::io::Error),
     #[error(transparent)]
     Lua(#[from] mlua::Error),
+    #[error(transparent)]
+    Wasm(#[from] WasmError),
+    #[error("applying filters was aborted")]
+    Aborted,
 }
 
 impl Filter {
+    /// Loads every filter in the directory. `configured` holds setting values given in the config file, keyed by filter ids.
     pub fn load(
         dir: &Path,
         available_scopes: &HashSet<String>,
+        configured: &HashMap<String, Settings>,
     ) -> Result<Vec<Filter>, FilterError> {
+        Self::dirs(dir)?
+            .into_iter()
+            .map(|dir| {
+                let configured = configured.get(&Self::id_for(&dir));
+                match Self::load_single(&dir, available_scopes, configured) {
+                    Ok(filter) => Ok(filter),
+                    Err(err) => {
+                        error!("could not load filter in {}/ : {}", dir.display(), err);
+                        Err(err)
+                    }
+                }
+            })
+            .collect()
+    }
+
+    /// Lists the directories of the filters in `dir` which declare hooks running on the request, reading only their `binchotan.toml`.
+    /// Filters whose `binchotan.toml` cannot be read are skipped, so that they do not break requests which they have nothing to do with.
+    pub fn hooking(dir: &Path, request: &PlainRequest) -> Result<Vec<PathBuf>, FilterError> {
+        Ok(Self::dirs(dir)?
+            .into_iter()
+            .filter(|dir| match FilterMeta::read(dir) {
+                Ok(meta) => meta.hooks.iter().any(|hook| hook.hooks(request)),
+                Err(err) => {
+                    warn!("skipping filter in {}/ : {}", dir.display(), err);
+                    false
+                }
+            })
+            .collect())
+    }
+
+    /// The directories of the filters in `dir`, sorted. They may not be valid filters.
+    pub fn dirs(dir: &Path) -> Result<Vec<PathBuf>, FilterError> {
         if !dir.is_dir() {
             return Err(FilterError::PathNotDir(dir.to_owned()));
         }
 
-        dir.read_dir()?
+        let mut dirs: Vec<PathBuf> = dir
+            .read_dir()?
             .filter_map(|entry| match entry {
                 Ok(entry) => Some(entry.path()),
                 _ => None,
             })
-            .filter(|path| path.is_dir())
-            .map(|dir| match Self::load_single(&dir, available_scopes) {
-                Ok(filter) => Ok(filter),
-                Err(err) => {
-                    error!("could not load filter in {}/ : {}", dir.display(), err);
-                    Err(err)
-                }
+            // directories such as `_lib` and ones being installed are not filters
+            .filter(|path| {
+                let id = Self::id_for(path);
+                path.is_dir() && !id.starts_with('_') && !id.starts_with('.')
             })
-            .collect()
+            .collect();
+        // read_dir does not guarantee any order
+        dirs.sort();
+        Ok(dirs)
     }
 
-    fn load_single(dir: &Path, available_scopes: &HashSet<String>) -> Result<Filter, FilterError> {
+    /// Loads the filter in the directory. `configured` holds the setting values given in the config file.
+    pub fn load_single(
+        dir: &Path,
+        available_scopes: &HashSet<String>,
+        configured: Option<&Settings>,
+    ) -> Result<Filter, FilterError> {
         if !dir.is_dir() {
             return Err(FilterError::PathNotDir(dir.to_owned()));
         }
 
-        let meta_path = dir.join("binchotan.toml");
-        let mut meta_buf = String::new();
-        File::open(&meta_path)?.read_to_string(&mut meta_buf)?;
-        let meta: FilterMeta = toml::from_str(&meta_buf).map_err(FilterError::MetaParse)?;
+        // everything is read at once, so that the files run are the ones hashed
+        let lib = dir.parent().unwrap_or(dir).join(LIB_DIR);
+        let files = Arc::new(FilterFiles::read(dir, &lib)?);
+        let read = |path: &str| -> Result<&[u8], FilterError> {
+            files.get(path).ok_or_else(|| {
+                let path = dir.join(path);
+                FilterError::Io(std::io::Error::new(
+                    ErrorKind::NotFound,
+                    format!("{} not found", path.display()),
+                ))
+            })
+        };
+
+        let mut meta = FilterMeta::parse(read("binchotan.toml")?)?;
+        meta.check_versions()?;
+        let read_src = |path: &Option<String>| -> Result<Option<String>, FilterError> {
+            let Some(path) = path else {
+                return Ok(None);
+            };
+            let src = String::from_utf8(read(path)?.to_vec())
+                .map_err(|err| FilterError::Io(std::io::Error::new(ErrorKind::InvalidData, err)))?;
+            Ok(Some(src))
+        };
+        let runtime: Box<dyn FilterRuntime> = match meta.kind {
+            // filters which only have hooks do nothing on timelines
+            FilterKind::Lua
+                if meta.entrypoint.is_none()
+                    && meta.batch_entrypoint.is_none()
+                    && meta.hooks.is_empty() =>
+            {
+                return Err(FilterError::NoEntrypoint(meta.name));
+            }
+            FilterKind::Lua => Box::new(LuaRuntime {
+                src: read_src(&meta.entrypoint)?,
+                batch_src: read_src(&meta.batch_entrypoint)?,
+            }),
+            FilterKind::Rules => {
+                for rule in &mut meta.rules {
+                    rule.when
+                        .prepare()
+                        .map_err(|err| FilterError::InvalidRules(meta.name.clone(), err))?;
+                }
+                Box::new(RulesRuntime)
+            }
+            FilterKind::Wasm => {
+                let Some(entrypoint) = &meta.entrypoint else {
+                    return Err(FilterError::NoEntrypoint(meta.name));
+                };
+                let module = read(entrypoint)?;
+                Box::new(
+                    WasmRuntime::new(module)
+                        .map_err(|err| FilterError::InvalidWasm(meta.name.clone(), err))?,
+                )
+            }
+        };
 
-        let mut src = String::new();
-        File::open(&dir.join(&meta.entrypoint))?.read_to_string(&mut src)?;
+        let hooks = meta
+            .hooks
+            .iter()
+            .map(|spec| {
+                // SAFETY: the path is given
+                let src = read_src(&Some(spec.entrypoint.clone()))?.unwrap();
+                Ok(Hook {
+                    spec: spec.clone(),
+                    src,
+                })
+            })
+            .collect::<Result<Vec<_>, FilterError>>()?;
 
         let diff: Vec<String> = meta.scopes.difference(available_scopes).cloned().collect();
         if !diff.is_empty() {
             return Err(FilterError::InsufficientScopes(meta.name, diff));
         }
 
-        Ok(Filter { src, meta })
+        let settings = settings::defaults(&meta.settings);
+        settings::validate(&meta.settings, &settings)
+            .map_err(|err| FilterError::InvalidSettings(meta.name.clone(), err))?;
+
+        let id = Self::id_for(dir);
+        let mut filter = Filter {
+            hash: files.hash(),
+            modules: Arc::new(Modules::new(&id, files)),
+            id,
+            runtime,
+            meta,
+            settings,
+            storage: Arc::default(),
+            api: None,
+            rules: vec![],
+            hooks,
+        };
+        if let Some(values) = configured {
+            filter.configure(values)?;
+        }
+
+        Ok(filter)
+    }
+
+    pub fn id_for(dir: &Path) -> String {
+        dir.file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    }
+
+    /// Overrides the setting values after validating them.
+    pub fn configure(&mut self, values: &Settings) -> Result<(), FilterError> {
+        settings::validate(&self.meta.settings, values)
+            .map_err(|err| FilterError::InvalidSettings(self.meta.name.clone(), err))?;
+        self.settings
+            .extend(values.iter().map(|(k, v)| (k.clone(), v.clone())));
+        Ok(())
+    }
+
+    /// Applies the filter on the given post.
+    /// The post is hydrated with the data in `includes` of the page, and `includes` and `meta` of the page are also available to the filter.
+    pub fn run(
+        &self,
+        tweet: &Tweet,
+        page: &Page,
+        worker: &mut Worker,
+    ) -> Result<Outcome, FilterError> {
+        self.runtime.run(self, tweet, page, worker)
+    }
+
+    /// Applies the filter on the whole list of posts. Returns the new list, and the posts which are not in the list along with the reasons given by the filter.
+    pub fn run_batch(
+        &self,
+        tweets: &[Tweet],
+        page: &Page,
+        worker: &mut Worker,
+    ) -> Result<Batch, FilterError> {
+        self.runtime.run_batch(self, tweets, page, worker)
+    }
+
+    /// Runs a hook of the filter on a `v0.plain` call. The script receives `request`, and `response` for response hooks, and returns the new body of the request or the new response. Returns none if the script returns nil.
+    pub fn run_hook(
+        &self,
+        hook: &Hook,
+        request: &PlainRequest,
+        response: Option<&serde_json::Value>,
+        confirmed: &HashSet<String>,
+    ) -> Result<Option<serde_json::Value>, HookError> {
+        let meta = serde_json::Value::Null;
+        let page =
+            Page::new(None, &meta).with_context(TimelineContext::for_endpoint(&request.endpoint));
+        let output = Arc::default();
+        let decision = Arc::new(Mutex::new(None));
+        let run = || -> Result<Option<serde_json::Value>, FilterError> {
+            let lua = self.prepare_lua(&page, Arc::default(), Arc::clone(&output))?;
+            lua.globals().set("request", lua.to_value(request)?)?;
+            if let Some(response) = response {
+                lua.globals().set("response", lua.to_value(response)?)?;
+            }
+            lua.globals().set(
+                "hook",
+                hooks::create_lua_table(&lua, &self.id, request, confirmed, decision.clone())?,
+            )?;
+            let ret = lua
+                .load(&hook.src)
+                .set_name(self.chunk_name(&Some(hook.spec.entrypoint.clone())))?
+                .eval()?;
+            Ok(lua.from_value(ret)?)
+        };
+        let result = run();
+
+        for line in std::mem::take(&mut *output.lock().unwrap()) {
+            debug!("[{}] {}", self.id, line);
+        }
+        let decision = decision.lock().unwrap().take();
+        match decision {
+            Some(Decision::Reject(reason)) => {
+                Err(HookError::Rejected(self.meta.name.clone(), reason))
+            }
+            Some(Decision::Confirm(message, token)) => Err(HookError::ConfirmationRequired(
+                self.meta.name.clone(),
+                message,
+                token,
+            )),
+            None => result.map_err(|err| HookError::Run(self.meta.name.clone(), Box::new(err))),
+        }
     }
 
-    /// Applies the filter on the given post. The filter is a Lua script which returns a Tweet or null.
-    pub fn run(&self, tweet: &Tweet) -> Result<Option<Tweet>, FilterError> {
+    fn prepare_lua(
+        &self,
+        page: &Page,
+        annotations: Arc<Mutex<Annotations>>,
+        output: Arc<Mutex<Vec<String>>>,
+    ) -> Result<Lua, FilterError> {
         let lua = Lua::new();
-        lua.globals().set("post", lua.to_value(tweet)?)?;
-        let ret = lua.load(&self.src).eval()?;
-        let v: Option<Tweet> = lua.from_value(ret)?;
-        Ok(v)
+        self.set_page(&lua, &lua.globals(), page)?;
+        lua.globals().set(
+            "storage",
+            Storage::create_lua_table(&lua, self.storage.clone())?,
+        )?;
+        lua.globals().set(
+            "annotate",
+            Annotations::create_lua_table(&lua, annotations)?,
+        )?;
+        lua.globals().set(
+            "api",
+            FilterApi::create_lua_table(&lua, self.api.clone(), self.meta.scopes.clone())?,
+        )?;
+        let lib = stdlib::create_lua_table(&lua)?;
+        lua.globals().set("binchotan", lib.clone())?;
+        // also available as `require "binchotan"`
+        lua.globals()
+            .get::<_, LuaTable>("package")?
+            .get::<_, LuaTable>("loaded")?
+            .set("binchotan", lib)?;
+
+        Modules::install(&lua, self.modules.clone())?;
+
+        let print = lua.create_function(move |lua, args: LuaMultiValue| {
+            let tostring: LuaFunction = lua.globals().get("tostring")?;
+            let line = args
+                .into_iter()
+                .map(|arg| tostring.call::<_, String>(arg))
+                .collect::<LuaResult<Vec<_>>>()?
+                .join("\t");
+            output.lock().unwrap().push(line);
+            Ok(())
+        })?;
+        lua.globals().set("print", print)?;
+
+        Ok(lua)
+    }
+
+    // Sets the values of the page and the settings, which scripts may modify, as globals in `table`.
+    fn set_page(&self, lua: &Lua, table: &LuaTable, page: &Page) -> Result<(), FilterError> {
+        table.set("includes", lua.to_value(&page.includes)?)?;
+        table.set("meta", lua.to_value(page.meta)?)?;
+        table.set("context", lua.to_value(&page.context)?)?;
+        table.set("config", lua.to_value(&self.settings)?)?;
+        Ok(())
+    }
+
+    // Creates the globals for a run in a state prepared with `prepare_lua`. The globals of the state are only read through the metatable, and whatever the script sets, including modules loaded with `require`, is discarded with the returned table.
+    fn fresh_env<'lua>(&self, lua: &'lua Lua, page: &Page) -> Result<LuaTable<'lua>, FilterError> {
+        let inherit = |parent: LuaTable<'lua>| -> LuaResult<LuaTable<'lua>> {
+            let table = lua.create_table()?;
+            let meta = lua.create_table()?;
+            meta.set("__index", parent)?;
+            table.set_metatable(Some(meta));
+            Ok(table)
+        };
+
+        let globals = lua.globals();
+        let package: LuaTable = globals.get("package")?;
+        let env_package = inherit(package.clone())?;
+        env_package.set("loaded", inherit(package.get("loaded")?)?)?;
+        let env = inherit(globals)?;
+        env.set("package", env_package)?;
+        env.set("_G", env.clone())?;
+        self.set_page(lua, &env, page)?;
+        Modules::use_env(lua, env.clone())?;
+
+        Ok(env)
+    }
+
+    // names the chunk after the script so that errors point to the file, e.g. `mute_word/main.lua:3: ...`
+    fn chunk_name(&self, path: &Option<String>) -> String {
+        format!("@{}/{}", self.id, path.as_deref().unwrap_or_default())
+    }
+
+    /// Cleans up a post returned by the script: removes the hydrated fields, restores the `binchotan` field of the original post and puts the annotation there.
+    pub fn finish(
+        &self,
+        result: &mut Tweet,
+        original: Option<&Tweet>,
+        annotation: Option<Annotation>,
+    ) {
+        result.dehydrate();
+        match original {
+            Some(original) => result.restore_namespace(original),
+            None => result.clear_namespace(),
+        }
+        if let Some(annotation) = annotation {
+            result.annotate(&self.id, annotation);
+        }
+    }
+
+    /// Applies the filters in order. Filters with a batch script run on the whole list at once, while consecutive per-post scripts are applied to each post in turn.
+    /// Errors are handled according to the `on_error` policy of the failing filter; ones which did not abort the request are returned along with the remaining posts.
+    /// If `trace` is set, what each filter did to each post is recorded as well.
+    /// If `pool` is given, per-post scripts are applied to as many posts in parallel as it has threads, while the order of the posts is kept.
+    pub fn apply_all(
+        filters: &[Filter],
+        tweets: Vec<Tweet>,
+        page: &Page,
+        trace: bool,
+        pool: Option<&ThreadPool>,
+    ) -> Result<Applied, FilterError> {
+        let mut applied = Applied {
+            positions: tweets
+                .iter()
+                .enumerate()
+                .filter_map(|(i, t)| Some((t.id()?.to_owned(), i)))
+                .collect(),
+            trace: trace.then(HashMap::new),
+            ..Default::default()
+        };
+
+        let mut tweets = tweets;
+        let mut per_post: Vec<&Filter> = vec![];
+        for filter in filters {
+            if filter.runtime.per_post() {
+                per_post.push(filter);
+            }
+            if filter.runtime.batch() {
+                tweets = Self::apply_per_post(&per_post, tweets, page, pool, &mut applied)?;
+                per_post.clear();
+                tweets = Self::apply_batch(filter, tweets, page, &mut applied)?;
+            }
+        }
+        applied.posts = Self::apply_per_post(&per_post, tweets, page, pool, &mut applied)?;
+
+        Ok(applied)
+    }
+
+    /// Runs `apply_all` on the pool so that filters do not block the async runtime. The pool is shared by every request, so filters never use more threads than it has.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn apply_blocking(
+        filters: Arc<Vec<Filter>>,
+        tweets: Vec<Tweet>,
+        includes: Option<serde_json::Value>,
+        meta: serde_json::Value,
+        context: TimelineContext,
+        trace: bool,
+        pool: Arc<ThreadPool>,
+    ) -> Result<Applied, FilterError> {
+        // filters calling the API need the async runtime
+        let handle = tokio::runtime::Handle::current();
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let workers = pool.clone();
+        pool.spawn(move || {
+            let _guard = handle.enter();
+            let applied = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                let page = Page::new(includes.as_ref(), &meta).with_context(context);
+                Self::apply_all(&filters, tweets, &page, trace, Some(&workers))
+            }));
+            // the request might have been dropped already
+            let _ = tx.send(applied.unwrap_or(Err(FilterError::Aborted)));
+        });
+        rx.await.unwrap_or(Err(FilterError::Aborted))
+    }
+
+    fn apply_per_post(
+        filters: &[&Filter],
+        tweets: Vec<Tweet>,
+        page: &Page,
+        pool: Option<&ThreadPool>,
+        applied: &mut Applied,
+    ) -> Result<Vec<Tweet>, FilterError> {
+        if filters.is_empty() {
+            return Ok(tweets);
+        }
+
+        let trace = applied.trace.is_some();
+        let chains = match pool {
+            Some(pool) if pool.current_num_threads() > 1 && tweets.len() > 1 => {
+                Self::apply_parallel(filters, tweets, page, trace, pool)
+                    .into_iter()
+                    .collect::<Result<Vec<_>, _>>()?
+            }
+            _ => {
+                let mut worker = Worker::default();
+                tweets
+                    .into_iter()
+                    .map(|tweet| Self::apply_chain(filters, tweet, page, trace, &mut worker))
+                    .collect::<Result<Vec<_>, _>>()?
+            }
+        };
+
+        let mut filtered = vec![];
+        for chain in chains {
+            for (post_id, step) in chain.steps {
+                applied.record(post_id.as_deref(), step);
+            }
+            applied.failures.extend(chain.failures);
+            if chain.dropped {
+                applied.dropped.push(chain.post);
+            } else {
+                filtered.push(chain.post);
+            }
+        }
+
+        Ok(filtered)
+    }
+
+    // Applies the filters to the posts with as many tasks as the pool has threads. Each task takes the next post in turn, and the results are returned in the original order.
+    // Once a filter fails the whole request, no more posts are taken.
+    fn apply_parallel(
+        filters: &[&Filter],
+        tweets: Vec<Tweet>,
+        page: &Page,
+        trace: bool,
+        pool: &ThreadPool,
+    ) -> Vec<Result<Chain, FilterError>> {
+        // filters calling the API need the async runtime
+        let handle = tokio::runtime::Handle::try_current().ok();
+        let tasks = pool.current_num_threads().min(tweets.len());
+        let slots: Vec<Mutex<Option<Tweet>>> =
+            tweets.into_iter().map(|t| Mutex::new(Some(t))).collect();
+        let next = AtomicUsize::new(0);
+        let abort = AtomicBool::new(false);
+        let done = Mutex::new(vec![]);
+
+        pool.scope(|scope| {
+            for _ in 0..tasks {
+                scope.spawn(|_| {
+                    let _guard = handle.as_ref().map(|handle| handle.enter());
+                    let mut worker = Worker::default();
+                    while !abort.load(Ordering::Relaxed) {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(slot) = slots.get(i) else {
+                            break;
+                        };
+                        // SAFETY: each index is taken only once
+                        let tweet = slot.lock().unwrap().take().unwrap();
+                        let chain = Self::apply_chain(filters, tweet, page, trace, &mut worker);
+                        if chain.is_err() {
+                            abort.store(true, Ordering::Relaxed);
+                        }
+                        done.lock().unwrap().push((i, chain));
+                    }
+                });
+            }
+        });
+
+        let mut chains: Vec<Option<Result<Chain, FilterError>>> =
+            slots.iter().map(|_| None).collect();
+        for (i, chain) in done.into_inner().unwrap() {
+            chains[i] = Some(chain);
+        }
+        // posts are taken in order, so the ones left after aborting come after the failed one
+        chains.into_iter().flatten().collect()
+    }
+
+    // Applies the filters to a single post in order.
+    fn apply_chain(
+        filters: &[&Filter],
+        tweet: Tweet,
+        page: &Page,
+        trace: bool,
+        worker: &mut Worker,
+    ) -> Result<Chain, FilterError> {
+        let mut chain = Chain {
+            post: tweet,
+            dropped: false,
+            steps: vec![],
+            failures: vec![],
+        };
+        for filter in filters {
+            let started = Instant::now();
+            let outcome = filter.run(&chain.post, page, worker);
+            let mut step = filter.step(started, worker);
+            let post_id = chain.post.id().map(String::from);
+
+            match outcome {
+                Ok(Outcome::Keep(t)) => {
+                    if trace {
+                        step.diff = trace::diff(chain.post.as_value(), t.as_value());
+                        if !step.diff.is_empty() {
+                            step.result = StepResult::Changed;
+                        }
+                        chain.steps.push((post_id, step));
+                    }
+                    chain.post = t;
+                }
+                Ok(Outcome::Drop(reason)) => {
+                    step.result = StepResult::Dropped;
+                    step.reason = reason.clone();
+                    if trace {
+                        chain.steps.push((post_id, step));
+                    }
+                    chain.post.mark_dropped(&filter.id, reason);
+                    chain.dropped = true;
+                    break;
+                }
+                Err(err) => {
+                    let message = err.to_string();
+                    step.result = StepResult::Failed;
+                    step.reason = Some(message.clone());
+                    if trace {
+                        chain.steps.push((post_id.clone(), step));
+                    }
+                    if filter.handle_error(err, post_id, &mut chain.failures)?
+                        == ErrorPolicy::DropPost
+                    {
+                        chain.post.mark_dropped(&filter.id, Some(message));
+                        chain.dropped = true;
+                        break;
+                    }
+                }
+            }
+        }
+
+        Ok(chain)
+    }
+
+    fn apply_batch(
+        filter: &Filter,
+        tweets: Vec<Tweet>,
+        page: &Page,
+        applied: &mut Applied,
+    ) -> Result<Vec<Tweet>, FilterError> {
+        let mut worker = Worker::default();
+        let started = Instant::now();
+        let outcome = filter.run_batch(&tweets, page, &mut worker);
+        let step = filter.step(started, &worker);
+
+        match outcome {
+            Ok((result, dropped)) => {
+                if applied.trace.is_some() {
+                    let before: HashMap<&str, &Tweet> =
+                        tweets.iter().filter_map(|t| Some((t.id()?, t))).collect();
+                    for t in &result {
+                        let Some(original) = t.id().and_then(|id| before.get(id)) else {
+                            continue;
+                        };
+                        let mut step = step.clone();
+                        step.diff = trace::diff(original.as_value(), t.as_value());
+                        if !step.diff.is_empty() {
+                            step.result = StepResult::Changed;
+                        }
+                        applied.record(t.id(), step);
+                    }
+                }
+                for (mut t, reason) in dropped {
+                    let mut step = step.clone();
+                    step.result = StepResult::Dropped;
+                    step.reason = reason.clone();
+                    applied.record(t.id(), step);
+                    t.mark_dropped(&filter.id, reason);
+                    applied.dropped.push(t);
+                }
+                Ok(result)
+            }
+            Err(err) => {
+                let message = err.to_string();
+                for t in &tweets {
+                    let mut step = step.clone();
+                    step.result = StepResult::Failed;
+                    step.reason = Some(message.clone());
+                    applied.record(t.id(), step);
+                }
+                match filter.handle_error(err, None, &mut applied.failures)? {
+                    ErrorPolicy::DropPost => {
+                        for mut t in tweets {
+                            t.mark_dropped(&filter.id, Some(message.clone()));
+                            applied.dropped.push(t);
+                        }
+                        Ok(vec![])
+                    }
+                    _ => Ok(tweets),
+                }
+            }
+        }
+    }
+
+    // Starts a trace step for a run which has just finished. The printed lines are taken here so that they do not pile up.
+    fn step(&self, started: Instant, worker: &Worker) -> TraceStep {
+        let elapsed_us = started.elapsed().as_micros() as u64;
+        let output = worker.take_output();
+        for line in &output {
+            debug!("[{}] {}", self.id, line);
+        }
+
+        TraceStep {
+            filter: self.id.clone(),
+            result: StepResult::Kept,
+            diff: vec![],
+            reason: None,
+            elapsed_us,
+            output,
+        }
+    }
+
+    /// Records the error unless the filter is set to fail the whole request. Returns the policy applied.
+    fn handle_error(
+        &self,
+        err: FilterError,
+        post_id: Option<String>,
+        failures: &mut Vec<FilterFailure>,
+    ) -> Result<ErrorPolicy, FilterError> {
+        let policy = self.meta.on_error;
+        if policy == ErrorPolicy::Fail {
+            return Err(FilterError::Run(self.meta.name.clone(), Box::new(err)));
+        }
+
+        warn!("filter `{}` failed: {}", self.meta.name, err);
+        failures.push(FilterFailure {
+            filter: self.meta.name.clone(),
+            post_id,
+            message: err.to_string(),
+        });
+        Ok(policy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::methods::HttpMethod;
+
+    fn filter(name: &str, src: &str, on_error: ErrorPolicy) -> Filter {
+        Filter {
+            id: name.to_owned(),
+            runtime: Box::new(LuaRuntime {
+                src: Some(src.to_owned()),
+                batch_src: None,
+            }),
+            meta: FilterMeta {
+                name: name.to_owned(),
+                description: String::new(),
+                author: String::new(),
+                version: None,
+                min_backend_version: None,
+                kind: FilterKind::Lua,
+                entrypoint: Some("main.lua".to_owned()),
+                batch_entrypoint: None,
+                scopes: HashSet::new(),
+                on_error,
+                settings: BTreeMap::new(),
+                rules: vec![],
+                hooks: vec![],
+            },
+            settings: Settings::new(),
+            storage: Arc::default(),
+            api: None,
+            modules: Arc::default(),
+            rules: vec![],
+            hooks: vec![],
+            hash: String::new(),
+        }
+    }
+
+    fn batch_filter(name: &str, src: &str, on_error: ErrorPolicy) -> Filter {
+        let mut filter = filter(name, "", on_error);
+        filter.runtime = Box::new(LuaRuntime {
+            src: None,
+            batch_src: Some(src.to_owned()),
+        });
+        filter
+    }
+
+    fn wasm_filter(name: &str, wat: &str, on_error: ErrorPolicy) -> Filter {
+        let mut filter = filter(name, "", on_error);
+        filter.meta.kind = FilterKind::Wasm;
+        filter.runtime = Box::new(WasmRuntime::new(&wat::parse_str(wat).unwrap()).unwrap());
+        filter
+    }
+
+    // A module which logs the length of its input, and returns `verdict` placed at the address 0.
+    fn wasm_module(verdict: &str, body: &str) -> String {
+        format!(
+            r#"(module
+                (import "binchotan" "log" (func $log (param i32 i32)))
+                (memory (export "memory") 1)
+                (global $next (mut i32) (i32.const 1024))
+                (data (i32.const 0) "{}")
+                (data (i32.const 512) "called")
+                (func (export "alloc") (param $len i32) (result i32)
+                  (local $ptr i32)
+                  (local.set $ptr (global.get $next))
+                  (global.set $next (i32.add (global.get $next) (local.get $len)))
+                  (local.get $ptr))
+                (func (export "filter") (param $ptr i32) (param $len i32) (result i64)
+                  (call $log (i32.const 512) (i32.const 6))
+                  {}
+                  (i64.const {})))"#,
+            verdict.replace('"', "\\\""),
+            body,
+            verdict.len()
+        )
+    }
+
+    fn empty_page() -> Page<'static> {
+        Page::new(None, &serde_json::Value::Null)
+    }
+
+    fn tweets() -> Vec<Tweet> {
+        vec![
+            serde_json::from_str(r#"{"id": "1", "text": "foo"}"#).unwrap(),
+            serde_json::from_str(r#"{"id": "2", "text": "bar"}"#).unwrap(),
+        ]
+    }
+
+    const BROKEN: &str = r#"if post.id == "1" then error("boom") end return post"#;
+
+    #[test]
+    fn skip_failing_filter() -> Result<(), FilterError> {
+        let filters = [
+            filter("broken", BROKEN, ErrorPolicy::SkipFilter),
+            filter("echo", "return post", ErrorPolicy::Fail),
+        ];
+        let Applied {
+            posts, failures, ..
+        } = Filter::apply_all(&filters, tweets(), &empty_page(), false, None)?;
+        assert_eq!(posts.len(), 2);
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].filter, "broken");
+        assert_eq!(failures[0].post_id.as_deref(), Some("1"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn drop_post_on_failure() -> Result<(), FilterError> {
+        let filters = [filter("broken", BROKEN, ErrorPolicy::DropPost)];
+        let Applied {
+            posts, failures, ..
+        } = Filter::apply_all(&filters, tweets(), &empty_page(), false, None)?;
+        assert_eq!(posts.len(), 1);
+        assert_eq!(posts[0].id(), Some("2"));
+        assert_eq!(failures.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn fail_whole_request() {
+        let filters = [filter("broken", BROKEN, ErrorPolicy::Fail)];
+        let result = Filter::apply_all(&filters, tweets(), &empty_page(), false, None);
+        assert!(matches!(result, Err(FilterError::Run(name, _)) if name == "broken"));
+    }
+
+    #[test]
+    fn mix_batch_and_per_post_filters() -> Result<(), FilterError> {
+        let reverse = r#"
+            local reversed = {}
+            for i = #posts, 1, -1 do
+              table.insert(reversed, posts[i])
+            end
+            return reversed
+        "#;
+        let filters = [
+            filter(
+                "tag",
+                r#"post.text = post.text .. "!" return post"#,
+                ErrorPolicy::Fail,
+            ),
+            batch_filter("reverse", reverse, ErrorPolicy::Fail),
+            filter(
+                "mute",
+                r#"if post.id == "1" then return nil end return post"#,
+                ErrorPolicy::Fail,
+            ),
+        ];
+        let mut input = tweets();
+        input.push(serde_json::from_str(r#"{"id": "3", "text": "baz"}"#).unwrap());
+        let Applied { posts, .. } = Filter::apply_all(&filters, input, &empty_page(), false, None)?;
+        let ids: Vec<_> = posts.iter().map(|p| p.id().unwrap()).collect();
+        assert_eq!(ids, vec!["3", "2"]);
+        assert_eq!(serde_json::to_value(&posts[0]).unwrap()["text"], "baz!");
+
+        Ok(())
+    }
+
+    #[test]
+    fn keep_posts_when_batch_fails() -> Result<(), FilterError> {
+        let filters = [batch_filter(
+            "broken",
+            "return nil",
+            ErrorPolicy::SkipFilter,
+        )];
+        let Applied {
+            posts, failures, ..
+        } = Filter::apply_all(&filters, tweets(), &empty_page(), false, None)?;
+        assert_eq!(posts.len(), 2);
+        assert_eq!(failures.len(), 1);
+        assert!(failures[0].post_id.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn annotate_and_keep_dropped_posts() -> Result<(), FilterError> {
+        let label = r#"
+            annotate.label("cw")
+            post.binchotan = "forged"
+            return post
+        "#;
+        let mute = r#"
+            if post.id == "1" then
+              annotate.reason("muted word")
+              return nil
+            end
+            return post
+        "#;
+        let filters = [
+            filter("label", label, ErrorPolicy::Fail),
+            filter("mute", mute, ErrorPolicy::Fail),
+        ];
+        let applied = Filter::apply_all(&filters, tweets(), &empty_page(), false, None)?;
+        assert_eq!(applied.posts.len(), 1);
+        assert_eq!(applied.dropped.len(), 1);
+
+        let posts: Vec<serde_json::Value> = applied
+            .into_posts(true)
+            .into_iter()
+            .map(|p| serde_json::to_value(p).unwrap())
+            .collect();
+        assert_eq!(posts.len(), 2);
+        assert_eq!(posts[0]["id"], "1");
+        assert_eq!(posts[0]["binchotan"]["dropped"]["filter"], "mute");
+        assert_eq!(posts[0]["binchotan"]["dropped"]["reason"], "muted word");
+        assert_eq!(
+            posts[1]["binchotan"],
+            serde_json::json!({ "annotations": { "label": { "labels": ["cw"] } } })
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_backend_version() {
+        let mut meta = filter("new", "return post", ErrorPolicy::Fail).meta;
+        meta.version = Some("1.2.0".into());
+        meta.min_backend_version = Some(VERSION.into());
+        assert!(meta.check_versions().is_ok());
+
+        meta.min_backend_version = Some("99.0.0".into());
+        let err = meta.check_versions().unwrap_err();
+        assert!(matches!(err, FilterError::Incompatible(_, _)));
+        assert!(err.to_string().contains(VERSION), "{}", err);
+
+        meta.min_backend_version = None;
+        meta.version = Some("1.2".into());
+        assert!(matches!(
+            meta.check_versions(),
+            Err(FilterError::InvalidVersion(_, _))
+        ));
+    }
+
+    #[test]
+    fn trace_each_step() -> Result<(), FilterError> {
+        let shout = r#"
+            print("shouting", post.id)
+            post.text = post.text:upper()
+            return post
+        "#;
+        let filters = [
+            filter("echo", "return post", ErrorPolicy::Fail),
+            filter("shout", shout, ErrorPolicy::Fail),
+            filter(
+                "mute",
+                r#"if post.id == "2" then return nil end return post"#,
+                ErrorPolicy::Fail,
+            ),
+        ];
+        let applied = Filter::apply_all(&filters, tweets(), &empty_page(), true, None)?;
+        let trace = applied.trace.unwrap();
+
+        let steps = &trace["1"];
+        let results: Vec<_> = steps.iter().map(|s| s.result).collect();
+        assert_eq!(
+            results,
+            [StepResult::Kept, StepResult::Changed, StepResult::Kept]
+        );
+        assert_eq!(steps[1].output, ["shouting\t1"]);
+        assert_eq!(
+            steps[1].diff,
+            [trace::DiffOp::Replace {
+                path: "/text".into(),
+                value: "FOO".into()
+            }]
+        );
+        assert_eq!(trace["2"][2].result, StepResult::Dropped);
+
+        Ok(())
+    }
+
+    #[test]
+    fn run_wasm_filters() -> Result<(), FilterError> {
+        let filters = [
+            filter(
+                "tag",
+                r#"post.text = post.text .. "!" return post"#,
+                ErrorPolicy::Fail,
+            ),
+            wasm_filter(
+                "patch",
+                &wasm_module(r#"{"action":"patch","patch":{"lang":"ja"}}"#, ""),
+                ErrorPolicy::Fail,
+            ),
+            wasm_filter(
+                "mute",
+                &wasm_module(r#"{"action":"drop","reason":"wasm"}"#, ""),
+                ErrorPolicy::Fail,
+            ),
+        ];
+        let mut applied = Filter::apply_all(&filters, tweets(), &empty_page(), true, None)?;
+        assert!(applied.posts.is_empty());
+        let trace = applied.trace.take().unwrap();
+        assert_eq!(trace["1"][2].output, ["called"]);
+
+        let posts: Vec<serde_json::Value> = applied
+            .into_posts(true)
+            .into_iter()
+            .map(|p| serde_json::to_value(p).unwrap())
+            .collect();
+        assert_eq!(posts[0]["text"], "foo!");
+        assert_eq!(posts[0]["lang"], "ja");
+        assert_eq!(posts[0]["binchotan"]["dropped"]["reason"], "wasm");
+
+        Ok(())
+    }
+
+    #[test]
+    fn limit_wasm_filters() -> Result<(), FilterError> {
+        let keep = r#"{"action":"keep"}"#;
+        let filters = [
+            wasm_filter(
+                "spin",
+                &wasm_module(keep, "(loop $spin (br $spin))"),
+                ErrorPolicy::SkipFilter,
+            ),
+            wasm_filter(
+                "greedy",
+                &wasm_module(
+                    keep,
+                    "(if (i32.eq (memory.grow (i32.const 2048)) (i32.const -1)) (then unreachable))",
+                ),
+                ErrorPolicy::SkipFilter,
+            ),
+        ];
+        let Applied {
+            posts, failures, ..
+        } = Filter::apply_all(&filters, tweets(), &empty_page(), false, None)?;
+        assert_eq!(posts.len(), 2);
+        assert_eq!(failures.len(), 4);
+        assert!(
+            failures[0].message.contains("fuel"),
+            "{}",
+            failures[0].message
+        );
+
+        let err =
+            WasmRuntime::new(&wat::parse_str("(module (memory (export \"memory\") 1))").unwrap())
+                .unwrap_err();
+        assert!(matches!(err, WasmError::MissingExport("alloc")));
+
+        Ok(())
+    }
+
+    fn pool(workers: usize) -> ThreadPool {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(workers)
+            .build()
+            .unwrap()
+    }
+
+    fn many_tweets(n: usize) -> Vec<Tweet> {
+        (0..n)
+            .map(|i| {
+                serde_json::from_value(serde_json::json!({ "id": i.to_string(), "text": "foo" }))
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn keep_order_in_parallel() -> Result<(), FilterError> {
+        let filters = [
+            filter(
+                "tag",
+                r#"post.text = post.text .. post.id return post"#,
+                ErrorPolicy::Fail,
+            ),
+            filter(
+                "mute",
+                r#"if tonumber(post.id) % 3 == 0 then return nil end return post"#,
+                ErrorPolicy::Fail,
+            ),
+            filter(
+                "broken",
+                r#"if tonumber(post.id) % 5 == 0 then error("boom") end return post"#,
+                ErrorPolicy::SkipFilter,
+            ),
+        ];
+        let serial = Filter::apply_all(&filters, many_tweets(100), &empty_page(), true, None)?;
+        let parallel = Filter::apply_all(
+            &filters,
+            many_tweets(100),
+            &empty_page(),
+            true,
+            Some(&pool(4)),
+        )?;
+        let summary = |applied: &Applied| {
+            let steps: BTreeMap<String, Vec<(String, StepResult)>> = applied
+                .trace
+                .iter()
+                .flatten()
+                .map(|(id, steps)| {
+                    let steps = steps.iter().map(|s| (s.filter.clone(), s.result)).collect();
+                    (id.clone(), steps)
+                })
+                .collect();
+            let failures: Vec<_> = applied.failures.iter().map(|f| f.post_id.clone()).collect();
+            (
+                serde_json::to_value(&applied.posts).unwrap(),
+                serde_json::to_value(&applied.dropped).unwrap(),
+                steps,
+                failures,
+            )
+        };
+        assert_eq!(summary(&serial), summary(&parallel));
+        assert_eq!(parallel.posts.len(), 66);
+        assert_eq!(parallel.failures.len(), 13);
+
+        let filters = [filter("broken", BROKEN, ErrorPolicy::Fail)];
+        let result = Filter::apply_all(
+            &filters,
+            many_tweets(100),
+            &empty_page(),
+            false,
+            Some(&pool(4)),
+        );
+        assert!(matches!(result, Err(FilterError::Run(name, _)) if name == "broken"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn reset_globals_for_each_post() -> Result<(), FilterError> {
+        let leaky = r#"
+            if hit or _G.hit_g or package.loaded.hit_module or config.hit or type(includes) == "table" then
+              return nil
+            end
+            hit = true
+            _G.hit_g = true
+            package.loaded.hit_module = true
+            config.hit = true
+            includes = {}
+            return post
+        "#;
+        let filters = [filter("leaky", leaky, ErrorPolicy::Fail)];
+        for pool in [None, Some(pool(1)), Some(pool(4))] {
+            let applied = Filter::apply_all(
+                &filters,
+                many_tweets(20),
+                &empty_page(),
+                false,
+                pool.as_ref(),
+            )?;
+            assert_eq!(applied.posts.len(), 20);
+            assert!(applied.dropped.is_empty());
+        }
+
+        Ok(())
+    }
+
+    // run with `cargo test --release -- --ignored bench_parallel --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_parallel() -> Result<(), FilterError> {
+        let heavy = r#"
+            local n = 0
+            for i = 1, 200000 do
+              n = n + #post.text * i % 7
+            end
+            post.score = n
+            return post
+        "#;
+        let filters = [filter("heavy", heavy, ErrorPolicy::Fail)];
+        let cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let mut elapsed = vec![];
+        for n in [1, cpus.max(2)] {
+            let started = Instant::now();
+            let pool = pool(n);
+            let applied = Filter::apply_all(
+                &filters,
+                many_tweets(100),
+                &empty_page(),
+                false,
+                Some(&pool),
+            )?;
+            assert_eq!(applied.posts.len(), 100);
+            elapsed.push(started.elapsed());
+            println!("{} worker(s): {:?}", n, started.elapsed());
+        }
+        if cpus > 1 {
+            assert!(elapsed[1] < elapsed[0]);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn find_hooking_filters() -> Result<(), FilterError> {
+        let examples = Path::new(env!("CARGO_MANIFEST_DIR")).join("example_filters");
+        let request = |method, endpoint: &str| PlainRequest {
+            method,
+            endpoint: endpoint.to_owned(),
+            body: serde_json::json!({}),
+        };
+        let hooking = Filter::hooking(&examples, &request(HttpMethod::Post, "tweets"))?;
+        assert_eq!(hooking, [examples.join("phone_guard")]);
+        assert!(Filter::hooking(&examples, &request(HttpMethod::Get, "tweets"))?.is_empty());
+
+        // broken filters do not matter to requests they do not hook
+        let dir = std::env::temp_dir().join(format!("binchotan-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(dir.join("broken"))?;
+        std::fs::write(dir.join("broken/binchotan.toml"), "name = ")?;
+        assert!(Filter::hooking(&dir, &request(HttpMethod::Post, "tweets"))?.is_empty());
+        std::fs::remove_dir_all(&dir)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn leave_missing_includes_nil() -> Result<(), FilterError> {
+        let src = r#"
+            if post.author then
+              post.text = post.author.username
+            end
+            for _, r in ipairs(post.referenced) do
+              if r.tweet then
+                post.text = r.tweet.text
+              end
+            end
+            return post
+        "#;
+        let filters = [filter("author", src, ErrorPolicy::Fail)];
+        let mut input = tweets();
+        input.push(
+            serde_json::from_str(
+                r#"{"id": "3", "text": "baz", "author_id": "10", "referenced_tweets": [{"type": "quoted", "id": "4"}]}"#,
+            )
+            .unwrap(),
+        );
+        let Applied { posts, .. } = Filter::apply_all(&filters, input, &empty_page(), false, None)?;
+        let texts: Vec<_> = posts
+            .iter()
+            .map(|p| serde_json::to_value(p).unwrap()["text"].clone())
+            .collect();
+        assert_eq!(texts, ["foo", "bar", "baz"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn expose_timeline_context() -> Result<(), FilterError> {
+        let filters = [filter(
+            "home_only",
+            r#"if context.kind ~= "home" then return nil end return post"#,
+            ErrorPolicy::Fail,
+        )];
+        let applied = Filter::apply_all(&filters, tweets(), &empty_page(), false, None)?;
+        assert_eq!(applied.posts.len(), 2);
+
+        let meta = serde_json::Value::Null;
+        let page = Page::new(None, &meta)
+            .with_context(TimelineContext::for_endpoint("tweets/search/recent"));
+        let applied = Filter::apply_all(&filters, tweets(), &page, false, None)?;
+        assert!(applied.posts.is_empty());
+
+        Ok(())
     }
 }
@@ -0,0 +1,343 @@
+use mlua::prelude::*;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use thiserror::Error;
+use tracing::debug;
+
+use crate::api::{ApiClient, ApiClientError};
+
+/// How long responses are reused for the same request.
+const CACHE_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// GET endpoints available to filters, along with the scopes they require. `:x` matches any path segment.
+const ENDPOINTS: &[(&str, &[&str])] = &[
+    ("tweets", &["tweet.read", "users.read"]),
+    ("tweets/:id", &["tweet.read", "users.read"]),
+    ("tweets/search/recent", &["tweet.read", "users.read"]),
+    ("tweets/counts/recent", &["tweet.read"]),
+    ("tweets/:id/quote_tweets", &["tweet.read", "users.read"]),
+    ("tweets/:id/retweeted_by", &["tweet.read", "users.read"]),
+    (
+        "tweets/:id/liking_users",
+        &["tweet.read", "users.read", "like.read"],
+    ),
+    ("users", &["tweet.read", "users.read"]),
+    ("users/me", &["tweet.read", "users.read"]),
+    ("users/:id", &["tweet.read", "users.read"]),
+    ("users/by", &["tweet.read", "users.read"]),
+    ("users/by/username/:username", &["tweet.read", "users.read"]),
+    ("users/:id/tweets", &["tweet.read", "users.read"]),
+    ("users/:id/mentions", &["tweet.read", "users.read"]),
+    (
+        "users/:id/followers",
+        &["tweet.read", "users.read", "follows.read"],
+    ),
+    (
+        "users/:id/following",
+        &["tweet.read", "users.read", "follows.read"],
+    ),
+    (
+        "users/:id/blocking",
+        &["tweet.read", "users.read", "block.read"],
+    ),
+    (
+        "users/:id/muting",
+        &["tweet.read", "users.read", "mute.read"],
+    ),
+    (
+        "users/:id/liked_tweets",
+        &["tweet.read", "users.read", "like.read"],
+    ),
+    (
+        "users/:id/bookmarks",
+        &["tweet.read", "users.read", "bookmark.read"],
+    ),
+    (
+        "users/:id/owned_lists",
+        &["tweet.read", "users.read", "list.read"],
+    ),
+    (
+        "users/:id/followed_lists",
+        &["tweet.read", "users.read", "list.read"],
+    ),
+    (
+        "users/:id/list_memberships",
+        &["tweet.read", "users.read", "list.read"],
+    ),
+    ("lists/:id", &["tweet.read", "users.read", "list.read"]),
+    (
+        "lists/:id/tweets",
+        &["tweet.read", "users.read", "list.read"],
+    ),
+    (
+        "lists/:id/members",
+        &["tweet.read", "users.read", "list.read"],
+    ),
+    (
+        "lists/:id/followers",
+        &["tweet.read", "users.read", "list.read"],
+    ),
+];
+
+#[derive(Debug, Error)]
+pub enum FilterApiError {
+    #[error("endpoint `{0}` is not available to filters")]
+    UnknownEndpoint(String),
+    #[error("calling `{0}` requires scopes which the filter does not declare: {}", .1.join(","))]
+    MissingScopes(String, Vec<String>),
+    #[error("the rate limit for `{0}` has been exhausted until {1} (epoch sec)")]
+    RateLimited(String, usize),
+    #[error("the API is not available here")]
+    Unavailable,
+    #[error(transparent)]
+    ApiClient(#[from] ApiClientError),
+}
+
+/// Returns the endpoint pattern which the path matches, and the scopes it requires.
+fn lookup(path: &str) -> Option<(&'static str, &'static [&'static str])> {
+    ENDPOINTS
+        .iter()
+        .copied()
+        .find(|(pattern, _)| path_matches(pattern, path))
+}
+
+/// Whether the path matches the endpoint pattern, in which `:x` matches a path segment made of `[A-Za-z0-9_-]`, such as an id or a username, or `:x` itself.
+/// Other segments such as `..` or the ones with a query are rejected, as they would reach another URL than the pattern.
+pub fn path_matches(pattern: &str, path: &str) -> bool {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let pattern: Vec<&str> = pattern.split('/').collect();
+    let param = |s: &str| {
+        !s.is_empty()
+            && s.chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    };
+    pattern.len() == segments.len()
+        && pattern
+            .iter()
+            .zip(&segments)
+            .all(|(p, s)| p == s || (p.starts_with(':') && param(s)))
+}
+
+/// Ensures that the filter declares the scopes required for the endpoint. Returns the endpoint pattern.
+pub fn authorize(scopes: &HashSet<String>, path: &str) -> Result<&'static str, FilterApiError> {
+    let (pattern, required) =
+        lookup(path).ok_or_else(|| FilterApiError::UnknownEndpoint(path.to_owned()))?;
+    let missing: Vec<String> = required
+        .iter()
+        .filter(|scope| !scopes.contains(**scope))
+        .map(|scope| scope.to_string())
+        .collect();
+    if !missing.is_empty() {
+        return Err(FilterApiError::MissingScopes(path.to_owned(), missing));
+    }
+
+    Ok(pattern)
+}
+
+#[derive(Debug, Clone, Copy)]
+struct RateLimit {
+    remaining: usize,
+    // in epoch sec
+    reset: usize,
+}
+
+/// Responses and rate limits of the API calls made by filters. This is shared among requests so that the cache and the rate limits outlive a single timeline.
+#[derive(Debug, Default)]
+pub struct ApiCache {
+    // keyed by the user id, the endpoint and the parameters
+    responses: Mutex<HashMap<String, (Instant, serde_json::Value)>>,
+    // keyed by the user id and the endpoint pattern
+    limits: Mutex<HashMap<(String, &'static str), RateLimit>>,
+}
+
+/// The API as seen by a filter: calls are made on behalf of the account, limited to the scopes declared by the filter.
+#[derive(Clone)]
+pub struct FilterApi {
+    client: Arc<ApiClient>,
+    cache: Arc<ApiCache>,
+}
+
+impl std::fmt::Debug for FilterApi {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FilterApi")
+            .field("user_id", &self.client.user_id)
+            .finish()
+    }
+}
+
+impl FilterApi {
+    pub fn new(client: Arc<ApiClient>, cache: Arc<ApiCache>) -> Self {
+        Self { client, cache }
+    }
+
+    /// Calls the endpoint with GET, or returns the cached response if any.
+    pub fn get(
+        &self,
+        scopes: &HashSet<String>,
+        path: &str,
+        mut params: Vec<(String, String)>,
+    ) -> Result<serde_json::Value, FilterApiError> {
+        let pattern = authorize(scopes, path)?;
+        let user_id = &self.client.user_id;
+        params.sort();
+        let key = format!("{}:{}?{:?}", user_id, path, params);
+        {
+            let mut responses = self.cache.responses.lock().unwrap();
+            responses.retain(|_, (fetched_at, _)| fetched_at.elapsed() < CACHE_TTL);
+            if let Some((_, value)) = responses.get(&key) {
+                debug!("using the cached response for {}", path);
+                return Ok(value.clone());
+            }
+        }
+
+        let limit_key = (user_id.clone(), pattern);
+        if let Some(limit) = self.cache.limits.lock().unwrap().get(&limit_key) {
+            if limit.remaining == 0 && limit.reset > now() {
+                return Err(FilterApiError::RateLimited(path.to_owned(), limit.reset));
+            }
+        }
+
+        let handle =
+            tokio::runtime::Handle::try_current().map_err(|_| FilterApiError::Unavailable)?;
+        // filters run synchronously on blocking threads, so the call blocks the thread until it completes
+        let (value, remaining, reset) =
+            tokio::task::block_in_place(|| handle.block_on(self.client.get(path, &params)))?;
+
+        self.cache
+            .limits
+            .lock()
+            .unwrap()
+            .insert(limit_key, RateLimit { remaining, reset });
+        self.cache
+            .responses
+            .lock()
+            .unwrap()
+            .insert(key, (Instant::now(), value.clone()));
+
+        Ok(value)
+    }
+
+    /// Creates the `api` table exposed to Lua scripts. If `api` is none, every call fails, e.g. when the filter is run offline.
+    pub fn create_lua_table(
+        lua: &Lua,
+        api: Option<FilterApi>,
+        scopes: HashSet<String>,
+    ) -> LuaResult<LuaTable<'_>> {
+        let table = lua.create_table()?;
+
+        table.set(
+            "get",
+            lua.create_function(
+                move |lua, (path, params): (String, Option<HashMap<String, LuaValue>>)| {
+                    let params = params
+                        .unwrap_or_default()
+                        .into_iter()
+                        .map(|(key, value)| {
+                            let value = match value {
+                                LuaValue::String(s) => s.to_str()?.to_owned(),
+                                LuaValue::Integer(i) => i.to_string(),
+                                LuaValue::Number(n) => n.to_string(),
+                                LuaValue::Boolean(b) => b.to_string(),
+                                other => {
+                                    return Err(LuaError::RuntimeError(format!(
+                                        "api.get: parameter `{}` must be a string, a number or a boolean, not {}",
+                                        key,
+                                        other.type_name()
+                                    )))
+                                }
+                            };
+                            Ok((key, value))
+                        })
+                        .collect::<LuaResult<Vec<_>>>()?;
+
+                    let value = authorize(&scopes, &path)
+                        .and_then(|_| api.as_ref().ok_or(FilterApiError::Unavailable))
+                        .and_then(|api| api.get(&scopes, &path, params))
+                        .map_err(|err| LuaError::RuntimeError(format!("api.get: {}", err)))?;
+                    lua.to_value(&value)
+                },
+            )?,
+        )?;
+
+        Ok(table)
+    }
+}
+
+fn now() -> usize {
+    // SAFETY: the system clock should not be set before 1970
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reject_unsafe_segments() {
+        assert!(path_matches("tweets/:id", "tweets/1585000000000000000"));
+        assert!(path_matches(
+            "users/by/username/:username",
+            "users/by/username/jack_-1"
+        ));
+        for path in [
+            "tweets/1?expansions=author_id",
+            "tweets/1#x",
+            "tweets/..",
+            "tweets/.",
+            "users//followers",
+            "users/../tweets/search/recent",
+            "users/%2e%2e/followers",
+        ] {
+            assert!(lookup(path).is_none(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn lookup_endpoints() {
+        assert_eq!(
+            lookup("users/:id/followers").map(|(p, _)| p),
+            Some("users/:id/followers")
+        );
+        assert_eq!(
+            lookup("/users/12345/following").map(|(p, _)| p),
+            Some("users/:id/following")
+        );
+        assert_eq!(
+            lookup("users/by/username/jack").map(|(p, _)| p),
+            Some("users/by/username/:username")
+        );
+        assert_eq!(lookup("users/me").map(|(p, _)| p), Some("users/me"));
+        assert!(lookup("users/:id/following/123").is_none());
+        assert!(lookup("dm_events").is_none());
+    }
+
+    #[test]
+    fn reject_without_scopes() -> LuaResult<()> {
+        let lua = Lua::new();
+        let scopes = HashSet::from(["tweet.read".to_owned(), "users.read".to_owned()]);
+        lua.globals()
+            .set("api", FilterApi::create_lua_table(&lua, None, scopes)?)?;
+
+        let err = lua
+            .load(r#"api.get("users/:id/followers", { max_results = 10 })"#)
+            .exec()
+            .unwrap_err();
+        assert!(err.to_string().contains("follows.read"), "{}", err);
+        let err = lua.load(r#"api.get("dm_events")"#).exec().unwrap_err();
+        assert!(
+            err.to_string().contains("not available to filters"),
+            "{}",
+            err
+        );
+        let err = lua.load(r#"api.get("users/me")"#).exec().unwrap_err();
+        assert!(err.to_string().contains("not available here"), "{}", err);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,305 @@
+use serde::Deserialize;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+use crate::{
+    filter::{Applied, Filter, FilterError, FilterMeta},
+    settings::Settings,
+    timeline::{TimelineContext, TimelineKind},
+    trace,
+    tweet::{Page, Tweet},
+};
+
+/// A test file in `tests/` of a filter.
+#[derive(Debug, Deserialize)]
+struct TestFile {
+    /// A JSONL file of posts in the shape of Twitter API v2, relative to the test file.
+    fixture: Option<String>,
+    /// `includes` used to hydrate the posts.
+    includes: Option<serde_json::Value>,
+    /// Setting values used instead of the defaults.
+    #[serde(default)]
+    settings: Settings,
+    /// The kind of the timeline the posts are in, given to the filter as `context.kind`.
+    #[serde(default)]
+    context: TimelineKind,
+    #[serde(default, rename = "case")]
+    cases: Vec<TestCase>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TestCase {
+    name: Option<String>,
+    /// The id of a post in the fixture.
+    post: Option<String>,
+    /// A post given inline, used instead of `post`.
+    input: Option<serde_json::Value>,
+    expect: Expect,
+    /// Fields which the post should have after the filter, for `expect = "patch"`.
+    #[serde(default)]
+    patch: serde_json::Map<String, serde_json::Value>,
+    /// The reason the filter should give, for `expect = "drop"`.
+    reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Expect {
+    /// The post is kept as is.
+    Keep,
+    /// The post is dropped.
+    Drop,
+    /// The post is kept, with the fields in `patch`.
+    Patch,
+}
+
+#[derive(Debug)]
+pub struct CaseResult {
+    pub file: String,
+    pub name: String,
+    /// Why the case failed, if it did.
+    pub failure: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct TestReport {
+    pub filter: String,
+    pub cases: Vec<CaseResult>,
+}
+
+#[derive(Debug, Error)]
+pub enum FilterTestError {
+    #[error("{0} has no tests/ directory")]
+    NoTests(PathBuf),
+    #[error("could not parse {0}: {1}")]
+    Parse(PathBuf, toml::de::Error),
+    #[error("{0}:{1}: {2}")]
+    Fixture(PathBuf, usize, serde_json::Error),
+    #[error("{0}: {1}")]
+    InvalidCase(PathBuf, String),
+    #[error(transparent)]
+    Filter(#[from] FilterError),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// Runs the tests in `tests/*.toml` of the filter in the directory, without connecting to Twitter or the database.
+/// The filter is loaded afresh for each test file, with all the scopes it declares and empty storage.
+pub fn run(dir: &Path) -> Result<TestReport, FilterTestError> {
+    let tests_dir = dir.join("tests");
+    if !tests_dir.is_dir() {
+        return Err(FilterTestError::NoTests(dir.to_owned()));
+    }
+    let mut paths: Vec<PathBuf> = tests_dir
+        .read_dir()?
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "toml"))
+        .collect();
+    paths.sort();
+
+    let scopes = FilterMeta::read(dir)?.scopes;
+    let mut cases = vec![];
+    for path in paths {
+        let filter = Filter::load_single(dir, &scopes, None)?;
+        cases.extend(run_file(filter, &path)?);
+    }
+
+    Ok(TestReport {
+        filter: dir
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        cases,
+    })
+}
+
+fn run_file(mut filter: Filter, path: &Path) -> Result<Vec<CaseResult>, FilterTestError> {
+    let file: TestFile = toml::from_str(&fs::read_to_string(path)?)
+        .map_err(|err| FilterTestError::Parse(path.to_owned(), err))?;
+    filter.configure(&file.settings)?;
+
+    let mut posts = match &file.fixture {
+        // SAFETY: the path is a file in tests/, so it has a parent
+        Some(fixture) => read_fixture(&path.parent().unwrap().join(fixture))?,
+        None => vec![],
+    };
+    let mut ids = vec![];
+    for case in &file.cases {
+        let id = match (&case.input, &case.post) {
+            (Some(input), _) => {
+                let id = input["id"].as_str().ok_or_else(|| {
+                    FilterTestError::InvalidCase(path.to_owned(), "`input` has no id".into())
+                })?;
+                posts.push(serde_json::from_value(input.clone()).map_err(|err| {
+                    FilterTestError::InvalidCase(path.to_owned(), err.to_string())
+                })?);
+                id.to_owned()
+            }
+            (None, Some(id)) if posts.iter().any(|p| p.id() == Some(id)) => id.clone(),
+            (None, Some(id)) => {
+                return Err(FilterTestError::InvalidCase(
+                    path.to_owned(),
+                    format!("no post with id {} in the fixture", id),
+                ))
+            }
+            (None, None) => {
+                return Err(FilterTestError::InvalidCase(
+                    path.to_owned(),
+                    "a case needs either `post` or `input`".into(),
+                ))
+            }
+        };
+        ids.push(id);
+    }
+
+    let meta = serde_json::json!({});
+    let page =
+        Page::new(file.includes.as_ref(), &meta).with_context(TimelineContext::new(file.context));
+    let applied = Filter::apply_all(&[filter], posts.clone(), &page, false, None);
+
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let results = file
+        .cases
+        .iter()
+        .zip(ids)
+        .map(|(case, id)| {
+            let failure = match &applied {
+                Ok(applied) => {
+                    // SAFETY: every id has been looked up in the posts above
+                    let original = posts.iter().find(|p| p.id() == Some(&id)).unwrap();
+                    check(case, original, applied)
+                }
+                Err(err) => Some(err.to_string()),
+            };
+            CaseResult {
+                file: file_name.clone(),
+                name: case.name.clone().unwrap_or(id),
+                failure,
+            }
+        })
+        .collect();
+
+    Ok(results)
+}
+
+fn read_fixture(path: &Path) -> Result<Vec<Tweet>, FilterTestError> {
+    fs::read_to_string(path)?
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            serde_json::from_str(line)
+                .map_err(|err| FilterTestError::Fixture(path.to_owned(), i + 1, err))
+        })
+        .collect()
+}
+
+// Returns why the case failed, if it did.
+fn check(case: &TestCase, original: &Tweet, applied: &Applied) -> Option<String> {
+    let id = original.id();
+    if let Some(failure) = applied
+        .failures
+        .iter()
+        .find(|f| f.post_id.is_none() || f.post_id.as_deref() == id)
+    {
+        return Some(format!("the filter failed: {}", failure.message));
+    }
+
+    let kept = applied.posts.iter().find(|p| p.id() == id);
+    let dropped = applied.dropped.iter().find(|p| p.id() == id);
+    match (case.expect, kept, dropped) {
+        (Expect::Drop, Some(_), _) => Some("expected to be dropped, but kept".into()),
+        (Expect::Drop, None, Some(post)) => {
+            let reason = post.as_value()["binchotan"]["dropped"]["reason"].as_str();
+            match &case.reason {
+                Some(expected) if reason != Some(expected.as_str()) => Some(format!(
+                    "dropped with reason {:?}, expected {:?}",
+                    reason, expected
+                )),
+                _ => None,
+            }
+        }
+        (Expect::Keep | Expect::Patch, None, Some(post)) => Some(format!(
+            "expected to be kept, but dropped with reason {:?}",
+            post.as_value()["binchotan"]["dropped"]["reason"].as_str()
+        )),
+        (Expect::Keep, Some(post), _) => {
+            let mut post = post.as_value().clone();
+            if let Some(fields) = post.as_object_mut() {
+                fields.remove("binchotan");
+            }
+            let diff = trace::diff(original.as_value(), &post);
+            (!diff.is_empty()).then(|| {
+                format!(
+                    "expected to be kept as is, but changed: {}",
+                    serde_json::to_string(&diff).unwrap_or_default()
+                )
+            })
+        }
+        (Expect::Patch, Some(post), _) => case
+            .patch
+            .iter()
+            .find(|(key, value)| &post.as_value()[key.as_str()] != *value)
+            .map(|(key, value)| {
+                format!(
+                    "`{}` is {}, expected {}",
+                    key,
+                    post.as_value()[key.as_str()],
+                    value
+                )
+            }),
+        (_, None, None) => Some("the post has disappeared".into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn example_filters_pass() -> Result<(), FilterTestError> {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("example_filters");
+        for name in ["echo", "mute_rules", "mute_source", "mute_word"] {
+            let report = run(&dir.join(name))?;
+            assert!(!report.cases.is_empty());
+            for case in &report.cases {
+                assert_eq!(case.failure, None, "{}: {}", name, case.name);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn report_mismatch() {
+        let case: TestCase = toml::from_str(
+            r#"
+            post = "1"
+            expect = "patch"
+            patch = { text = "bar" }
+            "#,
+        )
+        .unwrap();
+        let original: Tweet = serde_json::from_str(r#"{ "id": "1", "text": "foo" }"#).unwrap();
+        let meta = serde_json::json!({});
+        let applied = Filter::apply_all(
+            &[],
+            vec![original.clone()],
+            &Page::new(None, &meta),
+            false,
+            None,
+        )
+        .unwrap();
+
+        assert_eq!(
+            check(&case, &original, &applied).as_deref(),
+            Some(r#"`text` is "foo", expected "bar""#)
+        );
+    }
+}
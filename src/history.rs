@@ -0,0 +1,551 @@
+use serde::Deserialize;
+use sqlx::PgPool;
+use std::{collections::BTreeMap, sync::Arc};
+use thiserror::Error;
+
+use crate::{
+    models::Account,
+    search::{self, SearchQuery},
+    tweet::{Page, Tweet},
+};
+
+/// Objects in `includes` which are stored, along with the fields identifying them.
+const INCLUDES: &[(&str, &str)] = &[
+    ("users", "id"),
+    ("tweets", "id"),
+    ("media", "media_key"),
+    ("places", "id"),
+    ("polls", "id"),
+];
+
+/// The most tweets returned in a page of the history.
+pub const MAX_RESULTS: usize = 100;
+
+/// The time tweet ids start from, in epoch milliseconds. Ids contain the time they were created at in the upper bits.
+const TWEET_EPOCH: i64 = 1288834974657;
+
+#[derive(Debug, Error)]
+pub enum HistoryError {
+    #[error("invalid pagination token: {0}")]
+    InvalidToken(String),
+    #[error("invalid time `{0}`: {1}")]
+    InvalidTime(String, chrono::ParseError),
+    #[error("database error: {0}")]
+    Database(#[from] sqlx::Error),
+}
+
+/// When timelines are served from the stored tweets instead of failing, while the API is unavailable.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OfflineFallback {
+    /// Only when the request sets `offline_fallback`.
+    #[default]
+    OptIn,
+    /// Unless the request sets `offline_fallback` to false.
+    Auto,
+}
+
+/// How long fetched tweets are kept, set in the config.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct HistoryConfig {
+    /// Whether tweets are stored at all.
+    pub enabled: bool,
+    /// Tweets which have not appeared on any timeline for this many days are deleted. 0 keeps them forever.
+    pub retention_days: u32,
+    pub offline_fallback: OfflineFallback,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            retention_days: 30,
+            offline_fallback: OfflineFallback::default(),
+        }
+    }
+}
+
+/// A page of the stored timeline, in the same shape as the response of the API.
+pub struct HistoryPage {
+    pub tweets: Vec<Tweet>,
+    pub includes: Option<serde_json::Value>,
+    pub meta: serde_json::Value,
+}
+
+/// Stores fetched tweets and `includes`, and which timelines of which accounts they appeared on.
+#[derive(Clone)]
+pub struct HistoryStore {
+    conn: Arc<PgPool>,
+    config: HistoryConfig,
+}
+
+impl HistoryStore {
+    pub fn new(conn: PgPool, config: HistoryConfig) -> Self {
+        Self {
+            conn: Arc::new(conn),
+            config,
+        }
+    }
+
+    /// Whether the timeline is served from the stored tweets when the API is unavailable. `requested` is `offline_fallback` of the request.
+    pub fn falls_back(&self, requested: Option<bool>) -> bool {
+        self.config.enabled
+            && requested.unwrap_or(self.config.offline_fallback == OfflineFallback::Auto)
+    }
+
+    /// When the timeline was fetched last, in epoch sec.
+    pub async fn captured_at(
+        &self,
+        account: &Account,
+        timeline: &str,
+    ) -> Result<Option<i64>, HistoryError> {
+        let rec = sqlx::query!(
+            r#"
+            select extract(epoch from max(seen_at))::bigint as captured_at from timeline_entries
+                where account_id = $1 and timeline = $2
+            "#,
+            account.id,
+            timeline
+        )
+        .fetch_one(self.conn.as_ref())
+        .await?;
+
+        Ok(rec.captured_at)
+    }
+
+    /// Upserts the tweets and `includes` of a response, and records that the tweets appeared on the timeline of the account.
+    pub async fn save(
+        &self,
+        account: &Account,
+        timeline: &str,
+        tweets: &[Tweet],
+        includes: Option<&serde_json::Value>,
+    ) -> Result<(), HistoryError> {
+        if !self.config.enabled {
+            return Ok(());
+        }
+
+        let page = Page::new(includes, &serde_json::Value::Null);
+        let mut tx = self.conn.begin().await?;
+        for tweet in tweets {
+            let Some(id) = tweet.id().and_then(|id| id.parse::<i64>().ok()) else {
+                continue;
+            };
+            let document = search::document(page.hydrate(tweet).as_value());
+            sqlx::query!(
+                r#"
+                insert into tweets (id, data, search) values ($1, $2, $3::tsvector)
+                    on conflict (id) do update
+                        set data = $2, search = $3::tsvector, fetched_at = now()
+                "#,
+                id,
+                tweet.as_value(),
+                document as _
+            )
+            .execute(&mut tx)
+            .await?;
+            sqlx::query!(
+                r#"
+                insert into timeline_entries (account_id, timeline, tweet_id) values ($1, $2, $3)
+                    on conflict (account_id, timeline, tweet_id) do update set seen_at = now()
+                "#,
+                account.id,
+                timeline,
+                id
+            )
+            .execute(&mut tx)
+            .await?;
+        }
+
+        for (kind, field) in INCLUDES {
+            let Some(items) = includes.and_then(|includes| includes[*kind].as_array()) else {
+                continue;
+            };
+            for item in items {
+                let Some(key) = item[*field].as_str() else {
+                    continue;
+                };
+                sqlx::query!(
+                    r#"
+                    insert into tweet_includes (kind, key, data) values ($1, $2, $3)
+                        on conflict (kind, key) do update set data = $3, fetched_at = now()
+                    "#,
+                    kind,
+                    key,
+                    item
+                )
+                .execute(&mut tx)
+                .await?;
+            }
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Returns the stored tweets of the timeline, newest first. `pagination_token` is `meta.next_token` of the previous page, or a tweet id which the results are older than like `until_id` of the API.
+    /// If `since_id` is given, only the tweets newer than it are returned.
+    pub async fn timeline(
+        &self,
+        account: &Account,
+        timeline: &str,
+        max_results: usize,
+        pagination_token: Option<&str>,
+        since_id: Option<&str>,
+    ) -> Result<HistoryPage, HistoryError> {
+        let until_id = parse_token(pagination_token)?;
+        let since_id = parse_token(since_id)?;
+        let limit = max_results.clamp(1, MAX_RESULTS);
+
+        let tweets: Vec<Tweet> = sqlx::query!(
+            r#"
+            select t.data from timeline_entries e join tweets t on t.id = e.tweet_id
+                where e.account_id = $1 and e.timeline = $2
+                    and ($3::bigint is null or e.tweet_id < $3)
+                    and ($4::bigint is null or e.tweet_id > $4)
+                order by e.tweet_id desc
+                limit $5
+            "#,
+            account.id,
+            timeline,
+            until_id,
+            since_id,
+            limit as i64 + 1
+        )
+        .fetch_all(self.conn.as_ref())
+        .await?
+        .into_iter()
+        .filter_map(|rec| serde_json::from_value(rec.data).ok())
+        .collect();
+
+        self.page(tweets, limit).await
+    }
+
+    /// Searches the tweets which have appeared on the timelines of the account, newest first. `pagination_token` is `meta.next_token` of the previous page.
+    pub async fn search(
+        &self,
+        account: &Account,
+        query: &SearchQuery,
+        max_results: usize,
+        pagination_token: Option<&str>,
+    ) -> Result<HistoryPage, HistoryError> {
+        let since_id = query.start_time.as_deref().map(id_at).transpose()?;
+        let until_id = [
+            parse_token(pagination_token)?,
+            query.end_time.as_deref().map(id_at).transpose()?,
+        ]
+        .into_iter()
+        .flatten()
+        .min();
+        let limit = max_results.clamp(1, MAX_RESULTS);
+        let author = query
+            .author
+            .as_deref()
+            .map(|author| author.trim_start_matches('@'));
+
+        let tweets: Vec<Tweet> = sqlx::query!(
+            r#"
+            select t.data from tweets t
+                where exists (
+                        select 1 from timeline_entries e
+                            where e.tweet_id = t.id and e.account_id = $1
+                                and ($2::text is null or e.timeline = $2)
+                    )
+                    and ($3::text is null or t.search @@ $3::tsquery)
+                    and ($4::bigint is null or t.id >= $4)
+                    and ($5::bigint is null or t.id < $5)
+                    and ($6::text is null or t.data->>'author_id' = $6 or t.data->>'author_id' in (
+                        select key from tweet_includes
+                            where kind = 'users' and lower(data->>'username') = lower($6)
+                    ))
+                    and (not $7 or t.data->'attachments'->'media_keys' is not null)
+                order by t.id desc
+                limit $8
+            "#,
+            account.id,
+            query.timeline,
+            search::to_tsquery(&query.query),
+            since_id,
+            until_id,
+            author,
+            query.has_media,
+            limit as i64 + 1
+        )
+        .fetch_all(self.conn.as_ref())
+        .await?
+        .into_iter()
+        .filter_map(|rec| serde_json::from_value(rec.data).ok())
+        .collect();
+
+        self.page(tweets, limit).await
+    }
+
+    // Makes a page of the tweets, which are fetched one more than the limit to tell whether there is the next page.
+    async fn page(
+        &self,
+        mut tweets: Vec<Tweet>,
+        limit: usize,
+    ) -> Result<HistoryPage, HistoryError> {
+        let has_next = tweets.len() > limit;
+        tweets.truncate(limit);
+
+        let includes = self.includes(&tweets).await?;
+        let mut meta = serde_json::json!({ "result_count": tweets.len() });
+        if let (Some(newest), Some(oldest)) = (tweets.first(), tweets.last()) {
+            meta["newest_id"] = newest.id().into();
+            meta["oldest_id"] = oldest.id().into();
+            if has_next {
+                meta["next_token"] = oldest.id().into();
+            }
+        }
+
+        Ok(HistoryPage {
+            tweets,
+            includes,
+            meta,
+        })
+    }
+
+    /// Indexes the stored tweets which are not searchable yet, e.g. the ones stored before search was added. Returns the number of tweets indexed.
+    pub async fn index_unsearchable(&self) -> Result<usize, HistoryError> {
+        let mut indexed = 0;
+        loop {
+            let recs = sqlx::query!(
+                "select id, data from tweets where search is null order by id desc limit $1",
+                MAX_RESULTS as i64
+            )
+            .fetch_all(self.conn.as_ref())
+            .await?;
+            if recs.is_empty() {
+                return Ok(indexed);
+            }
+
+            let tweets: Vec<(i64, Option<Tweet>)> = recs
+                .into_iter()
+                .map(|rec| (rec.id, serde_json::from_value(rec.data).ok()))
+                .collect();
+            // the authors are needed to index their names
+            let parsed: Vec<Tweet> = tweets.iter().filter_map(|(_, t)| t.clone()).collect();
+            let includes = self.includes(&parsed).await?;
+            let page = Page::new(includes.as_ref(), &serde_json::Value::Null);
+            let mut tx = self.conn.begin().await?;
+            for (id, tweet) in &tweets {
+                // broken ones are left unsearchable, but not taken again
+                let document = tweet
+                    .as_ref()
+                    .map(|tweet| search::document(page.hydrate(tweet).as_value()))
+                    .unwrap_or_default();
+                sqlx::query!(
+                    "update tweets set search = $2::tsvector where id = $1",
+                    id,
+                    document as _
+                )
+                .execute(&mut tx)
+                .await?;
+            }
+            tx.commit().await?;
+            indexed += tweets.len();
+        }
+    }
+
+    /// Deletes what has not appeared on any timeline within the retention period.
+    pub async fn prune(&self) -> Result<(), HistoryError> {
+        if self.config.retention_days == 0 {
+            return Ok(());
+        }
+
+        let days = self.config.retention_days as i32;
+        let mut tx = self.conn.begin().await?;
+        sqlx::query!(
+            "delete from timeline_entries where seen_at < now() - make_interval(days => $1)",
+            days
+        )
+        .execute(&mut tx)
+        .await?;
+        sqlx::query!(
+            r#"
+            delete from tweets
+                where fetched_at < now() - make_interval(days => $1)
+                    and not exists (select 1 from timeline_entries where tweet_id = tweets.id)
+            "#,
+            days
+        )
+        .execute(&mut tx)
+        .await?;
+        sqlx::query!(
+            "delete from tweet_includes where fetched_at < now() - make_interval(days => $1)",
+            days
+        )
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    // Looks up the objects the tweets refer to, and the ones the referenced tweets refer to in turn.
+    async fn includes(&self, tweets: &[Tweet]) -> Result<Option<serde_json::Value>, HistoryError> {
+        let mut includes: BTreeMap<&str, Vec<serde_json::Value>> = BTreeMap::new();
+        let mut refs: Vec<(&str, String)> = tweets
+            .iter()
+            .flat_map(|t| references(t.as_value()))
+            .collect();
+        while !refs.is_empty() {
+            refs.sort();
+            refs.dedup();
+            let (kinds, keys): (Vec<String>, Vec<String>) = refs
+                .drain(..)
+                .map(|(kind, key)| (kind.to_owned(), key))
+                .unzip();
+            let found = sqlx::query!(
+                r#"
+                select kind, data from tweet_includes
+                    where (kind, key) in (select * from unnest($1::text[], $2::text[]))
+                    order by kind, key
+                "#,
+                &kinds,
+                &keys
+            )
+            .fetch_all(self.conn.as_ref())
+            .await?;
+
+            for rec in found {
+                let Some((kind, _)) = INCLUDES.iter().find(|(kind, _)| *kind == rec.kind) else {
+                    continue;
+                };
+                // the authors and the media of quoted tweets are needed too, but not their references
+                if *kind == "tweets" {
+                    refs.extend(
+                        references(&rec.data)
+                            .into_iter()
+                            .filter(|(kind, _)| *kind != "tweets"),
+                    );
+                }
+                includes.entry(kind).or_default().push(rec.data);
+            }
+            refs.retain(|(kind, key)| {
+                !includes.get(kind).is_some_and(|items| {
+                    items
+                        .iter()
+                        .any(|item| item_key(kind, item) == Some(key.as_str()))
+                })
+            });
+        }
+
+        if includes.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::to_value(includes).unwrap_or_default()))
+    }
+}
+
+fn parse_token(token: Option<&str>) -> Result<Option<i64>, HistoryError> {
+    token
+        .map(|token| {
+            token
+                .parse::<i64>()
+                .map_err(|_| HistoryError::InvalidToken(token.to_owned()))
+        })
+        .transpose()
+}
+
+// The smallest tweet id created at the time.
+fn id_at(time: &str) -> Result<i64, HistoryError> {
+    let time = chrono::DateTime::parse_from_rfc3339(time)
+        .map_err(|err| HistoryError::InvalidTime(time.to_owned(), err))?;
+    Ok((time.timestamp_millis() - TWEET_EPOCH).max(0) << 22)
+}
+
+// The objects in `includes` which the tweet refers to.
+fn references(tweet: &serde_json::Value) -> Vec<(&'static str, String)> {
+    let strings = |value: &serde_json::Value| -> Vec<String> {
+        value
+            .as_array()
+            .map(|items| {
+                items
+                    .iter()
+                    .filter_map(|item| item.as_str().map(String::from))
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+
+    let mut refs = vec![];
+    if let Some(id) = tweet["author_id"].as_str() {
+        refs.push(("users", id.to_owned()));
+    }
+    refs.extend(
+        strings(&tweet["attachments"]["media_keys"])
+            .into_iter()
+            .map(|key| ("media", key)),
+    );
+    refs.extend(
+        strings(&tweet["attachments"]["poll_ids"])
+            .into_iter()
+            .map(|id| ("polls", id)),
+    );
+    if let Some(id) = tweet["geo"]["place_id"].as_str() {
+        refs.push(("places", id.to_owned()));
+    }
+    if let Some(referenced) = tweet["referenced_tweets"].as_array() {
+        refs.extend(
+            referenced
+                .iter()
+                .filter_map(|r| Some(("tweets", r["id"].as_str()?.to_owned()))),
+        );
+    }
+
+    refs
+}
+
+fn item_key<'a>(kind: &str, item: &'a serde_json::Value) -> Option<&'a str> {
+    let (_, field) = INCLUDES.iter().find(|(k, _)| *k == kind)?;
+    item[*field].as_str()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collect_references() {
+        let tweet = serde_json::json!({
+            "id": "1",
+            "author_id": "10",
+            "attachments": { "media_keys": ["3_1"], "poll_ids": ["7"] },
+            "geo": { "place_id": "p1" },
+            "referenced_tweets": [{ "type": "quoted", "id": "2" }]
+        });
+        assert_eq!(
+            references(&tweet),
+            [
+                ("users", "10".to_owned()),
+                ("media", "3_1".to_owned()),
+                ("polls", "7".to_owned()),
+                ("places", "p1".to_owned()),
+                ("tweets", "2".to_owned()),
+            ]
+        );
+        assert!(references(&serde_json::json!({ "id": "1" })).is_empty());
+
+        let media = serde_json::json!({ "media_key": "3_1" });
+        assert_eq!(item_key("media", &media), Some("3_1"));
+        assert_eq!(item_key("users", &media), None);
+    }
+
+    #[test]
+    fn convert_times_to_ids() -> Result<(), HistoryError> {
+        // created at 2022-10-27T12:34:56.789Z
+        let id = 1585611002114748473;
+        assert!(id_at("2022-10-27T12:34:56Z")? <= id);
+        assert!(id_at("2022-10-27T12:34:57+00:00")? > id);
+        assert!(id_at("2022-10-27T21:34:56+09:00")? <= id);
+        assert!(matches!(
+            id_at("2022-10-27"),
+            Err(HistoryError::InvalidTime(_, _))
+        ));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,335 @@
+use mlua::prelude::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
+use thiserror::Error;
+use tracing::warn;
+
+use crate::{
+    filter::{ErrorPolicy, Filter, FilterError},
+    filter_api,
+    methods::HttpMethod,
+};
+
+/// When a hook runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HookStage {
+    /// Before the request is sent. The script can rewrite or reject the request.
+    Request,
+    /// After the response is received. The script can rewrite the response.
+    Response,
+}
+
+/// A hook declared in `binchotan.toml`, run on `v0.plain` calls to the matching endpoints.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HookSpec {
+    pub method: HttpMethod,
+    /// The endpoint pattern, e.g. `users/:id/followers`. `:x` matches any path segment.
+    pub endpoint: String,
+    pub stage: HookStage,
+    /// The path of the script, relative to the filter directory.
+    pub entrypoint: String,
+}
+
+impl HookSpec {
+    /// Whether the hook runs on the request, at either stage.
+    pub fn hooks(&self, request: &PlainRequest) -> bool {
+        self.method == request.method && filter_api::path_matches(&self.endpoint, &request.endpoint)
+    }
+}
+
+/// A hook along with its script.
+#[derive(Debug, Clone)]
+pub struct Hook {
+    pub spec: HookSpec,
+    pub src: String,
+}
+
+impl Hook {
+    pub fn matches(&self, stage: HookStage, request: &PlainRequest) -> bool {
+        self.spec.stage == stage && self.spec.hooks(request)
+    }
+}
+
+/// A `v0.plain` call as seen by hooks, exposed to the script as `request`.
+#[derive(Debug, Clone, Serialize)]
+pub struct PlainRequest {
+    pub method: HttpMethod,
+    pub endpoint: String,
+    /// The parameters sent as the body.
+    pub body: serde_json::Value,
+}
+
+#[derive(Debug, Error)]
+pub enum HookError {
+    #[error("filter `{0}` rejected the request: {1}")]
+    Rejected(String, String),
+    #[error("filter `{0}` asks for confirmation: {1}")]
+    ConfirmationRequired(String, String, String),
+    #[error("a hook of filter `{0}` failed: {1}")]
+    Run(String, Box<FilterError>),
+    #[error("running hooks was aborted: {0}")]
+    Aborted(#[from] tokio::task::JoinError),
+}
+
+impl HookError {
+    /// Details for the frontend, returned in `data` of the JSON-RPC error.
+    pub fn data(&self) -> Option<serde_json::Value> {
+        match self {
+            HookError::Rejected(filter, reason) => {
+                Some(serde_json::json!({ "filter": filter, "reason": reason }))
+            }
+            HookError::ConfirmationRequired(filter, message, token) => {
+                Some(serde_json::json!({ "filter": filter, "confirm": message, "token": token }))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// What the script decided with the `hook` table, which stops the script.
+#[derive(Debug)]
+pub enum Decision {
+    Reject(String),
+    /// The message and the token which the frontend sends back once the user has confirmed it.
+    Confirm(String, String),
+}
+
+/// The token confirming `message` shown by the filter `filter` for `request`. It changes with any of them, so confirming one prompt does not skip the others nor the same prompt for another request.
+pub fn confirm_token(filter: &str, request: &PlainRequest, message: &str) -> String {
+    let mut hasher = Sha256::new();
+    // SAFETY: PlainRequest is serde::Serialize so it should always be able to be serialized
+    let request = serde_json::to_string(request).unwrap();
+    for part in [filter, &request, message] {
+        hasher.update(part.as_bytes());
+        hasher.update([0]);
+    }
+    hex::encode(hasher.finalize())
+}
+
+/// Creates the `hook` table exposed to hook scripts run by the filter `filter` on `request`. `confirmed` holds the tokens of the prompts which the user has already confirmed.
+pub fn create_lua_table<'lua>(
+    lua: &'lua Lua,
+    filter: &str,
+    request: &PlainRequest,
+    confirmed: &HashSet<String>,
+    decision: Arc<Mutex<Option<Decision>>>,
+) -> LuaResult<LuaTable<'lua>> {
+    let table = lua.create_table()?;
+
+    let d = decision.clone();
+    table.set(
+        "reject",
+        lua.create_function(move |_, reason: String| -> LuaResult<()> {
+            *d.lock().unwrap() = Some(Decision::Reject(reason));
+            Err(LuaError::RuntimeError("the request was rejected".into()))
+        })?,
+    )?;
+
+    let (filter, request, confirmed) = (filter.to_owned(), request.clone(), confirmed.clone());
+    table.set(
+        "confirm",
+        lua.create_function(move |_, message: String| -> LuaResult<()> {
+            let token = confirm_token(&filter, &request, &message);
+            if confirmed.contains(&token) {
+                return Ok(());
+            }
+            *decision.lock().unwrap() = Some(Decision::Confirm(message, token));
+            Err(LuaError::RuntimeError(
+                "the request needs confirmation".into(),
+            ))
+        })?,
+    )?;
+
+    Ok(table)
+}
+
+/// Runs the request hooks of the filters in order, each receiving the request rewritten by the previous ones. Returns the request to send.
+pub fn run_request_hooks(
+    filters: &[Filter],
+    mut request: PlainRequest,
+    confirmed: &HashSet<String>,
+) -> Result<PlainRequest, HookError> {
+    for filter in filters {
+        for hook in &filter.hooks {
+            if !hook.matches(HookStage::Request, &request) {
+                continue;
+            }
+            match filter.run_hook(hook, &request, None, confirmed) {
+                Ok(Some(body)) => request.body = body,
+                Ok(None) => {}
+                Err(err) => tolerate(filter, err)?,
+            }
+        }
+    }
+
+    Ok(request)
+}
+
+/// Runs the response hooks of the filters in order. Returns the response to return to the frontend.
+pub fn run_response_hooks(
+    filters: &[Filter],
+    request: &PlainRequest,
+    mut response: serde_json::Value,
+) -> Result<serde_json::Value, HookError> {
+    for filter in filters {
+        for hook in &filter.hooks {
+            if !hook.matches(HookStage::Response, request) {
+                continue;
+            }
+            match filter.run_hook(hook, request, Some(&response), &HashSet::new()) {
+                Ok(Some(value)) => response = value,
+                Ok(None) => {}
+                Err(err) => tolerate(filter, err)?,
+            }
+        }
+    }
+
+    Ok(response)
+}
+
+/// Runs `run_request_hooks` on the blocking thread pool.
+pub async fn on_request(
+    filters: Arc<Vec<Filter>>,
+    request: PlainRequest,
+    confirmed: HashSet<String>,
+) -> Result<PlainRequest, HookError> {
+    tokio::task::spawn_blocking(move || run_request_hooks(&filters, request, &confirmed)).await?
+}
+
+/// Runs `run_response_hooks` on the blocking thread pool.
+pub async fn on_response(
+    filters: Arc<Vec<Filter>>,
+    request: PlainRequest,
+    response: serde_json::Value,
+) -> Result<serde_json::Value, HookError> {
+    tokio::task::spawn_blocking(move || run_response_hooks(&filters, &request, response)).await?
+}
+
+// Errors in scripts are ignored unless the filter is set to fail the whole request. Rejections are never ignored.
+fn tolerate(filter: &Filter, err: HookError) -> Result<(), HookError> {
+    match err {
+        HookError::Run(_, _) if filter.meta.on_error != ErrorPolicy::Fail => {
+            warn!("ignoring the failed hook: {}", err);
+            Ok(())
+        }
+        err => Err(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{collections::HashSet, path::Path};
+
+    fn phone_guard() -> Filter {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("example_filters/phone_guard");
+        let scopes = ["users.read", "tweet.read", "offline.access"]
+            .into_iter()
+            .map(String::from)
+            .collect::<HashSet<_>>();
+        Filter::load_single(&dir, &scopes, None).unwrap()
+    }
+
+    fn post(text: &str) -> PlainRequest {
+        PlainRequest {
+            method: HttpMethod::Post,
+            endpoint: "tweets".to_owned(),
+            body: serde_json::json!({ "text": text }),
+        }
+    }
+
+    #[test]
+    fn confirm_before_posting() -> Result<(), HookError> {
+        let filters = [phone_guard()];
+
+        let none = HashSet::new();
+        let request = run_request_hooks(&filters, post("いい天気"), &none)?;
+        assert_eq!(request.body["text"], "いい天気");
+
+        let text = "call me at 090-1234-5678";
+        let err = run_request_hooks(&filters, post(text), &none).unwrap_err();
+        assert!(
+            matches!(err, HookError::ConfirmationRequired(ref name, _, _) if name == "phone guard")
+        );
+        let data = err.data().unwrap();
+        assert_eq!(data["filter"], "phone guard");
+        let token = data["token"].as_str().unwrap().to_owned();
+        let confirmed = HashSet::from([token.clone()]);
+        run_request_hooks(&filters, post(text), &confirmed)?;
+
+        // the token confirms neither another request nor another prompt
+        let err = run_request_hooks(&filters, post("or 03 1234 5678"), &confirmed).unwrap_err();
+        assert!(matches!(err, HookError::ConfirmationRequired(_, _, ref t) if *t != token));
+        let mut other = phone_guard();
+        other.id = "other_guard".to_owned();
+        let err = run_request_hooks(&[other], post(text), &confirmed).unwrap_err();
+        assert!(matches!(err, HookError::ConfirmationRequired(_, _, ref t) if *t != token));
+
+        // other endpoints are not hooked
+        let mut request = post("090-1234-5678");
+        request.method = HttpMethod::Get;
+        run_request_hooks(&filters, request, &none)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn rewrite_and_reject() -> Result<(), HookError> {
+        let mut filter = phone_guard();
+        filter.hooks = vec![
+            Hook {
+                spec: HookSpec {
+                    method: HttpMethod::Get,
+                    endpoint: "users/:id/followers".to_owned(),
+                    stage: HookStage::Response,
+                    entrypoint: "followers.lua".to_owned(),
+                },
+                src: r#"
+                    local kept = {}
+                    for _, user in ipairs(response.data) do
+                      if user.id ~= "1" then table.insert(kept, user) end
+                    end
+                    response.data = kept
+                    return response
+                "#
+                .to_owned(),
+            },
+            Hook {
+                spec: HookSpec {
+                    method: HttpMethod::Delete,
+                    endpoint: "tweets/:id".to_owned(),
+                    stage: HookStage::Request,
+                    entrypoint: "delete.lua".to_owned(),
+                },
+                src: r#"hook.reject("tweets cannot be deleted")"#.to_owned(),
+            },
+        ];
+        let filters = [filter];
+
+        let request = PlainRequest {
+            method: HttpMethod::Get,
+            endpoint: "users/123/followers".to_owned(),
+            body: serde_json::json!({}),
+        };
+        let response = serde_json::json!({ "data": [{ "id": "1" }, { "id": "2" }] });
+        let response = run_response_hooks(&filters, &request, response)?;
+        assert_eq!(response, serde_json::json!({ "data": [{ "id": "2" }] }));
+
+        let request = PlainRequest {
+            method: HttpMethod::Delete,
+            endpoint: "tweets/1".to_owned(),
+            body: serde_json::json!({}),
+        };
+        let err = run_request_hooks(&filters, request, &HashSet::new()).unwrap_err();
+        assert!(
+            matches!(err, HookError::Rejected(_, ref reason) if reason == "tweets cannot be deleted")
+        );
+
+        Ok(())
+    }
+}
@@ -1,28 +1,59 @@
-use crate::{auth::Auth, config::Config, connection::Request};
+use crate::{
+    auth::Auth,
+    cli::{Command, PackageCommand},
+    config::Config,
+    connection::Request,
+};
 use anyhow::Context;
 use connection::Handler;
 use credential::CredentialStore;
 use error::AppError;
-use sqlx::postgres::PgPoolOptions;
+use history::HistoryStore;
+use marker::MarkerStore;
+use package::Installer;
+use pipeline::PipelineStore;
+use rules::RulesStore;
+use settings::SettingsStore;
+use sqlx::{postgres::PgPoolOptions, PgPool};
 use std::{
     io::{BufRead, BufReader, Write},
     os::unix::net::{UnixListener, UnixStream},
     path::{Path, PathBuf},
+    sync::Arc,
 };
+use storage::StorageStore;
 use thiserror::Error;
-use tracing::error;
+use tracing::{error, info, warn};
 
+mod annotation;
 mod api;
 mod auth;
 mod cache;
+mod cli;
 mod config;
 mod connection;
 mod credential;
 mod error;
 mod filter;
+mod filter_api;
+mod filter_test;
+mod history;
+mod hooks;
+mod marker;
 mod methods;
 mod models;
+mod modules;
+mod package;
+mod pipeline;
+mod rules;
+mod search;
+mod settings;
+mod stdlib;
+mod storage;
+mod timeline;
+mod trace;
 mod tweet;
+mod wasm;
 
 const VERSION: &str = "0.1.0";
 
@@ -31,9 +62,26 @@ async fn main() -> Result<(), AppError> {
     dotenvy::dotenv().ok();
     tracing_subscriber::fmt::init();
 
-    let config = Config::new()?;
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let command = match Command::parse(&args) {
+        Ok(command) => command,
+        Err(usage) => {
+            eprintln!("{}", usage);
+            std::process::exit(2);
+        }
+    };
 
-    let result = start(config).await;
+    let result = match command {
+        // runs without the config, as it touches neither the database nor the API
+        Command::FilterTest(dirs) => {
+            if !cli::filter_test(&dirs)? {
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        Command::Serve => start(Config::new()?).await,
+        Command::Package(command) => package(Config::new()?, command).await,
+    };
     if let Err(err) = &result {
         println!("{}", err);
     }
@@ -41,18 +89,49 @@ async fn main() -> Result<(), AppError> {
     result
 }
 
+async fn connect(database_url: &str) -> Result<PgPool, AppError> {
+    let conn = PgPoolOptions::new()
+        .max_connections(5)
+        .connect(database_url)
+        .await
+        .context("could not connect to the database")?;
+    Ok(conn)
+}
+
+async fn package(config: Config, command: PackageCommand) -> Result<(), AppError> {
+    let conn = connect(&config.database_url).await?;
+    let installer = Installer::new(conn, config.filter_dir, config.scopes);
+    if !cli::package(&installer, command).await? {
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
+
 async fn start(config: Config) -> Result<(), AppError> {
+    let filter_pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(config.filter_workers())
+        .thread_name(|i| format!("filter-{}", i))
+        .build()
+        .context("could not start the threads applying filters")?;
     let auth = Auth::new(
         config.twitter_client_id,
         config.twitter_client_secret,
         config.redirect_host,
         config.scopes.clone(),
     );
-    let conn = PgPoolOptions::new()
-        .max_connections(5)
-        .connect(&config.database_url)
-        .await
-        .context("could not connect to the database")?;
+    let conn = connect(&config.database_url).await?;
+    let pipelines = PipelineStore::new(conn.clone(), config.pipeline, config.pipelines);
+    let settings = SettingsStore::new(conn.clone());
+    let storage = StorageStore::new(conn.clone());
+    let rules = RulesStore::new(conn.clone());
+    let markers = MarkerStore::new(conn.clone());
+    let history = HistoryStore::new(conn.clone(), config.history);
+    let installer = Installer::new(
+        conn.clone(),
+        config.filter_dir.clone(),
+        config.scopes.clone(),
+    );
     let store = CredentialStore::new(config.cache_path.into(), auth, conn)?;
 
     let mut listener = Listener::new(&config.socket_path)?;
@@ -64,15 +143,54 @@ async fn start(config: Config) -> Result<(), AppError> {
     })
     .context("could not create a Ctrl-C(SIGINT) handler")?;
 
-    // validate filters' scopes in advance
-    filter::Filter::load(config.filter_dir.as_ref(), &config.scopes)?;
+    // validate filters' scopes in advance, and warn about the ones which need approval
+    let filters = filter::Filter::load(
+        config.filter_dir.as_ref(),
+        &config.scopes,
+        &config.filter_settings,
+    )?;
+    installer.adopt(&filters).await?;
+    installer.verify(filters).await?;
 
     let handler = Handler {
         store,
+        pipelines,
+        settings,
+        storage,
+        rules,
+        markers,
+        history: history.clone(),
+        installer,
+        api_cache: Default::default(),
         filter_path: config.filter_dir.clone(),
+        package_dir: config.package_dir,
         scopes: config.scopes.clone(),
+        filter_pool: Arc::new(filter_pool),
+        fill: config.fill,
+        filter_settings: config.filter_settings,
     };
 
+    // make the tweets stored before they were indexed searchable, without delaying requests
+    let indexer = history.clone();
+    tokio::spawn(async move {
+        match indexer.index_unsearchable().await {
+            Ok(0) => {}
+            Ok(count) => info!("indexed {} stored tweets for search", count),
+            Err(err) => warn!("could not index stored tweets for search: {}", err),
+        }
+    });
+
+    // delete old tweets now and then, as the backend may keep running for days
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(60 * 60));
+        loop {
+            interval.tick().await;
+            if let Err(err) = history.prune().await {
+                warn!("could not delete old tweets: {}", err);
+            }
+        }
+    });
+
     listener.listen(handler).await?;
 
     Ok(())
@@ -0,0 +1,247 @@
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::{collections::HashMap, sync::Arc};
+use thiserror::Error;
+
+use crate::{
+    models::Account,
+    timeline::{TimelineContext, TimelineKind},
+    tweet::compare_ids,
+};
+
+/// The most unread posts remembered per timeline. Older ones are forgotten.
+const UNREAD_LIMIT: usize = 1000;
+
+#[derive(Debug, Error)]
+pub enum MarkerError {
+    #[error("database error: {0}")]
+    Database(#[from] sqlx::Error),
+}
+
+/// Where a timeline request starts from, instead of `since_id` in `api_params`.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Since {
+    /// After the newest post fetched last time, by any frontend.
+    Last,
+}
+
+/// Where the account has fetched and read up to in a timeline, shared among frontends.
+#[derive(Debug, Clone, Serialize)]
+pub struct Marker {
+    pub timeline: String,
+    /// The newest post fetched so far, passed as `since_id` for `since: "last"`.
+    pub newest_id: Option<String>,
+    /// The newest post the user has read, set with `v0.timeline.mark_read`.
+    pub read_id: Option<String>,
+    /// Posts kept by the filters which are newer than `read_id`, newest first.
+    #[serde(skip)]
+    unread: Vec<String>,
+}
+
+impl Marker {
+    pub fn new(timeline: String) -> Self {
+        Self {
+            timeline,
+            newest_id: None,
+            read_id: None,
+            unread: vec![],
+        }
+    }
+
+    pub fn unread(&self) -> usize {
+        self.unread.len()
+    }
+
+    /// Records the posts fetched. `newest_id` is of the response, and `kept` are the ids of the posts which survived the filters.
+    pub fn fetched<'a>(
+        &mut self,
+        newest_id: Option<&str>,
+        kept: impl IntoIterator<Item = &'a str>,
+    ) {
+        if let Some(id) = newest_id {
+            if !is_older(id, self.newest_id.as_deref()) {
+                self.newest_id = Some(id.to_owned());
+            }
+        }
+
+        for id in kept {
+            if is_older(id, self.read_id.as_deref()) || self.unread.iter().any(|u| u == id) {
+                continue;
+            }
+            self.unread.push(id.to_owned());
+        }
+        self.unread.sort_by(|a, b| compare_ids(b, a));
+        self.unread.truncate(UNREAD_LIMIT);
+    }
+
+    /// Moves the read marker to the post. Unread posts up to it are marked as read.
+    pub fn mark_read(&mut self, post_id: String) {
+        self.unread.retain(|id| compare_ids(id, &post_id).is_gt());
+        self.read_id = Some(post_id);
+    }
+}
+
+/// Identifies the timeline of the endpoint for markers, for the account whose id is `user_id`. `users/<user_id>/...` is the same timeline as `users/:id/...`, which the API client resolves to it, and slashes around the path are ignored.
+/// Search results are told apart by their queries, which are percent-encoded.
+pub fn timeline_key(
+    context: &TimelineContext,
+    api_params: &HashMap<String, serde_json::Value>,
+    user_id: &str,
+) -> String {
+    let endpoint = context
+        .endpoint
+        .as_deref()
+        .unwrap_or_default()
+        .trim_matches('/');
+    let endpoint = match endpoint
+        .strip_prefix("users/")
+        .and_then(|rest| rest.strip_prefix(user_id))
+    {
+        Some(rest) if !user_id.is_empty() && (rest.is_empty() || rest.starts_with('/')) => {
+            format!("users/:id{}", rest)
+        }
+        _ => endpoint.to_owned(),
+    };
+    match (
+        context.kind,
+        api_params.get("query").and_then(|q| q.as_str()),
+    ) {
+        (TimelineKind::Search, Some(query)) => {
+            let query: String = url::form_urlencoded::byte_serialize(query.as_bytes()).collect();
+            format!("{}?query={}", endpoint, query)
+        }
+        _ => endpoint,
+    }
+}
+
+// Whether the post is not newer than the marker. Every post is newer than no marker.
+fn is_older(id: &str, marker: Option<&str>) -> bool {
+    marker.is_some_and(|marker| compare_ids(id, marker).is_le())
+}
+
+/// Persists the markers of each timeline, per account.
+pub struct MarkerStore {
+    conn: Arc<PgPool>,
+}
+
+impl MarkerStore {
+    pub fn new(conn: PgPool) -> Self {
+        Self {
+            conn: Arc::new(conn),
+        }
+    }
+
+    /// Returns the marker of the timeline, which is empty if the timeline has never been fetched.
+    pub async fn load(&self, account: &Account, timeline: &str) -> Result<Marker, MarkerError> {
+        let marker = sqlx::query!(
+            r#"
+            select timeline, newest_id, read_id, unread from timeline_markers
+                where account_id = $1 and timeline = $2
+            "#,
+            account.id,
+            timeline
+        )
+        .fetch_optional(self.conn.as_ref())
+        .await?
+        .map(|rec| Marker {
+            timeline: rec.timeline,
+            newest_id: rec.newest_id,
+            read_id: rec.read_id,
+            unread: rec.unread,
+        })
+        .unwrap_or_else(|| Marker::new(timeline.to_owned()));
+
+        Ok(marker)
+    }
+
+    /// Returns the markers of every timeline the account has fetched.
+    pub async fn all(&self, account: &Account) -> Result<Vec<Marker>, MarkerError> {
+        let markers = sqlx::query!(
+            r#"
+            select timeline, newest_id, read_id, unread from timeline_markers
+                where account_id = $1
+                order by timeline
+            "#,
+            account.id
+        )
+        .fetch_all(self.conn.as_ref())
+        .await?
+        .into_iter()
+        .map(|rec| Marker {
+            timeline: rec.timeline,
+            newest_id: rec.newest_id,
+            read_id: rec.read_id,
+            unread: rec.unread,
+        })
+        .collect();
+
+        Ok(markers)
+    }
+
+    pub async fn save(&self, account: &Account, marker: &Marker) -> Result<(), MarkerError> {
+        sqlx::query!(
+            r#"
+            insert into timeline_markers (account_id, timeline, newest_id, read_id, unread)
+                values ($1, $2, $3, $4, $5)
+                on conflict (account_id, timeline) do
+                    update set newest_id = $3, read_id = $4, unread = $5
+            "#,
+            account.id,
+            marker.timeline,
+            marker.newest_id,
+            marker.read_id,
+            &marker.unread
+        )
+        .execute(self.conn.as_ref())
+        .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_timeline_keys() {
+        let key = |endpoint: &str, query: Option<&str>| {
+            let params = query
+                .map(|q| HashMap::from([("query".to_owned(), q.into())]))
+                .unwrap_or_default();
+            timeline_key(&TimelineContext::for_endpoint(endpoint), &params, "42")
+        };
+        let home = "users/:id/timelines/reverse_chronological";
+        assert_eq!(key(home, None), home);
+        assert_eq!(key("/users/42/timelines/reverse_chronological", None), home);
+        assert_eq!(key("users/42/", None), "users/:id");
+        assert_eq!(key("users/420/tweets", None), "users/420/tweets");
+        assert_eq!(key("/lists/42/tweets", None), "lists/42/tweets");
+        assert_eq!(
+            key("tweets/search/recent", Some("炭 lang:ja&x=1")),
+            "tweets/search/recent?query=%E7%82%AD+lang%3Aja%26x%3D1"
+        );
+    }
+
+    #[test]
+    fn count_unread_posts() {
+        let mut marker = Marker::new("users/:id/timelines/reverse_chronological".into());
+        marker.fetched(Some("105"), ["105", "103", "99"]);
+        assert_eq!(marker.newest_id.as_deref(), Some("105"));
+        assert_eq!(marker.unread(), 3);
+
+        marker.mark_read("103".into());
+        assert_eq!(marker.unread, ["105"]);
+
+        // posts up to the read marker are not unread even when fetched again
+        marker.fetched(Some("1000"), ["1000", "110", "105", "101"]);
+        assert_eq!(marker.newest_id.as_deref(), Some("1000"));
+        assert_eq!(marker.unread, ["1000", "110", "105"]);
+
+        // fetching older pages does not move the newest post back
+        marker.fetched(Some("90"), ["90"]);
+        assert_eq!(marker.newest_id.as_deref(), Some("1000"));
+        assert_eq!(marker.unread(), 3);
+    }
+}
@@ -1,4 +1,4 @@
-use sqlx::{PgPool, Pool};
+use sqlx::PgPool;
 
 pub struct Account {
     pub id: i32,
@@ -31,4 +31,22 @@ impl Account {
             .collect();
         Ok(accounts)
     }
+
+    pub async fn find_by_session_key(
+        session_key: &str,
+        conn: &PgPool,
+    ) -> Result<Account, sqlx::Error> {
+        let rec = sqlx::query!("select * from accounts where session_key = $1", session_key)
+            .fetch_one(conn)
+            .await?;
+
+        Ok(Account {
+            id: rec.id,
+            twitter_id: rec.twitter_id,
+            access_token: rec.access_token,
+            refresh_token: rec.refresh_token,
+            session_key: rec.session_key,
+            owned_by: rec.owned_by,
+        })
+    }
 }
@@ -0,0 +1,218 @@
+use mlua::prelude::*;
+use std::{path::Path, sync::Arc};
+
+use crate::package::FilterFiles;
+
+/// The directory in the filter directory which holds modules shared among filters. This is not loaded as a filter.
+pub const LIB_DIR: &str = "_lib";
+
+// The registry key of the environment which scripts are run in, when it is not the globals.
+const ENV_KEY: &str = "binchotan.env";
+
+// A module found in the files.
+struct Chunk<'a> {
+    // the name shown in error messages, e.g. `mute_word/words.lua`
+    name: String,
+    src: &'a [u8],
+}
+
+/// Lua modules available to a filter via `require`. Modules are resolved only inside the directory of the filter and the shared `_lib` directory, in this order.
+/// They are taken from the files read when the filter was loaded, not from the disk.
+#[derive(Debug, Default)]
+pub struct Modules {
+    id: String,
+    files: Arc<FilterFiles>,
+}
+
+impl Modules {
+    /// Creates the module resolver for the filter named `id`, whose files are `files`.
+    pub fn new(id: &str, files: Arc<FilterFiles>) -> Self {
+        Self {
+            id: id.to_owned(),
+            files,
+        }
+    }
+
+    fn find(&self, name: &str) -> LuaResult<Chunk<'_>> {
+        let valid = !name.is_empty()
+            && name.split('.').all(|part| {
+                !part.is_empty()
+                    && part
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+            });
+        if !valid {
+            return Err(LuaError::RuntimeError(format!(
+                "invalid module name '{}'",
+                name
+            )));
+        }
+
+        let rel = name.replace('.', "/");
+        let candidates = [format!("{}.lua", rel), format!("{}/init.lua", rel)];
+        let mut tried = vec![];
+        for (files, prefix) in [
+            (&self.files.own, self.id.as_str()),
+            (&self.files.lib, LIB_DIR),
+        ] {
+            for candidate in &candidates {
+                let name = format!("{}/{}", prefix, candidate);
+                match files.get(Path::new(candidate)) {
+                    Some(src) => return Ok(Chunk { name, src }),
+                    None => tried.push(name),
+                }
+            }
+        }
+
+        Err(LuaError::RuntimeError(format!(
+            "module '{}' not found; tried {}",
+            name,
+            tried.join(", ")
+        )))
+    }
+
+    /// Replaces `require` of the Lua state with the one which resolves modules with this resolver. Modules already in `package.loaded` (e.g. `binchotan`) are returned as they are.
+    /// Functions which read files or touch the system otherwise (`dofile`, `loadfile`, `io`, `package.loadlib` and most of `os`) are removed as well.
+    pub fn install(lua: &Lua, modules: Arc<Modules>) -> LuaResult<()> {
+        let globals = lua.globals();
+        let package: LuaTable = globals.get("package")?;
+        let loaded: LuaTable = package.get("loaded")?;
+        // disable the default searchers
+        package.set("path", "")?;
+        package.set("cpath", "")?;
+        package.set("searchers", lua.create_table()?)?;
+        for name in ["loadlib", "searchpath"] {
+            package.set(name, LuaNil)?;
+        }
+        for name in ["dofile", "loadfile", "io"] {
+            globals.set(name, LuaNil)?;
+            loaded.set(name, LuaNil)?;
+        }
+        // only the functions about time are left in `os`
+        if let Some(os) = globals.get::<_, Option<LuaTable>>("os")? {
+            let safe = lua.create_table()?;
+            for name in ["clock", "date", "difftime", "time"] {
+                safe.set(name, os.get::<_, LuaValue>(name)?)?;
+            }
+            globals.set("os", safe.clone())?;
+            loaded.set("os", safe)?;
+        }
+
+        let require = lua.create_function(move |lua, name: String| {
+            let env = Self::env(lua)?;
+            let loaded: LuaTable = env.get::<_, LuaTable>("package")?.get("loaded")?;
+            let value: LuaValue = loaded.get(name.as_str())?;
+            if value != LuaNil {
+                return Ok(value);
+            }
+
+            let chunk = modules.find(&name)?;
+            let value: LuaValue = lua
+                .load(chunk.src)
+                .set_name(format!("@{}", chunk.name))?
+                .set_environment(env)?
+                .call(name.as_str())
+                .map_err(|err| {
+                    LuaError::RuntimeError(format!(
+                        "error loading module '{}' from {}:\n{}",
+                        name, chunk.name, err
+                    ))
+                })?;
+            // modules which return nothing are marked as loaded, as the standard `require` does
+            let value = match value {
+                LuaNil => LuaValue::Boolean(true),
+                value => value,
+            };
+            loaded.set(name.as_str(), value.clone())?;
+            Ok(value)
+        })?;
+        lua.globals().set("require", require)?;
+
+        Ok(())
+    }
+
+    /// Makes `require` load modules into `env` and keep them in `env.package.loaded`, for the script about to be run with `env` as its globals.
+    pub fn use_env<'lua>(lua: &'lua Lua, env: LuaTable<'lua>) -> LuaResult<()> {
+        lua.set_named_registry_value(ENV_KEY, env)
+    }
+
+    /// Returns the globals of the script being run, which are set with `use_env` or otherwise the globals of the state.
+    pub fn env(lua: &Lua) -> LuaResult<LuaTable<'_>> {
+        Ok(lua
+            .named_registry_value::<_, Option<LuaTable>>(ENV_KEY)?
+            .unwrap_or_else(|| lua.globals()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn modules() -> Arc<Modules> {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("example_filters/mute_word");
+        let files = FilterFiles::read(&dir, &dir.join("../_lib")).unwrap();
+        Arc::new(Modules::new("mute_word", Arc::new(files)))
+    }
+
+    #[test]
+    fn reject_paths_outside() -> LuaResult<()> {
+        let lua = Lua::new();
+        Modules::install(&lua, modules())?;
+
+        for name in ["../echo/main", "/etc/passwd", "a..b", ""] {
+            let err = lua.load(&format!("require {:?}", name)).exec().unwrap_err();
+            assert!(err.to_string().contains("invalid module name"), "{}", err);
+        }
+        let err = lua.load(r#"require "missing""#).exec().unwrap_err();
+        assert!(err.to_string().contains("mute_word/missing.lua"), "{}", err);
+
+        for expr in [
+            "dofile",
+            "loadfile",
+            "io",
+            "os.execute",
+            "os.exit",
+            "os.getenv",
+            "package.loadlib",
+            "package.searchpath",
+            "package.loaded.io",
+            "package.loaded.os.remove",
+        ] {
+            let value: LuaValue = lua.load(expr).eval()?;
+            assert_eq!(value, LuaNil, "{}", expr);
+        }
+        let time: i64 = lua.load("os.time()").eval()?;
+        assert!(time > 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn name_the_file_on_error() -> LuaResult<()> {
+        let mut files = FilterFiles::default();
+        files
+            .own
+            .insert("broken.lua".into(), b"local x = 1\nerror('boom')".to_vec());
+        files.lib.insert(
+            "util.lua".into(),
+            b"count = (count or 0) + 1\nreturn { twice = function(x) return x * 2 end }".to_vec(),
+        );
+        let modules = Arc::new(Modules::new("mute_word", Arc::new(files)));
+        let lua = Lua::new();
+        Modules::install(&lua, modules)?;
+
+        let err = lua.load(r#"require "broken""#).exec().unwrap_err();
+        assert!(
+            err.to_string().contains("mute_word/broken.lua:2"),
+            "{}",
+            err
+        );
+
+        let (value, count): (i64, i64) = lua
+            .load(r#"local util = require "util"; require "util"; return util.twice(21), count"#)
+            .eval()?;
+        assert_eq!((value, count), (42, 1));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,804 @@
+use flate2::read::GzDecoder;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fs::{self, File},
+    io,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+use thiserror::Error;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::{
+    filter::{Filter, FilterError, FilterMeta},
+    modules::LIB_DIR,
+};
+
+#[derive(Debug, Error)]
+pub enum PackageError {
+    #[error("could not extract {0}: {1}")]
+    Archive(PathBuf, io::Error),
+    #[error("{0} contains `{1}`, which is neither a file nor a directory")]
+    UnsupportedEntry(PathBuf, String),
+    #[error("{0} does not contain a filter (binchotan.toml)")]
+    NotFilter(PathBuf),
+    #[error("`{0}` cannot be used as a filter id")]
+    InvalidId(String),
+    #[error("filter `{0}` is already installed. upgrade it instead")]
+    AlreadyInstalled(String),
+    #[error("filter `{0}` is not installed")]
+    NotInstalled(String),
+    #[error("{0} is not in the package directory")]
+    OutsidePackageDir(PathBuf),
+    #[error("filter `{0}` requests scopes which have not been approved: {}", .1.join(","))]
+    ScopesNotApproved(String, Vec<String>),
+    #[error(transparent)]
+    Filter(#[from] FilterError),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("database error: {0}")]
+    Database(#[from] sqlx::Error),
+}
+
+// A directory in the filter directory where a package is unpacked. It is removed on drop, along with whatever is left in it.
+#[derive(Debug)]
+struct Staging(PathBuf);
+
+impl Drop for Staging {
+    fn drop(&mut self) {
+        if self.0.exists() {
+            if let Err(err) = fs::remove_dir_all(&self.0) {
+                warn!("could not remove {}: {}", self.0.display(), err);
+            }
+        }
+    }
+}
+
+/// A filter read from an archive or a directory, validated and waiting to be installed.
+#[derive(Debug)]
+pub struct Package {
+    pub id: String,
+    pub meta: FilterMeta,
+    /// See `content_hash`.
+    pub hash: String,
+    staging: Staging,
+}
+
+impl Package {
+    fn dir(&self) -> PathBuf {
+        self.staging.0.join(&self.id)
+    }
+}
+
+/// What is shown to the user before installing or upgrading a package.
+#[derive(Debug, Serialize)]
+pub struct PackageInfo {
+    pub id: String,
+    pub name: String,
+    pub description: String,
+    pub author: String,
+    pub version: Option<String>,
+    /// Whether a filter with the same id is installed.
+    pub installed: bool,
+    pub installed_version: Option<String>,
+    pub scopes: BTreeSet<String>,
+    /// Scopes which the installed filter does not request. Same as `scopes` if the filter is not installed.
+    pub new_scopes: BTreeSet<String>,
+    pub hash: String,
+}
+
+/// What the user approved for a filter: the hash of its files and the scopes it requested at the time.
+#[derive(Debug, Clone)]
+pub struct Approval {
+    pub hash: String,
+    pub scopes: BTreeSet<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PendingReason {
+    /// The filter has never been approved, e.g. it has been put in the filter directory by hand.
+    Unapproved,
+    /// The files of the filter have been changed since approved.
+    Modified,
+}
+
+/// A filter in the filter directory which is not loaded until the user approves it.
+#[derive(Debug, Serialize)]
+pub struct PendingFilter {
+    pub id: String,
+    pub name: String,
+    pub reason: PendingReason,
+    pub scopes: BTreeSet<String>,
+    /// Scopes which have not been approved. Same as `scopes` if the filter has never been approved.
+    pub new_scopes: BTreeSet<String>,
+}
+
+impl PendingFilter {
+    // Returns none if the filter matches the approval.
+    fn check(filter: &Filter, hash: &str, approval: Option<&Approval>) -> Option<Self> {
+        let scopes: BTreeSet<String> = filter.meta.scopes.iter().cloned().collect();
+        let (reason, new_scopes) = match approval {
+            Some(approval) if approval.hash == hash => return None,
+            Some(approval) => (
+                PendingReason::Modified,
+                scopes.difference(&approval.scopes).cloned().collect(),
+            ),
+            None => (PendingReason::Unapproved, scopes.clone()),
+        };
+
+        Some(Self {
+            id: filter.id.clone(),
+            name: filter.meta.name.clone(),
+            reason,
+            scopes,
+            new_scopes,
+        })
+    }
+}
+
+/// Installs filters into the filter directory, and records the hashes of installed filters so that filters modified afterwards are not loaded until approved again.
+pub struct Installer {
+    conn: Arc<PgPool>,
+    filter_dir: PathBuf,
+    available_scopes: HashSet<String>,
+}
+
+impl Installer {
+    pub fn new(conn: PgPool, filter_dir: PathBuf, available_scopes: HashSet<String>) -> Self {
+        Self {
+            conn: Arc::new(conn),
+            filter_dir,
+            available_scopes,
+        }
+    }
+
+    /// Reads a filter from a `.tar.gz` archive or a directory, and validates it as `Filter::load` does.
+    /// An archive holds either a directory of the filter, whose name becomes the filter id, or the files of the filter at its root, in which case the archive is named after the filter (e.g. `mute_word.tar.gz`).
+    pub fn open(&self, path: &Path) -> Result<Package, PackageError> {
+        let staging = Staging(self.filter_dir.join(format!(".install-{}", Uuid::new_v4())));
+        fs::create_dir(&staging.0)?;
+
+        let id = if path.is_dir() {
+            let id = Filter::id_for(path);
+            copy_dir(path, &staging.0.join(&id))?;
+            id
+        } else {
+            let unpacked = staging.0.join(".archive");
+            extract(path, &unpacked)?;
+            let (root, id) = if unpacked.join("binchotan.toml").is_file() {
+                let name = Filter::id_for(path);
+                let id = name
+                    .strip_suffix(".tar.gz")
+                    .or_else(|| name.strip_suffix(".tgz"))
+                    .unwrap_or(&name)
+                    .to_owned();
+                (unpacked, id)
+            } else {
+                let dirs: Vec<PathBuf> = unpacked
+                    .read_dir()?
+                    .filter_map(|entry| Some(entry.ok()?.path()))
+                    .collect();
+                match dirs.as_slice() {
+                    [dir] if dir.join("binchotan.toml").is_file() => {
+                        (dir.clone(), Filter::id_for(dir))
+                    }
+                    _ => return Err(PackageError::NotFilter(path.to_owned())),
+                }
+            };
+            fs::rename(root, staging.0.join(&id))?;
+            id
+        };
+        if !valid_id(&id) {
+            return Err(PackageError::InvalidId(id));
+        }
+
+        let dir = staging.0.join(&id);
+        if !dir.join("binchotan.toml").is_file() {
+            return Err(PackageError::NotFilter(path.to_owned()));
+        }
+        let meta = Filter::load_single(&dir, &self.available_scopes, None)?.meta;
+        // the package is not in the filter directory yet, so the shared modules are taken from there
+        let hash = content_hash(&dir, &self.filter_dir.join(LIB_DIR))?;
+
+        Ok(Package {
+            id,
+            meta,
+            hash,
+            staging,
+        })
+    }
+
+    /// Describes the package, compared with the filter installed with the same id if any.
+    pub fn inspect(&self, package: &Package) -> PackageInfo {
+        let dir = self.filter_dir.join(&package.id);
+        let current = FilterMeta::read(&dir).ok();
+        let scopes: BTreeSet<String> = package.meta.scopes.iter().cloned().collect();
+        let new_scopes = match &current {
+            Some(current) => scopes
+                .difference(&current.scopes.iter().cloned().collect())
+                .cloned()
+                .collect(),
+            None => scopes.clone(),
+        };
+
+        PackageInfo {
+            id: package.id.clone(),
+            name: package.meta.name.clone(),
+            description: package.meta.description.clone(),
+            author: package.meta.author.clone(),
+            version: package.meta.version.clone(),
+            installed: dir.exists(),
+            installed_version: current.and_then(|meta| meta.version),
+            scopes,
+            new_scopes,
+            hash: package.hash.clone(),
+        }
+    }
+
+    /// Installs the package as a new filter. Every scope the filter requests must be in `approved`.
+    pub async fn install(
+        &self,
+        package: Package,
+        approved: &HashSet<String>,
+    ) -> Result<PackageInfo, PackageError> {
+        let info = self.inspect(&package);
+        if info.installed {
+            return Err(PackageError::AlreadyInstalled(package.id));
+        }
+        check_approved(&package.id, &package.meta, approved)?;
+
+        let dest = self.filter_dir.join(&package.id);
+        fs::rename(package.dir(), &dest)?;
+        if let Err(err) = self.record(&package.id, &package.meta, &package.hash).await {
+            fs::remove_dir_all(&dest)?;
+            return Err(err);
+        }
+        info!(
+            "installed filter {} (version {})",
+            package.id,
+            package.meta.version.as_deref().unwrap_or("unknown")
+        );
+
+        Ok(info)
+    }
+
+    /// Replaces the installed filter with the package. Every scope the new version requests must be in `approved`.
+    pub async fn upgrade(
+        &self,
+        package: Package,
+        approved: &HashSet<String>,
+    ) -> Result<PackageInfo, PackageError> {
+        let info = self.inspect(&package);
+        if !info.installed {
+            return Err(PackageError::NotInstalled(package.id));
+        }
+        check_approved(&package.id, &package.meta, approved)?;
+
+        // the old version is moved into the staging directory, and removed along with it
+        let dest = self.filter_dir.join(&package.id);
+        let old = package.staging.0.join(".old");
+        fs::rename(&dest, &old)?;
+        if let Err(err) = fs::rename(package.dir(), &dest) {
+            fs::rename(&old, &dest)?;
+            return Err(err.into());
+        }
+        if let Err(err) = self.record(&package.id, &package.meta, &package.hash).await {
+            fs::remove_dir_all(&dest)?;
+            fs::rename(&old, &dest)?;
+            return Err(err);
+        }
+        info!(
+            "upgraded filter {} ({} -> {})",
+            package.id,
+            info.installed_version.as_deref().unwrap_or("unknown"),
+            package.meta.version.as_deref().unwrap_or("unknown")
+        );
+
+        Ok(info)
+    }
+
+    /// Removes the filter from the filter directory. Settings, storage and rules saved for accounts are kept, so that they are used again if the filter is reinstalled.
+    pub async fn uninstall(&self, id: &str) -> Result<(), PackageError> {
+        if !valid_id(id) {
+            return Err(PackageError::InvalidId(id.to_owned()));
+        }
+        let dir = self.filter_dir.join(id);
+        if !dir.is_dir() {
+            return Err(PackageError::NotInstalled(id.to_owned()));
+        }
+
+        fs::remove_dir_all(&dir)?;
+        sqlx::query!("delete from installed_filters where filter_id = $1", id)
+            .execute(self.conn.as_ref())
+            .await?;
+        info!("uninstalled filter {}", id);
+
+        Ok(())
+    }
+
+    /// Returns the filters which have been approved, along with what was approved.
+    pub async fn approvals(&self) -> Result<HashMap<String, Approval>, PackageError> {
+        let approvals = sqlx::query!("select filter_id, hash, scopes from installed_filters")
+            .fetch_all(self.conn.as_ref())
+            .await?
+            .into_iter()
+            .map(|rec| {
+                let approval = Approval {
+                    hash: rec.hash,
+                    scopes: rec.scopes.into_iter().collect(),
+                };
+                (rec.filter_id, approval)
+            })
+            .collect();
+
+        Ok(approvals)
+    }
+
+    /// Separates the filters which are not approved, or have been modified since approved, from the others. Those filters should not be run until approved with `approve`.
+    pub async fn verify(
+        &self,
+        filters: Vec<Filter>,
+    ) -> Result<(Vec<Filter>, Vec<PendingFilter>), PackageError> {
+        let approvals = self.approvals().await?;
+        let mut verified = vec![];
+        let mut pending = vec![];
+        for filter in filters {
+            match PendingFilter::check(&filter, &filter.hash, approvals.get(&filter.id)) {
+                None => verified.push(filter),
+                Some(p) => {
+                    match (p.reason, p.new_scopes.is_empty()) {
+                        (PendingReason::Modified, true) => warn!(
+                            "filter {} has been modified since approved, and is not loaded until approved again",
+                            p.id
+                        ),
+                        (PendingReason::Modified, false) => warn!(
+                            "filter {} has been modified since approved, and now requests additional scopes: {}. it is not loaded until approved again",
+                            p.id,
+                            p.new_scopes.iter().cloned().collect::<Vec<_>>().join(",")
+                        ),
+                        (PendingReason::Unapproved, _) => {
+                            warn!("filter {} is not loaded until approved", p.id)
+                        }
+                    }
+                    pending.push(p);
+                }
+            }
+        }
+
+        Ok((verified, pending))
+    }
+
+    /// Approves the filters in the filter directory as they are, only the first time the backend runs with approvals. Otherwise the filters set up before approvals were introduced would stop running after upgrading the backend. Filters approved before (e.g. with `filter install`) are kept as they were.
+    pub async fn adopt(&self, filters: &[Filter]) -> Result<(), PackageError> {
+        let mut tx = self.conn.begin().await?;
+        let recorded = sqlx::query!(r#"select count(*) as "count!" from approval_baseline"#)
+            .fetch_one(&mut tx)
+            .await?
+            .count
+            > 0;
+        if recorded {
+            return Ok(());
+        }
+
+        for filter in filters {
+            let mut scopes: Vec<String> = filter.meta.scopes.iter().cloned().collect();
+            scopes.sort();
+            let adopted = sqlx::query!(
+                r#"
+                insert into installed_filters (filter_id, version, hash, scopes)
+                    values ($1, $2, $3, $4)
+                    on conflict (filter_id) do nothing
+                "#,
+                filter.id,
+                filter.meta.version,
+                filter.hash,
+                &scopes
+            )
+            .execute(&mut tx)
+            .await?
+            .rows_affected();
+            if adopted > 0 {
+                info!(
+                    "approved filter {} ({}), which had been installed before approvals were introduced",
+                    filter.id, filter.hash
+                );
+            }
+        }
+        sqlx::query!("insert into approval_baseline default values")
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Tells whether the filter in the filter directory needs approval, and why.
+    pub async fn review(&self, id: &str) -> Result<Option<PendingFilter>, PackageError> {
+        let (filter, hash) = self.read_installed(id)?;
+        let approvals = self.approvals().await?;
+        Ok(PendingFilter::check(&filter, &hash, approvals.get(id)))
+    }
+
+    /// Approves the filter in the filter directory as it is now. Every scope the filter requests must be in `approved`.
+    pub async fn approve(&self, id: &str, approved: &HashSet<String>) -> Result<(), PackageError> {
+        let (filter, hash) = self.read_installed(id)?;
+        check_approved(id, &filter.meta, approved)?;
+        self.record(id, &filter.meta, &hash).await?;
+        info!("approved filter {} ({})", id, hash);
+
+        Ok(())
+    }
+
+    // Loads the filter in the filter directory, and hashes it.
+    fn read_installed(&self, id: &str) -> Result<(Filter, String), PackageError> {
+        if !valid_id(id) {
+            return Err(PackageError::InvalidId(id.to_owned()));
+        }
+        let dir = self.filter_dir.join(id);
+        if !dir.is_dir() {
+            return Err(PackageError::NotInstalled(id.to_owned()));
+        }
+        let filter = Filter::load_single(&dir, &self.available_scopes, None)?;
+        let hash = filter.hash.clone();
+
+        Ok((filter, hash))
+    }
+
+    async fn record(&self, id: &str, meta: &FilterMeta, hash: &str) -> Result<(), PackageError> {
+        let mut scopes: Vec<String> = meta.scopes.iter().cloned().collect();
+        scopes.sort();
+        sqlx::query!(
+            r#"
+            insert into installed_filters (filter_id, version, hash, scopes)
+                values ($1, $2, $3, $4)
+                on conflict (filter_id) do update
+                    set version = $2, hash = $3, scopes = $4, installed_at = now()
+            "#,
+            id,
+            meta.version,
+            hash,
+            &scopes
+        )
+        .execute(self.conn.as_ref())
+        .await?;
+
+        Ok(())
+    }
+}
+
+fn check_approved(
+    id: &str,
+    meta: &FilterMeta,
+    approved: &HashSet<String>,
+) -> Result<(), PackageError> {
+    let mut missing: Vec<String> = meta.scopes.difference(approved).cloned().collect();
+    if !missing.is_empty() {
+        missing.sort();
+        return Err(PackageError::ScopesNotApproved(id.to_owned(), missing));
+    }
+    Ok(())
+}
+
+/// Resolves a package path given over RPC, which must be in the package directory. Relative paths are taken from the directory, and symlinks are followed before checking so that they cannot point outside of it.
+pub fn resolve(package_dir: Option<&Path>, path: &Path) -> Result<PathBuf, PackageError> {
+    let outside = || PackageError::OutsidePackageDir(path.to_owned());
+    let dir = package_dir.ok_or_else(outside)?.canonicalize()?;
+    let resolved = dir.join(path).canonicalize().map_err(|_| outside())?;
+    if !resolved.starts_with(&dir) {
+        return Err(outside());
+    }
+    Ok(resolved)
+}
+
+// Filter ids are used as directory names and in module names, so they are limited to the characters allowed there.
+fn valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && !id.starts_with('_')
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+fn extract(archive: &Path, dest: &Path) -> Result<(), PackageError> {
+    let error = |err| PackageError::Archive(archive.to_owned(), err);
+    fs::create_dir_all(dest)?;
+    let mut tar = tar::Archive::new(GzDecoder::new(File::open(archive).map_err(error)?));
+    for entry in tar.entries().map_err(error)? {
+        let mut entry = entry.map_err(error)?;
+        let kind = entry.header().entry_type();
+        if kind.is_pax_global_extensions() {
+            continue;
+        }
+        let name = entry
+            .path()
+            .map(|path| path.display().to_string())
+            .unwrap_or_default();
+        // e.g. symlinks, which might point outside the filter
+        if !kind.is_file() && !kind.is_dir() {
+            return Err(PackageError::UnsupportedEntry(archive.to_owned(), name));
+        }
+        // entries outside `dest`, such as `../foo`, are skipped by unpack_in
+        if !entry.unpack_in(dest).map_err(error)? {
+            return Err(PackageError::UnsupportedEntry(archive.to_owned(), name));
+        }
+    }
+
+    Ok(())
+}
+
+fn copy_dir(from: &Path, to: &Path) -> Result<(), FilterError> {
+    let mut files = vec![];
+    collect_files(from, Path::new(""), &mut files)?;
+    fs::create_dir_all(to)?;
+    for file in files {
+        let dest = to.join(&file);
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::copy(from.join(&file), dest)?;
+    }
+
+    Ok(())
+}
+
+// Collects regular files under `root/rel` as paths relative to `root`. Symlinks are refused, as the files they point to could be changed without changing the hash.
+fn collect_files(root: &Path, rel: &Path, files: &mut Vec<PathBuf>) -> Result<(), FilterError> {
+    for entry in root.join(rel).read_dir()? {
+        let entry = entry?;
+        let kind = entry.file_type()?;
+        let path = rel.join(entry.file_name());
+        if kind.is_dir() {
+            collect_files(root, &path, files)?;
+        } else if kind.is_file() {
+            files.push(path);
+        } else if kind.is_symlink() {
+            return Err(FilterError::Symlink(root.join(path)));
+        }
+    }
+
+    Ok(())
+}
+
+/// The files of a filter and of the shared `_lib`, read into memory at once. Filters are run from these, so that what runs is exactly what has been hashed.
+#[derive(Debug, Default)]
+pub struct FilterFiles {
+    /// Files in the directory of the filter, keyed by paths relative to it.
+    pub own: BTreeMap<PathBuf, Vec<u8>>,
+    /// Files in `_lib`, keyed by paths relative to it.
+    pub lib: BTreeMap<PathBuf, Vec<u8>>,
+}
+
+impl FilterFiles {
+    /// Reads every file of the filter in `dir`, and of `lib` if it exists.
+    pub fn read(dir: &Path, lib: &Path) -> Result<Self, FilterError> {
+        let read_all = |root: &Path| -> Result<BTreeMap<PathBuf, Vec<u8>>, FilterError> {
+            let mut files = vec![];
+            collect_files(root, Path::new(""), &mut files)?;
+            files
+                .into_iter()
+                .map(|file| {
+                    let content = fs::read(root.join(&file))?;
+                    Ok((file, content))
+                })
+                .collect()
+        };
+
+        Ok(Self {
+            own: read_all(dir)?,
+            lib: if lib.is_dir() {
+                read_all(lib)?
+            } else {
+                BTreeMap::new()
+            },
+        })
+    }
+
+    /// Returns the file of the filter at the path relative to its directory. Paths going outside the directory are not found.
+    pub fn get(&self, path: &str) -> Option<&[u8]> {
+        let path: PathBuf = Path::new(path)
+            .components()
+            .filter(|c| !matches!(c, Component::CurDir))
+            .collect();
+        self.own.get(&path).map(Vec::as_slice)
+    }
+
+    /// Hashes every file with SHA-256, along with its path relative to the directory. Files in `_lib` are hashed as `../_lib/<path>`, so that changing them requires approving every filter again. Returns the digest in hex.
+    pub fn hash(&self) -> String {
+        let lib = self
+            .lib
+            .iter()
+            .map(|(file, content)| (Path::new("..").join(LIB_DIR).join(file), content));
+        let own = self
+            .own
+            .iter()
+            .map(|(file, content)| (file.clone(), content));
+        let mut hasher = Sha256::new();
+        for (file, content) in own.chain(lib) {
+            hasher.update(file.to_string_lossy().as_bytes());
+            hasher.update([0]);
+            hasher.update((content.len() as u64).to_be_bytes());
+            hasher.update(content);
+        }
+
+        hex::encode(hasher.finalize())
+    }
+}
+
+/// Hashes the files of the filter in `dir` and the modules in `lib`. See `FilterFiles::hash`.
+pub fn content_hash(dir: &Path, lib: &Path) -> Result<String, FilterError> {
+    Ok(FilterFiles::read(dir, lib)?.hash())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_in_package_dir() -> Result<(), PackageError> {
+        let package_dir = std::env::temp_dir().join(format!("binchotan-test-{}", Uuid::new_v4()));
+        fs::create_dir_all(package_dir.join("mute_word"))?;
+        let outside = package_dir.with_extension("outside");
+        fs::create_dir_all(&outside)?;
+        std::os::unix::fs::symlink(&outside, package_dir.join("link"))?;
+        let dir = package_dir.canonicalize()?;
+
+        assert_eq!(
+            resolve(Some(&package_dir), Path::new("mute_word"))?,
+            dir.join("mute_word")
+        );
+        assert_eq!(
+            resolve(Some(&package_dir), &package_dir.join("mute_word"))?,
+            dir.join("mute_word")
+        );
+        for path in [
+            Path::new("../"),
+            Path::new("mute_word/../.."),
+            Path::new("link"),
+            Path::new("missing"),
+            Path::new("/etc"),
+        ] {
+            assert!(matches!(
+                resolve(Some(&package_dir), path),
+                Err(PackageError::OutsidePackageDir(_))
+            ));
+        }
+        assert!(matches!(
+            resolve(None, &package_dir.join("mute_word")),
+            Err(PackageError::OutsidePackageDir(_))
+        ));
+
+        fs::remove_dir_all(&package_dir)?;
+        fs::remove_dir_all(&outside)?;
+        Ok(())
+    }
+
+    #[test]
+    fn hash_changes_with_content() -> Result<(), PackageError> {
+        let examples = Path::new(env!("CARGO_MANIFEST_DIR")).join("example_filters");
+        let filter_dir = std::env::temp_dir().join(format!("binchotan-test-{}", Uuid::new_v4()));
+        let dir = filter_dir.join("mute_word");
+        let lib = filter_dir.join(LIB_DIR);
+        copy_dir(&examples.join("mute_word"), &dir)?;
+        copy_dir(&examples.join(LIB_DIR), &lib)?;
+        let hash = content_hash(&dir, &lib)?;
+        assert_eq!(
+            hash,
+            content_hash(&examples.join("mute_word"), &examples.join(LIB_DIR))?
+        );
+        let filter = Filter::load_single(&dir, &FilterMeta::read(&dir)?.scopes, None)?;
+        assert_eq!(filter.hash, hash);
+
+        // shared modules are part of the hash of every filter
+        fs::write(lib.join("mute.lua"), "return {}")?;
+        let lib_changed = content_hash(&dir, &lib)?;
+        assert_ne!(lib_changed, hash);
+
+        fs::write(dir.join("main.lua"), "return post")?;
+        assert_ne!(content_hash(&dir, &lib)?, lib_changed);
+
+        // the target of a symlink could be changed without changing the hash
+        std::os::unix::fs::symlink(lib.join("mute.lua"), dir.join("words.lua"))?;
+        assert!(matches!(
+            content_hash(&dir, &lib),
+            Err(FilterError::Symlink(_))
+        ));
+        assert!(matches!(
+            Filter::load_single(&dir, &HashSet::new(), None),
+            Err(FilterError::Symlink(_))
+        ));
+        fs::remove_dir_all(&filter_dir)?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn open_archive() -> Result<(), PackageError> {
+        let example = Path::new(env!("CARGO_MANIFEST_DIR")).join("example_filters/mute_word");
+        let filter_dir = std::env::temp_dir().join(format!("binchotan-test-{}", Uuid::new_v4()));
+        fs::create_dir(&filter_dir)?;
+        let scopes = FilterMeta::read(&example)?.scopes;
+        let conn = sqlx::postgres::PgPoolOptions::new().connect_lazy("postgres://localhost")?;
+        let installer = Installer::new(conn, filter_dir.clone(), scopes);
+
+        let archive = filter_dir.join("mute_word.tar.gz");
+        let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(
+            File::create(&archive)?,
+            flate2::Compression::default(),
+        ));
+        builder.append_dir_all("mute_word", &example)?;
+        builder.into_inner()?.finish()?;
+
+        let package = installer.open(&archive)?;
+        assert_eq!(package.id, "mute_word");
+        assert_eq!(
+            package.hash,
+            content_hash(&example, &filter_dir.join(LIB_DIR))?
+        );
+        let info = installer.inspect(&package);
+        assert!(!info.installed);
+        assert_eq!(info.new_scopes, info.scopes);
+        let staging = package.staging.0.clone();
+        drop(package);
+        assert!(!staging.exists());
+
+        let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(
+            File::create(&archive)?,
+            flate2::Compression::default(),
+        ));
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        builder.append_link(&mut header, "mute_word/binchotan.toml", "/etc/passwd")?;
+        builder.into_inner()?.finish()?;
+        assert!(matches!(
+            installer.open(&archive),
+            Err(PackageError::UnsupportedEntry(_, _))
+        ));
+
+        fs::remove_dir_all(&filter_dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn report_scope_escalation() -> Result<(), PackageError> {
+        let example = Path::new(env!("CARGO_MANIFEST_DIR")).join("example_filters/mute_word");
+        let mut filter = Filter::load_single(&example, &FilterMeta::read(&example)?.scopes, None)?;
+        let hash = filter.hash.clone();
+        let approval = Approval {
+            hash: hash.clone(),
+            scopes: filter.meta.scopes.iter().cloned().collect(),
+        };
+        assert!(PendingFilter::check(&filter, &hash, Some(&approval)).is_none());
+
+        let pending = PendingFilter::check(&filter, "changed", Some(&approval)).unwrap();
+        assert_eq!(pending.reason, PendingReason::Modified);
+        assert!(pending.new_scopes.is_empty());
+
+        filter.meta.scopes.insert("dm.read".into());
+        let pending = PendingFilter::check(&filter, "changed", Some(&approval)).unwrap();
+        assert_eq!(
+            pending.new_scopes.into_iter().collect::<Vec<_>>(),
+            ["dm.read"]
+        );
+
+        let pending = PendingFilter::check(&filter, &hash, None).unwrap();
+        assert_eq!(pending.reason, PendingReason::Unapproved);
+        assert_eq!(pending.new_scopes, pending.scopes);
+
+        Ok(())
+    }
+
+    #[test]
+    fn reject_bad_ids() {
+        assert!(valid_id("mute_word"));
+        assert!(valid_id("mute-word2"));
+        for id in ["", "_lib", ".install-1", "../etc", "mute_word-1.0.0"] {
+            assert!(!valid_id(id), "{}", id);
+        }
+    }
+}
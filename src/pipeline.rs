@@ -0,0 +1,264 @@
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+use thiserror::Error;
+use tracing::warn;
+
+use crate::{filter::Filter, models::Account};
+
+/// A list of filters to be applied, in this order.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct PipelineConfig {
+    pub filters: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PipelineEntry {
+    pub filter: String,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Error)]
+pub enum PipelineError {
+    #[error("no filter named `{0}` is installed")]
+    UnknownFilter(String),
+    #[error("database error: {0}")]
+    Database(#[from] sqlx::Error),
+}
+
+/// Decides which filters are applied to each account, and in what order.
+/// Pipelines modified via RPC are saved in the database and take precedence over the ones in the config file; those in the config file are looked up by the session key and then by the Twitter user id, falling back to the default pipeline. If no default pipeline is configured, every installed filter is applied in the order of their ids.
+pub struct PipelineStore {
+    conn: Arc<PgPool>,
+    default: Option<PipelineConfig>,
+    per_account: HashMap<String, PipelineConfig>,
+}
+
+impl PipelineStore {
+    pub fn new(
+        conn: PgPool,
+        default: Option<PipelineConfig>,
+        per_account: HashMap<String, PipelineConfig>,
+    ) -> Self {
+        Self {
+            conn: Arc::new(conn),
+            default,
+            per_account,
+        }
+    }
+
+    /// Returns the pipeline for the account. Installed filters which are not in the pipeline are included as disabled entries at the end.
+    pub async fn entries(
+        &self,
+        account: &Account,
+        installed: &[Filter],
+    ) -> Result<Vec<PipelineEntry>, PipelineError> {
+        let installed: Vec<&str> = installed.iter().map(|f| f.id.as_str()).collect();
+        self.entries_of(account, &installed).await
+    }
+
+    /// Same as `entries`, given the ids of the installed filters instead of the filters.
+    pub async fn entries_of(
+        &self,
+        account: &Account,
+        installed: &[&str],
+    ) -> Result<Vec<PipelineEntry>, PipelineError> {
+        let saved = sqlx::query!(
+            r#"
+            select filter_id, enabled from filter_pipelines
+                where account_id = $1 order by position
+            "#,
+            account.id
+        )
+        .fetch_all(self.conn.as_ref())
+        .await?
+        .into_iter()
+        .map(|rec| PipelineEntry {
+            filter: rec.filter_id,
+            enabled: rec.enabled,
+        })
+        .collect();
+
+        let configured = account
+            .session_key
+            .as_ref()
+            .and_then(|key| self.per_account.get(key))
+            .or_else(|| self.per_account.get(&account.twitter_id))
+            .or(self.default.as_ref());
+
+        Ok(resolve(saved, configured, installed))
+    }
+
+    pub async fn set_enabled(
+        &self,
+        account: &Account,
+        installed: &[Filter],
+        filter_id: &str,
+        enabled: bool,
+    ) -> Result<Vec<PipelineEntry>, PipelineError> {
+        let mut entries = self.entries(account, installed).await?;
+        let entry = entries
+            .iter_mut()
+            .find(|e| e.filter == filter_id)
+            .ok_or_else(|| PipelineError::UnknownFilter(filter_id.to_owned()))?;
+        entry.enabled = enabled;
+
+        self.save(account, &entries).await?;
+        Ok(entries)
+    }
+
+    /// Moves the given filters to the head of the pipeline in this order. The rest of the filters keep their relative order.
+    pub async fn reorder(
+        &self,
+        account: &Account,
+        installed: &[Filter],
+        order: &[String],
+    ) -> Result<Vec<PipelineEntry>, PipelineError> {
+        let mut entries = self.entries(account, installed).await?;
+        let mut reordered = vec![];
+        for id in order {
+            let pos = entries
+                .iter()
+                .position(|e| &e.filter == id)
+                .ok_or_else(|| PipelineError::UnknownFilter(id.to_owned()))?;
+            reordered.push(entries.remove(pos));
+        }
+        reordered.append(&mut entries);
+
+        self.save(account, &reordered).await?;
+        Ok(reordered)
+    }
+
+    async fn save(
+        &self,
+        account: &Account,
+        entries: &[PipelineEntry],
+    ) -> Result<(), PipelineError> {
+        let mut tx = self.conn.begin().await?;
+        sqlx::query!(
+            "delete from filter_pipelines where account_id = $1",
+            account.id
+        )
+        .execute(&mut tx)
+        .await?;
+        for (position, entry) in entries.iter().enumerate() {
+            sqlx::query!(
+                r#"
+                insert into filter_pipelines (account_id, filter_id, position, enabled)
+                    values ($1, $2, $3, $4)
+                "#,
+                account.id,
+                entry.filter,
+                position as i32,
+                entry.enabled
+            )
+            .execute(&mut tx)
+            .await?;
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+}
+
+/// Picks the enabled filters out of `filters` in the order of the pipeline.
+pub fn arrange(entries: &[PipelineEntry], filters: Vec<Filter>) -> Vec<Filter> {
+    let mut filters: HashMap<String, Filter> =
+        filters.into_iter().map(|f| (f.id.clone(), f)).collect();
+    entries
+        .iter()
+        .filter(|e| e.enabled)
+        .filter_map(|e| filters.remove(&e.filter))
+        .collect()
+}
+
+fn resolve(
+    saved: Vec<PipelineEntry>,
+    configured: Option<&PipelineConfig>,
+    installed: &[&str],
+) -> Vec<PipelineEntry> {
+    let (base, rest_enabled) = if !saved.is_empty() {
+        (saved, false)
+    } else if let Some(config) = configured {
+        let entries = config
+            .filters
+            .iter()
+            .map(|id| PipelineEntry {
+                filter: id.clone(),
+                enabled: true,
+            })
+            .collect();
+        (entries, false)
+    } else {
+        (vec![], true)
+    };
+
+    let mut seen = HashSet::new();
+    let mut entries: Vec<PipelineEntry> = base
+        .into_iter()
+        .filter(|e| {
+            if !installed.contains(&e.filter.as_str()) {
+                warn!("filter `{}` in the pipeline is not installed", e.filter);
+                return false;
+            }
+            seen.insert(e.filter.clone())
+        })
+        .collect();
+
+    for id in installed {
+        if !seen.contains(*id) {
+            entries.push(PipelineEntry {
+                filter: id.to_string(),
+                enabled: rest_enabled,
+            });
+        }
+    }
+
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(filter: &str, enabled: bool) -> PipelineEntry {
+        PipelineEntry {
+            filter: filter.to_owned(),
+            enabled,
+        }
+    }
+
+    #[test]
+    fn enable_all_without_config() {
+        let entries = resolve(vec![], None, &["a", "b"]);
+        assert_eq!(entries, vec![entry("a", true), entry("b", true)]);
+    }
+
+    #[test]
+    fn follow_configured_order() {
+        let config = PipelineConfig {
+            filters: vec!["c".into(), "a".into(), "missing".into(), "a".into()],
+        };
+        let entries = resolve(vec![], Some(&config), &["a", "b", "c"]);
+        assert_eq!(
+            entries,
+            vec![entry("c", true), entry("a", true), entry("b", false)]
+        );
+    }
+
+    #[test]
+    fn prefer_saved_pipeline() {
+        let config = PipelineConfig {
+            filters: vec!["a".into()],
+        };
+        let saved = vec![entry("b", true), entry("a", false)];
+        let entries = resolve(saved, Some(&config), &["a", "b", "c"]);
+        assert_eq!(
+            entries,
+            vec![entry("b", true), entry("a", false), entry("c", false)]
+        );
+    }
+}
@@ -0,0 +1,507 @@
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::sync::Arc;
+use thiserror::Error;
+
+use crate::{annotation::Annotation, models::Account, stdlib::MatchOptions};
+
+/// A rule of a filter with `kind = "rules"`: when the post satisfies the condition, the action is taken.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub when: Condition,
+    pub action: Action,
+    /// The reason given when the post is dropped or annotated.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// Labels given when annotated.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub labels: Vec<String>,
+    /// The score given when annotated.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub score: Option<f64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Drop,
+    Annotate,
+}
+
+// misspelled keys are rejected rather than ignored, as every variant would otherwise match some of the keys
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged, deny_unknown_fields)]
+pub enum Condition {
+    All {
+        all: Vec<Condition>,
+    },
+    Any {
+        any: Vec<Condition>,
+    },
+    Not {
+        not: Box<Condition>,
+    },
+    Match {
+        /// A path to the field in the hydrated post separated by dots, e.g. `author.username`. Numbers index into arrays; other keys apply to every element of an array.
+        field: String,
+        op: Op,
+        #[serde(default)]
+        value: serde_json::Value,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        ignore_case: bool,
+        /// NFKC and katakana to hiragana. Regexes are matched against the normalized text and normalized themselves.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        normalize: bool,
+        #[serde(skip)]
+        regex: Option<Regex>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Op {
+    Equals,
+    /// The field equals any of the values in the array.
+    In,
+    Contains,
+    /// The field contains any of the strings in the array.
+    ContainsAny,
+    StartsWith,
+    EndsWith,
+    Regex,
+    Gt,
+    Gte,
+    Lt,
+    Lte,
+    /// The field is present and not null.
+    Exists,
+}
+
+#[derive(Debug, Error)]
+pub enum RulesError {
+    #[error("filter `{0}` is not a rules filter")]
+    NotRules(String),
+    #[error("invalid regex `{0}`: {1}")]
+    InvalidRegex(String, regex::Error),
+    #[error("operator `{0:?}` needs {1} as the value")]
+    InvalidValue(Op, &'static str),
+    #[error("database error: {0}")]
+    Database(#[from] sqlx::Error),
+}
+
+impl Condition {
+    /// Checks the values and compiles the regexes. This must be called before the condition is evaluated.
+    pub fn prepare(&mut self) -> Result<(), RulesError> {
+        match self {
+            Condition::All { all: conds } | Condition::Any { any: conds } => {
+                conds.iter_mut().try_for_each(Condition::prepare)
+            }
+            Condition::Not { not } => not.prepare(),
+            Condition::Match {
+                op,
+                value,
+                ignore_case,
+                normalize,
+                regex,
+                ..
+            } => {
+                let expected = match op {
+                    Op::Equals | Op::Exists => return Ok(()),
+                    Op::In => value.is_array().then_some(()).ok_or("an array"),
+                    Op::ContainsAny => value
+                        .as_array()
+                        .is_some_and(|v| v.iter().all(|v| v.is_string()))
+                        .then_some(())
+                        .ok_or("an array of strings"),
+                    Op::Contains | Op::StartsWith | Op::EndsWith => {
+                        value.is_string().then_some(()).ok_or("a string")
+                    }
+                    Op::Gt | Op::Gte | Op::Lt | Op::Lte => {
+                        value.is_number().then_some(()).ok_or("a number")
+                    }
+                    Op::Regex => {
+                        let pattern = value
+                            .as_str()
+                            .ok_or(RulesError::InvalidValue(*op, "a string"))?;
+                        // case is ignored by the regex itself, so that classes such as `\p{Lu}` still work
+                        let pattern = MatchOptions {
+                            ignore_case: false,
+                            normalize: *normalize,
+                        }
+                        .apply(pattern);
+                        let pattern = if *ignore_case {
+                            format!("(?i){}", pattern)
+                        } else {
+                            pattern
+                        };
+                        *regex = Some(
+                            Regex::new(&pattern)
+                                .map_err(|err| RulesError::InvalidRegex(pattern, err))?,
+                        );
+                        return Ok(());
+                    }
+                };
+                expected.map_err(|expected| RulesError::InvalidValue(*op, expected))
+            }
+        }
+    }
+
+    pub fn matches(&self, post: &serde_json::Value) -> bool {
+        match self {
+            Condition::All { all } => all.iter().all(|c| c.matches(post)),
+            Condition::Any { any } => any.iter().any(|c| c.matches(post)),
+            Condition::Not { not } => !not.matches(post),
+            Condition::Match {
+                field,
+                op,
+                value,
+                ignore_case,
+                normalize,
+                regex,
+            } => {
+                let opts = MatchOptions {
+                    ignore_case: *ignore_case,
+                    normalize: *normalize,
+                };
+                let path: Vec<&str> = field.split('.').collect();
+                let mut found = vec![];
+                resolve(post, &path, &mut found);
+                found
+                    .into_iter()
+                    .any(|actual| compare(*op, actual, value, &opts, regex.as_ref()))
+            }
+        }
+    }
+}
+
+// Collects the values at the path. Keys other than numbers are applied to every element of arrays.
+fn resolve<'a>(
+    value: &'a serde_json::Value,
+    path: &[&str],
+    found: &mut Vec<&'a serde_json::Value>,
+) {
+    let Some((key, rest)) = path.split_first() else {
+        found.push(value);
+        return;
+    };
+    match value {
+        serde_json::Value::Object(fields) => {
+            if let Some(child) = fields.get(*key) {
+                resolve(child, rest, found);
+            }
+        }
+        serde_json::Value::Array(items) => match key.parse::<usize>() {
+            Ok(i) => {
+                if let Some(child) = items.get(i) {
+                    resolve(child, rest, found);
+                }
+            }
+            Err(_) => {
+                for item in items {
+                    resolve(item, path, found);
+                }
+            }
+        },
+        _ => {}
+    }
+}
+
+fn compare(
+    op: Op,
+    actual: &serde_json::Value,
+    expected: &serde_json::Value,
+    opts: &MatchOptions,
+    regex: Option<&Regex>,
+) -> bool {
+    use serde_json::Value;
+
+    let equals = |expected: &Value| match (actual, expected) {
+        (Value::String(a), Value::String(e)) => opts.apply(a) == opts.apply(e),
+        (Value::Number(a), Value::Number(e)) => a.as_f64() == e.as_f64(),
+        (a, e) => a == e,
+    };
+    let number = |f: fn(f64, f64) -> bool| match (actual.as_f64(), expected.as_f64()) {
+        (Some(a), Some(e)) => f(a, e),
+        _ => false,
+    };
+
+    match op {
+        Op::Exists => !actual.is_null(),
+        Op::Equals => equals(expected),
+        Op::In => expected
+            .as_array()
+            .is_some_and(|values| values.iter().any(equals)),
+        Op::Gt => number(|a, e| a > e),
+        Op::Gte => number(|a, e| a >= e),
+        Op::Lt => number(|a, e| a < e),
+        Op::Lte => number(|a, e| a <= e),
+        _ => {
+            let Some(actual) = actual.as_str() else {
+                return false;
+            };
+            if op == Op::Regex {
+                let actual = MatchOptions {
+                    ignore_case: false,
+                    ..*opts
+                }
+                .apply(actual);
+                return regex.is_some_and(|re| re.is_match(&actual));
+            }
+            let actual = opts.apply(actual);
+            let test = |word: &str| {
+                let word = opts.apply(word);
+                match op {
+                    Op::StartsWith => actual.starts_with(&word),
+                    Op::EndsWith => actual.ends_with(&word),
+                    _ => actual.contains(&word),
+                }
+            };
+            match expected {
+                Value::String(word) => test(word),
+                Value::Array(words) => words.iter().filter_map(Value::as_str).any(test),
+                _ => false,
+            }
+        }
+    }
+}
+
+/// What the rules decided for a post.
+#[derive(Debug, Default, PartialEq)]
+pub struct Verdict {
+    /// Set if the post is to be dropped, with the reason of the rule.
+    pub drop: Option<Option<String>>,
+    /// Collected from the matching `annotate` rules.
+    pub annotation: Option<Annotation>,
+}
+
+/// Evaluates the rules on the hydrated post. The first matching `drop` rule drops the post; every matching `annotate` rule before it adds to the annotation.
+pub fn evaluate<'a>(
+    rules: impl IntoIterator<Item = &'a Rule>,
+    post: &serde_json::Value,
+) -> Verdict {
+    let mut verdict = Verdict::default();
+    for rule in rules {
+        if !rule.when.matches(post) {
+            continue;
+        }
+        match rule.action {
+            Action::Drop => {
+                verdict.drop = Some(rule.reason.clone());
+                break;
+            }
+            Action::Annotate => {
+                let annotation = verdict.annotation.get_or_insert_with(Annotation::default);
+                for label in &rule.labels {
+                    if !annotation.labels.contains(label) {
+                        annotation.labels.push(label.clone());
+                    }
+                }
+                if rule.score.is_some() {
+                    annotation.score = rule.score;
+                }
+                if rule.reason.is_some() {
+                    annotation.reason = rule.reason.clone();
+                }
+            }
+        }
+    }
+
+    verdict
+}
+
+/// Keeps the rules added via RPC for each account, which are evaluated after the ones in `binchotan.toml`.
+pub struct RulesStore {
+    conn: Arc<PgPool>,
+}
+
+impl RulesStore {
+    pub fn new(conn: PgPool) -> Self {
+        Self {
+            conn: Arc::new(conn),
+        }
+    }
+
+    pub async fn load(&self, account: &Account, filter_id: &str) -> Result<Vec<Rule>, RulesError> {
+        let rec = sqlx::query!(
+            "select rules from filter_rules where account_id = $1 and filter_id = $2",
+            account.id,
+            filter_id
+        )
+        .fetch_optional(self.conn.as_ref())
+        .await?;
+
+        let Some(rec) = rec else {
+            return Ok(vec![]);
+        };
+        let mut rules: Vec<Rule> = match serde_json::from_value(rec.rules) {
+            Ok(rules) => rules,
+            Err(err) => {
+                tracing::warn!("ignoring broken rules of filter {}: {}", filter_id, err);
+                return Ok(vec![]);
+            }
+        };
+        for rule in &mut rules {
+            rule.when.prepare()?;
+        }
+        Ok(rules)
+    }
+
+    /// Replaces the rules of the account. The rules should be prepared in advance.
+    pub async fn save(
+        &self,
+        account: &Account,
+        filter_id: &str,
+        rules: &[Rule],
+    ) -> Result<(), RulesError> {
+        // SAFETY: rules consist of JSON values, strings and numbers
+        let rules = serde_json::to_value(rules).unwrap();
+        sqlx::query!(
+            r#"
+            insert into filter_rules (account_id, filter_id, rules)
+                values ($1, $2, $3)
+                on conflict (account_id, filter_id) do update set rules = $3
+            "#,
+            account.id,
+            filter_id,
+            rules
+        )
+        .execute(self.conn.as_ref())
+        .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn rules(src: &str) -> Vec<Rule> {
+        #[derive(Deserialize)]
+        struct Rules {
+            rules: Vec<Rule>,
+        }
+        let mut rules = toml::from_str::<Rules>(src).unwrap().rules;
+        for rule in &mut rules {
+            rule.when.prepare().unwrap();
+        }
+        rules
+    }
+
+    #[test]
+    fn drop_and_annotate() {
+        let rules = rules(
+            r#"
+            [[rules]]
+            action = "annotate"
+            labels = ["cw"]
+            reason = "spoiler"
+            when = { field = "text", op = "contains", value = "ネタバレ" }
+
+            [[rules]]
+            action = "drop"
+            reason = "muted"
+            [rules.when]
+            any = [
+                { field = "text", op = "contains_any", value = ["大学", "twitter"], ignore_case = true, normalize = true },
+                { all = [
+                    { field = "author.username", op = "in", value = ["bot1", "bot2"] },
+                    { not = { field = "public_metrics.like_count", op = "gte", value = 10 } },
+                ] },
+            ]
+            "#,
+        );
+
+        let post = json!({ "text": "ネタバレ注意", "author": { "username": "alice" } });
+        let verdict = evaluate(&rules, &post);
+        assert_eq!(verdict.drop, None);
+        assert_eq!(verdict.annotation.unwrap().labels, ["cw"]);
+
+        let post = json!({ "text": "ＴＷＩＴＴＥＲ" });
+        assert_eq!(evaluate(&rules, &post).drop, Some(Some("muted".into())));
+
+        let post = json!({ "text": "hi", "author": { "username": "bot1" }, "public_metrics": { "like_count": 3 } });
+        assert!(evaluate(&rules, &post).drop.is_some());
+        let post = json!({ "text": "hi", "author": { "username": "bot1" }, "public_metrics": { "like_count": 30 } });
+        assert!(evaluate(&rules, &post).drop.is_none());
+    }
+
+    #[test]
+    fn match_elements_of_arrays() {
+        let rules = rules(
+            r#"
+            [[rules]]
+            action = "drop"
+            when = { field = "entities.hashtags.tag", op = "regex", value = "^spoiler", ignore_case = true }
+
+            [[rules]]
+            action = "drop"
+            when = { field = "referenced.0.type", op = "equals", value = "retweeted" }
+            "#,
+        );
+
+        let post =
+            json!({ "entities": { "hashtags": [{ "tag": "rust" }, { "tag": "Spoilers" }] } });
+        assert!(evaluate(&rules, &post).drop.is_some());
+        let post = json!({ "referenced": [{ "type": "retweeted" }] });
+        assert!(evaluate(&rules, &post).drop.is_some());
+        let post = json!({ "referenced": [{ "type": "quoted" }], "entities": { "hashtags": [] } });
+        assert!(evaluate(&rules, &post).drop.is_none());
+    }
+
+    #[test]
+    fn reject_invalid_values() {
+        let mut cond: Condition =
+            serde_json::from_value(json!({ "field": "text", "op": "contains", "value": 1 }))
+                .unwrap();
+        assert!(matches!(
+            cond.prepare(),
+            Err(RulesError::InvalidValue(Op::Contains, _))
+        ));
+
+        let mut cond: Condition =
+            serde_json::from_value(json!({ "field": "text", "op": "regex", "value": "(" }))
+                .unwrap();
+        assert!(matches!(
+            cond.prepare(),
+            Err(RulesError::InvalidRegex(_, _))
+        ));
+
+        for misspelled in [
+            json!({ "field": "text", "op": "contains", "value": "a", "ignorecase": true }),
+            json!({ "all": [], "any": [] }),
+            json!({ "not": { "field": "text", "op": "exists" }, "normalise": true }),
+        ] {
+            assert!(serde_json::from_value::<Condition>(misspelled).is_err());
+        }
+        let rule = json!({ "action": "drop", "lables": ["cw"], "when": { "all": [] } });
+        assert!(serde_json::from_value::<Rule>(rule).is_err());
+    }
+
+    #[test]
+    fn normalize_regexes() {
+        let rules = rules(
+            r#"
+            [[rules]]
+            action = "drop"
+            when = { field = "text", op = "regex", value = "ツイ(ッ|ー)タ", normalize = true }
+
+            [[rules]]
+            action = "drop"
+            when = { field = "source", op = "regex", value = "^BOT", ignore_case = true, normalize = true }
+            "#,
+        );
+
+        for text in ["ついったー", "ﾂｲｯﾀｰ", "ツイーター"] {
+            let post = json!({ "text": text });
+            assert!(evaluate(&rules, &post).drop.is_some(), "{}", text);
+        }
+        let post = json!({ "text": "tweet", "source": "ｂｏｔ client" });
+        assert!(evaluate(&rules, &post).drop.is_some());
+        let post = json!({ "text": "ツイート", "source": "client" });
+        assert!(evaluate(&rules, &post).drop.is_none());
+    }
+}
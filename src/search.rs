@@ -0,0 +1,168 @@
+use serde::Deserialize;
+
+/// Conditions of `v0.history.search`.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct SearchQuery {
+    /// Words which every result contains in its text, author or entities.
+    #[serde(default)]
+    pub query: String,
+    /// Limits the results to the timeline, as returned in `meta.timeline` of `v0.timeline`.
+    pub timeline: Option<String>,
+    /// The username or the id of the author.
+    pub author: Option<String>,
+    /// The oldest time of the results in RFC 3339, e.g. `2022-12-01T00:00:00Z`.
+    pub start_time: Option<String>,
+    /// The time which the results are older than, in RFC 3339.
+    pub end_time: Option<String>,
+    #[serde(default)]
+    pub has_media: bool,
+}
+
+// The largest position in a tsvector. Larger ones are taken as this by Postgres.
+const MAX_POSITION: usize = 16383;
+
+/// The `tsvector` of the tweet in its text form, holding the lexemes of the text, the username and the name of the author, and the entities, along with their positions. `tweet` must be hydrated with `author`.
+pub fn document(tweet: &serde_json::Value) -> String {
+    let mut texts = vec![
+        &tweet["text"],
+        &tweet["author"]["username"],
+        &tweet["author"]["name"],
+    ];
+    let entities = &tweet["entities"];
+    for (kind, field) in [
+        ("hashtags", "tag"),
+        ("cashtags", "tag"),
+        ("mentions", "username"),
+        ("urls", "expanded_url"),
+    ] {
+        if let Some(items) = entities[kind].as_array() {
+            texts.extend(items.iter().map(|item| &item[field]));
+        }
+    }
+
+    let mut entries = vec![];
+    let mut position = 0;
+    for text in texts.into_iter().filter_map(|text| text.as_str()) {
+        for lexeme in tokenize(text, true).into_iter().flatten() {
+            position += 1;
+            entries.push(format!("{}:{}", quote(&lexeme), position.min(MAX_POSITION)));
+        }
+        // a gap keeps phrases from spanning two texts
+        position += 1;
+    }
+    entries.join(" ")
+}
+
+/// Converts the words into a `tsquery` matching documents which contain all of them, or none if there is no word.
+/// The bigrams of a run of CJK characters have to appear in a row, so that e.g. `大学院` does not match `大学` and `学院` apart.
+pub fn to_tsquery(query: &str) -> Option<String> {
+    let terms: Vec<String> = tokenize(query, false)
+        .into_iter()
+        .map(|lexemes| match lexemes.as_slice() {
+            // a single character matches the bigrams starting with it, and the last character of a run
+            [lexeme] if lexeme.chars().count() == 1 && lexeme.chars().all(is_cjk) => {
+                format!("{}:*", quote(lexeme))
+            }
+            [lexeme] => quote(lexeme),
+            lexemes => {
+                let phrase: Vec<String> = lexemes.iter().map(|lexeme| quote(lexeme)).collect();
+                format!("({})", phrase.join(" <-> "))
+            }
+        })
+        .collect();
+    (!terms.is_empty()).then(|| terms.join(" & "))
+}
+
+// Quotes the lexeme for a `tsvector` or a `tsquery`.
+fn quote(lexeme: &str) -> String {
+    format!("'{}'", lexeme.replace('\\', "\\\\").replace('\'', "''"))
+}
+
+// Splits the text into lowercase words. Runs of CJK characters, which are not separated by spaces, are split into bigrams instead. Each word or run makes a group of lexemes.
+// Documents also get the last character of each run so that a single character can be searched for.
+fn tokenize(text: &str, for_document: bool) -> Vec<Vec<String>> {
+    let mut groups = vec![];
+    let mut word = String::new();
+    let mut run: Vec<char> = vec![];
+    let flush = |word: &mut String, run: &mut Vec<char>, groups: &mut Vec<Vec<String>>| {
+        if !word.is_empty() {
+            groups.push(vec![std::mem::take(word)]);
+        }
+        match run.len() {
+            0 => {}
+            1 => groups.push(vec![run[0].to_string()]),
+            _ => {
+                let mut lexemes: Vec<String> =
+                    run.windows(2).map(|pair| pair.iter().collect()).collect();
+                if for_document {
+                    lexemes.push(run[run.len() - 1].to_string());
+                }
+                groups.push(lexemes);
+            }
+        }
+        run.clear();
+    };
+
+    for c in text.chars() {
+        if is_cjk(c) {
+            flush(&mut word, &mut vec![], &mut groups);
+            run.push(c);
+        } else if c.is_alphanumeric() || c == '_' {
+            flush(&mut String::new(), &mut run, &mut groups);
+            word.extend(c.to_lowercase());
+        } else {
+            flush(&mut word, &mut run, &mut groups);
+        }
+    }
+    flush(&mut word, &mut run, &mut groups);
+
+    groups
+}
+
+// Hiragana, katakana, CJK ideographs and hangul, including the prolonged sound mark.
+fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{3040}'..='\u{30ff}'
+        | '\u{3400}'..='\u{4dbf}'
+        | '\u{4e00}'..='\u{9fff}'
+        | '\u{f900}'..='\u{faff}'
+        | '\u{ac00}'..='\u{d7af}'
+        | '\u{ff66}'..='\u{ff9f}'
+        | '\u{20000}'..='\u{2fa1f}'
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_japanese_into_bigrams() {
+        assert_eq!(
+            tokenize("Rustで大学のレポート", true),
+            [
+                vec!["rust"],
+                vec!["で大", "大学", "学の", "のレ", "レポ", "ポー", "ート", "ト"]
+            ]
+        );
+        assert_eq!(tokenize("#炭火 焼き", false), [["炭火"], ["焼き"]]);
+        assert_eq!(to_tsquery("大学 Rust"), Some("'大学' & 'rust'".to_owned()));
+        assert_eq!(
+            to_tsquery("大学院 Rust"),
+            Some("('大学' <-> '学院') & 'rust'".to_owned())
+        );
+        assert_eq!(to_tsquery("学"), Some("'学':*".to_owned()));
+        assert_eq!(to_tsquery(" !? "), None);
+
+        let tweet = serde_json::json!({
+            "text": "新しいフィルタ",
+            "author": { "username": "binchotan", "name": "備長炭" },
+            "entities": { "hashtags": [{ "tag": "Lua" }] }
+        });
+        assert_eq!(
+            document(&tweet),
+            "'新し':1 'しい':2 'いフ':3 'フィ':4 'ィル':5 'ルタ':6 'タ':7 \
+             'binchotan':9 '備長':11 '長炭':12 '炭':13 'lua':15"
+        );
+    }
+}
@@ -0,0 +1,196 @@
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::{collections::BTreeMap, fmt, sync::Arc};
+use thiserror::Error;
+
+use crate::models::Account;
+
+/// Setting values of a filter, keyed by their names.
+pub type Settings = serde_json::Map<String, serde_json::Value>;
+
+/// A setting declared in `binchotan.toml`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SettingSpec {
+    #[serde(rename = "type")]
+    pub kind: SettingType,
+    pub default: serde_json::Value,
+    #[serde(default)]
+    pub description: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SettingType {
+    String,
+    /// A list of strings.
+    List,
+    Number,
+    Boolean,
+}
+
+impl SettingType {
+    fn accepts(&self, value: &serde_json::Value) -> bool {
+        match self {
+            SettingType::String => value.is_string(),
+            SettingType::List => value
+                .as_array()
+                .is_some_and(|items| items.iter().all(|item| item.is_string())),
+            SettingType::Number => value.is_number(),
+            SettingType::Boolean => value.is_boolean(),
+        }
+    }
+}
+
+impl fmt::Display for SettingType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            SettingType::String => "string",
+            SettingType::List => "list of strings",
+            SettingType::Number => "number",
+            SettingType::Boolean => "boolean",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum SettingsError {
+    #[error("unknown setting `{0}`")]
+    UnknownKey(String),
+    #[error("setting `{0}` must be a {1}")]
+    Mismatch(String, SettingType),
+    #[error("database error: {0}")]
+    Database(#[from] sqlx::Error),
+}
+
+/// Ensures that every value is declared in the schema and has the declared type.
+pub fn validate(
+    schema: &BTreeMap<String, SettingSpec>,
+    values: &Settings,
+) -> Result<(), SettingsError> {
+    for (key, value) in values {
+        let spec = schema
+            .get(key)
+            .ok_or_else(|| SettingsError::UnknownKey(key.clone()))?;
+        if !spec.kind.accepts(value) {
+            return Err(SettingsError::Mismatch(key.clone(), spec.kind));
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the default values declared in the schema.
+pub fn defaults(schema: &BTreeMap<String, SettingSpec>) -> Settings {
+    schema
+        .iter()
+        .map(|(key, spec)| (key.clone(), spec.default.clone()))
+        .collect()
+}
+
+/// Keeps setting values given via RPC for each account.
+pub struct SettingsStore {
+    conn: Arc<PgPool>,
+}
+
+impl SettingsStore {
+    pub fn new(conn: PgPool) -> Self {
+        Self {
+            conn: Arc::new(conn),
+        }
+    }
+
+    /// Returns the values saved for the account, keyed by filter ids.
+    pub async fn all(
+        &self,
+        account: &Account,
+    ) -> Result<BTreeMap<String, Settings>, SettingsError> {
+        let recs = sqlx::query!(
+            "select filter_id, settings from filter_settings where account_id = $1",
+            account.id
+        )
+        .fetch_all(self.conn.as_ref())
+        .await?;
+
+        let settings = recs
+            .into_iter()
+            .filter_map(|rec| match rec.settings {
+                serde_json::Value::Object(values) => Some((rec.filter_id, values)),
+                _ => None,
+            })
+            .collect();
+        Ok(settings)
+    }
+
+    /// Saves the values for the filter. The values should be validated in advance.
+    pub async fn save(
+        &self,
+        account: &Account,
+        filter_id: &str,
+        values: Settings,
+    ) -> Result<(), SettingsError> {
+        sqlx::query!(
+            r#"
+            insert into filter_settings (account_id, filter_id, settings)
+                values ($1, $2, $3)
+                on conflict (account_id, filter_id) do update set settings = $3
+            "#,
+            account.id,
+            filter_id,
+            serde_json::Value::Object(values)
+        )
+        .execute(self.conn.as_ref())
+        .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn schema() -> BTreeMap<String, SettingSpec> {
+        toml::from_str(
+            r#"
+            [words]
+            type = "list"
+            default = ["foo"]
+
+            [threshold]
+            type = "number"
+            default = 3
+            "#,
+        )
+        .unwrap()
+    }
+
+    fn settings(value: serde_json::Value) -> Settings {
+        value.as_object().unwrap().clone()
+    }
+
+    #[test]
+    fn accept_valid_values() {
+        let values = settings(json!({ "words": ["bar", "baz"], "threshold": 1.5 }));
+        assert!(validate(&schema(), &values).is_ok());
+    }
+
+    #[test]
+    fn reject_unknown_key() {
+        let values = settings(json!({ "word": ["bar"] }));
+        assert!(matches!(
+            validate(&schema(), &values),
+            Err(SettingsError::UnknownKey(key)) if key == "word"
+        ));
+    }
+
+    #[test]
+    fn reject_mismatched_type() {
+        let values = settings(json!({ "words": ["bar", 1] }));
+        assert!(matches!(
+            validate(&schema(), &values),
+            Err(SettingsError::Mismatch(key, SettingType::List)) if key == "words"
+        ));
+    }
+}
@@ -0,0 +1,319 @@
+use chrono::DateTime;
+use mlua::prelude::*;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
+};
+use unicode_normalization::UnicodeNormalization;
+
+/// Options for the matching functions, given as a table in the last argument. Rules take the same options.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MatchOptions {
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub ignore_case: bool,
+    /// NFKC and katakana to hiragana
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub normalize: bool,
+}
+
+impl MatchOptions {
+    fn from_lua(opts: Option<LuaTable>) -> LuaResult<Self> {
+        let Some(opts) = opts else {
+            return Ok(Self::default());
+        };
+        Ok(Self {
+            ignore_case: opts.get::<_, Option<bool>>("ignore_case")?.unwrap_or(false),
+            normalize: opts.get::<_, Option<bool>>("normalize")?.unwrap_or(false),
+        })
+    }
+
+    /// Converts the text so that texts which should match become the same.
+    pub fn apply(&self, text: &str) -> String {
+        let mut text = if self.normalize {
+            fold_kana(&text.nfkc().collect::<String>())
+        } else {
+            text.to_owned()
+        };
+        if self.ignore_case {
+            text = text.to_lowercase();
+        }
+        text
+    }
+}
+
+/// Replaces katakana with the corresponding hiragana.
+pub fn fold_kana(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            // ァ..ヶ, ヽ and ヾ
+            '\u{30a1}'..='\u{30f6}' | '\u{30fd}' | '\u{30fe}' => {
+                // SAFETY: the hiragana block is 0x60 before the katakana block
+                char::from_u32(c as u32 - 0x60).unwrap()
+            }
+            c => c,
+        })
+        .collect()
+}
+
+/// Creates the `binchotan` table exposed to Lua scripts, which provides helpers for common tasks in filters.
+pub fn create_lua_table(lua: &Lua) -> LuaResult<LuaTable<'_>> {
+    let table = lua.create_table()?;
+
+    table.set(
+        "contains",
+        lua.create_function(
+            |_, (text, word, opts): (String, String, Option<LuaTable>)| {
+                let opts = MatchOptions::from_lua(opts)?;
+                Ok(opts.apply(&text).contains(&opts.apply(&word)))
+            },
+        )?,
+    )?;
+
+    table.set(
+        "contains_any",
+        lua.create_function(
+            |_, (text, words, opts): (String, Vec<String>, Option<LuaTable>)| {
+                let opts = MatchOptions::from_lua(opts)?;
+                let text = opts.apply(&text);
+                Ok(words
+                    .into_iter()
+                    .find(|word| text.contains(&opts.apply(word))))
+            },
+        )?,
+    )?;
+
+    // compiled patterns are kept as long as the Lua state, which serves many posts
+    let cache: RefCell<RegexCache> = RefCell::default();
+    table.set(
+        "regex",
+        lua.create_function(move |lua, (text, pattern): (String, String)| {
+            let mut cache = cache.borrow_mut();
+            let re = cache
+                .get(&pattern)
+                .map_err(|err| LuaError::RuntimeError(format!("binchotan.regex: {}", err)))?;
+            let Some(captures) = re.captures(&text) else {
+                return Ok(LuaNil);
+            };
+            // the whole match comes first, followed by the groups; groups which did not participate are false
+            let groups = captures
+                .iter()
+                .map(|m| match m {
+                    Some(m) => lua.create_string(m.as_str()).map(LuaValue::String),
+                    None => Ok(LuaValue::Boolean(false)),
+                })
+                .collect::<LuaResult<Vec<_>>>()?;
+            Ok(LuaValue::Table(lua.create_sequence_from(groups)?))
+        })?,
+    )?;
+
+    table.set(
+        "normalize",
+        lua.create_function(|_, text: String| Ok(text.nfkc().collect::<String>()))?,
+    )?;
+
+    table.set(
+        "fold_kana",
+        lua.create_function(|_, text: String| Ok(fold_kana(&text)))?,
+    )?;
+
+    table.set(
+        "urls",
+        lua.create_function(|_, post: LuaTable| {
+            entities(post, "urls", |e| {
+                Ok(e.get::<_, Option<String>>("expanded_url")?
+                    .or(e.get("url")?))
+            })
+        })?,
+    )?;
+
+    table.set(
+        "hashtags",
+        lua.create_function(|_, post: LuaTable| entities(post, "hashtags", |e| e.get("tag")))?,
+    )?;
+
+    table.set(
+        "mentions",
+        lua.create_function(|_, post: LuaTable| entities(post, "mentions", |e| e.get("username")))?,
+    )?;
+
+    table.set(
+        "parse_time",
+        lua.create_function(|_, text: String| {
+            let time = DateTime::parse_from_rfc3339(&text).map_err(|err| {
+                LuaError::RuntimeError(format!("binchotan.parse_time: {}: {}", text, err))
+            })?;
+            Ok(time.timestamp_millis() as f64 / 1000.0)
+        })?,
+    )?;
+
+    table.set(
+        "now",
+        lua.create_function(|_, ()| Ok(chrono::Utc::now().timestamp_millis() as f64 / 1000.0))?,
+    )?;
+
+    table.set(
+        "json_encode",
+        lua.create_function(|lua, value: LuaValue| {
+            let value: serde_json::Value = lua.from_value(value)?;
+            serde_json::to_string(&value).map_err(LuaError::external)
+        })?,
+    )?;
+
+    table.set(
+        "json_decode",
+        lua.create_function(|lua, text: String| {
+            let value: serde_json::Value =
+                serde_json::from_str(&text).map_err(LuaError::external)?;
+            lua.to_value(&value)
+        })?,
+    )?;
+
+    Ok(table)
+}
+
+// Collects a field of each entity of the kind in the post, e.g. `tag` in `post.entities.hashtags`.
+fn entities<'lua>(
+    post: LuaTable<'lua>,
+    kind: &str,
+    field: impl Fn(LuaTable<'lua>) -> LuaResult<Option<String>>,
+) -> LuaResult<Vec<String>> {
+    let Some(entities) = post.get::<_, Option<LuaTable>>("entities")? else {
+        return Ok(vec![]);
+    };
+    let Some(list) = entities.get::<_, Option<LuaTable>>(kind)? else {
+        return Ok(vec![]);
+    };
+
+    let mut values = vec![];
+    for entity in list.sequence_values::<LuaTable>() {
+        if let Some(value) = field(entity?)? {
+            values.push(value);
+        }
+    }
+    Ok(values)
+}
+
+// The most patterns kept compiled by `binchotan.regex`.
+const REGEX_CACHE_SIZE: usize = 64;
+
+// Compiled patterns, of which the oldest one is dropped when more than `REGEX_CACHE_SIZE` are compiled, e.g. when patterns are made from posts.
+#[derive(Default)]
+struct RegexCache {
+    regexes: HashMap<String, Regex>,
+    // in the order compiled
+    patterns: VecDeque<String>,
+}
+
+impl RegexCache {
+    fn get(&mut self, pattern: &str) -> Result<&Regex, regex::Error> {
+        if !self.regexes.contains_key(pattern) {
+            let re = Regex::new(pattern)?;
+            if self.patterns.len() >= REGEX_CACHE_SIZE {
+                if let Some(oldest) = self.patterns.pop_front() {
+                    self.regexes.remove(&oldest);
+                }
+            }
+            self.patterns.push_back(pattern.to_owned());
+            self.regexes.insert(pattern.to_owned(), re);
+        }
+        Ok(&self.regexes[pattern])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lua() -> LuaResult<Lua> {
+        let lua = Lua::new();
+        lua.globals().set("binchotan", create_lua_table(&lua)?)?;
+        Ok(lua)
+    }
+
+    #[test]
+    fn match_text() -> LuaResult<()> {
+        let lua = lua()?;
+        let results: Vec<bool> = lua
+            .load(
+                r#"
+                return {
+                    binchotan.contains("node.js", "e.j"),
+                    binchotan.contains("nodejs", "e.j"),
+                    binchotan.contains("Twitter", "twitter"),
+                    binchotan.contains("Twitter", "twitter", { ignore_case = true }),
+                    binchotan.contains("ＴＷＩＴＴＥＲ", "twitter", { ignore_case = true, normalize = true }),
+                    binchotan.contains("ついったー", "ツイッター", { normalize = true }),
+                    binchotan.contains_any("明日は大学", { "会社", "大学" }) == "大学",
+                    binchotan.contains_any("明日は休み", { "会社", "大学" }) == nil,
+                }
+                "#,
+            )
+            .eval()?;
+        assert_eq!(results, [true, false, false, true, true, true, true, true]);
+
+        let (whole, group): (String, String) = lua
+            .load(r#"local m = binchotan.regex("ver 1.23", [[(\d+)\.\d+]]) return m[1], m[2]"#)
+            .eval()?;
+        assert_eq!((whole.as_str(), group.as_str()), ("1.23", "1"));
+        assert!(lua.load(r#"binchotan.regex("a", "(")"#).exec().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn bound_regex_cache() -> Result<(), regex::Error> {
+        let mut cache = RegexCache::default();
+        for i in 0..REGEX_CACHE_SIZE * 3 {
+            assert!(cache.get(&format!("a{{{}}}", i))?.is_match(&"a".repeat(i)));
+        }
+        assert_eq!(cache.regexes.len(), REGEX_CACHE_SIZE);
+        assert_eq!(cache.patterns.len(), REGEX_CACHE_SIZE);
+        assert!(cache.get("(").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn extract_entities() -> LuaResult<()> {
+        let lua = lua()?;
+        let (urls, tags, mentions): (Vec<String>, Vec<String>, Vec<String>) = lua
+            .load(
+                r#"
+                local post = {
+                    entities = {
+                        urls = { { url = "https://t.co/x", expanded_url = "https://example.com" } },
+                        hashtags = { { tag = "rust" }, { tag = "lua" } },
+                    },
+                }
+                return binchotan.urls(post), binchotan.hashtags(post), binchotan.mentions(post)
+                "#,
+            )
+            .eval()?;
+        assert_eq!(urls, ["https://example.com"]);
+        assert_eq!(tags, ["rust", "lua"]);
+        assert!(mentions.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn parse_time_and_json() -> LuaResult<()> {
+        let lua = lua()?;
+        let (time, json, decoded): (f64, String, i64) = lua
+            .load(
+                r#"
+                return binchotan.parse_time("2022-10-25T12:00:00.500Z"),
+                    binchotan.json_encode({ a = { 1, 2 } }),
+                    binchotan.json_decode('{"b": [3, 4]}').b[2]
+                "#,
+            )
+            .eval()?;
+        assert_eq!(time, 1666699200.5);
+        assert_eq!(json, r#"{"a":[1,2]}"#);
+        assert_eq!(decoded, 4);
+
+        Ok(())
+    }
+}
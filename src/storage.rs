@@ -0,0 +1,260 @@
+use mlua::prelude::*;
+use sqlx::PgPool;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use thiserror::Error;
+
+use crate::models::Account;
+
+#[derive(Debug, Error)]
+pub enum StorageError {
+    #[error("database error: {0}")]
+    Database(#[from] sqlx::Error),
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    value: serde_json::Value,
+    // in epoch sec
+    expires_at: Option<i64>,
+}
+
+/// Key-value pairs which a filter keeps for an account. Filters read and write them in memory while they are running; the changes are written back to the database afterwards by `StorageStore::save`.
+#[derive(Debug, Default)]
+pub struct Storage {
+    entries: HashMap<String, Entry>,
+    // keys which have been set or deleted since loaded
+    dirty: HashSet<String>,
+}
+
+impl Storage {
+    pub fn get(&self, key: &str) -> Option<&serde_json::Value> {
+        let entry = self.entries.get(key)?;
+        match entry.expires_at {
+            Some(t) if t <= now() => None,
+            _ => Some(&entry.value),
+        }
+    }
+
+    /// Sets the value. If `ttl` (in seconds) is given, the value expires after that.
+    pub fn set(&mut self, key: String, value: serde_json::Value, ttl: Option<i64>) {
+        let expires_at = ttl.map(|ttl| now() + ttl);
+        self.dirty.insert(key.clone());
+        self.entries.insert(key, Entry { value, expires_at });
+    }
+
+    pub fn delete(&mut self, key: &str) {
+        if self.entries.remove(key).is_some() {
+            self.dirty.insert(key.to_owned());
+        }
+    }
+
+    /// Creates the `storage` table exposed to Lua scripts.
+    pub fn create_lua_table(lua: &Lua, storage: Arc<Mutex<Storage>>) -> LuaResult<LuaTable<'_>> {
+        let table = lua.create_table()?;
+
+        let s = storage.clone();
+        table.set(
+            "get",
+            lua.create_function(move |lua, key: String| {
+                let storage = s.lock().unwrap();
+                match storage.get(&key) {
+                    Some(value) => lua.to_value(value),
+                    None => Ok(LuaNil),
+                }
+            })?,
+        )?;
+
+        let s = storage.clone();
+        table.set(
+            "set",
+            lua.create_function(
+                move |lua, (key, value, ttl): (String, LuaValue, Option<i64>)| {
+                    if matches!(ttl, Some(ttl) if ttl <= 0) {
+                        return Err(LuaError::RuntimeError(
+                            "storage.set: ttl must be a positive number of seconds".into(),
+                        ));
+                    }
+                    let value: serde_json::Value = lua.from_value(value)?;
+                    s.lock().unwrap().set(key, value, ttl);
+                    Ok(())
+                },
+            )?,
+        )?;
+
+        table.set(
+            "delete",
+            lua.create_function(move |_, key: String| {
+                storage.lock().unwrap().delete(&key);
+                Ok(())
+            })?,
+        )?;
+
+        Ok(table)
+    }
+}
+
+/// Persists the storage of each filter in the database, scoped per filter and per account.
+pub struct StorageStore {
+    conn: Arc<PgPool>,
+}
+
+impl StorageStore {
+    pub fn new(conn: PgPool) -> Self {
+        Self {
+            conn: Arc::new(conn),
+        }
+    }
+
+    pub async fn load(&self, account: &Account, filter_id: &str) -> Result<Storage, StorageError> {
+        sqlx::query!(
+            r#"
+            delete from filter_storage
+                where account_id = $1 and filter_id = $2 and expires_at <= $3
+            "#,
+            account.id,
+            filter_id,
+            now()
+        )
+        .execute(self.conn.as_ref())
+        .await?;
+
+        let entries = sqlx::query!(
+            r#"
+            select key, value, expires_at from filter_storage
+                where account_id = $1 and filter_id = $2
+            "#,
+            account.id,
+            filter_id
+        )
+        .fetch_all(self.conn.as_ref())
+        .await?
+        .into_iter()
+        .map(|rec| {
+            let entry = Entry {
+                value: rec.value,
+                expires_at: rec.expires_at,
+            };
+            (rec.key, entry)
+        })
+        .collect();
+
+        Ok(Storage {
+            entries,
+            dirty: HashSet::new(),
+        })
+    }
+
+    /// Writes the keys changed since loaded back to the database.
+    pub async fn save(
+        &self,
+        account: &Account,
+        filter_id: &str,
+        storage: &mut Storage,
+    ) -> Result<(), StorageError> {
+        if storage.dirty.is_empty() {
+            return Ok(());
+        }
+
+        let mut tx = self.conn.begin().await?;
+        for key in storage.dirty.drain() {
+            match storage.entries.get(&key) {
+                Some(entry) => {
+                    sqlx::query!(
+                        r#"
+                        insert into filter_storage (account_id, filter_id, key, value, expires_at)
+                            values ($1, $2, $3, $4, $5)
+                            on conflict (account_id, filter_id, key) do
+                                update set value = $4, expires_at = $5
+                        "#,
+                        account.id,
+                        filter_id,
+                        key,
+                        entry.value,
+                        entry.expires_at
+                    )
+                    .execute(&mut tx)
+                    .await?;
+                }
+                None => {
+                    sqlx::query!(
+                        r#"
+                        delete from filter_storage
+                            where account_id = $1 and filter_id = $2 and key = $3
+                        "#,
+                        account.id,
+                        filter_id,
+                        key
+                    )
+                    .execute(&mut tx)
+                    .await?;
+                }
+            }
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+}
+
+fn now() -> i64 {
+    // SAFETY: the system clock should not be set before 1970
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hide_expired_values() {
+        let mut storage = Storage::default();
+        storage.set("fresh".into(), 1.into(), Some(60));
+        storage.set("permanent".into(), 2.into(), None);
+        storage.entries.insert(
+            "stale".into(),
+            Entry {
+                value: 3.into(),
+                expires_at: Some(now() - 1),
+            },
+        );
+
+        assert_eq!(storage.get("fresh"), Some(&1.into()));
+        assert_eq!(storage.get("permanent"), Some(&2.into()));
+        assert_eq!(storage.get("stale"), None);
+    }
+
+    #[test]
+    fn access_from_lua() -> LuaResult<()> {
+        let storage = Arc::new(Mutex::new(Storage::default()));
+        let lua = Lua::new();
+        let table = Storage::create_lua_table(&lua, storage.clone())?;
+        lua.globals().set("storage", table)?;
+
+        let count: i64 = lua
+            .load(
+                r#"
+                storage.set("count", (storage.get("count") or 0) + 1)
+                storage.set("count", storage.get("count") + 1, 3600)
+                storage.set("tmp", { "a" })
+                storage.delete("tmp")
+                return storage.get("count")
+                "#,
+            )
+            .eval()?;
+        assert_eq!(count, 2);
+
+        let storage = storage.lock().unwrap();
+        assert!(storage.get("tmp").is_none());
+        assert!(storage.dirty.contains("count"));
+        assert!(storage.dirty.contains("tmp"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,588 @@
+use serde::{Deserialize, Serialize};
+
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    filter_api,
+    tweet::{compare_ids, Tweet},
+};
+
+/// The kind of timeline which filters are applied to. Filters see this as `context.kind` so that they can behave differently, e.g. not muting words in search results.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TimelineKind {
+    #[default]
+    Home,
+    Search,
+    List,
+    User,
+    Mentions,
+    Likes,
+    Bookmarks,
+    Quotes,
+    /// Any other endpoint which returns tweets.
+    Other,
+}
+
+impl TimelineKind {
+    /// The query parameter which takes `meta.next_token` of a response to fetch the next page.
+    pub fn pagination_param(self) -> &'static str {
+        match self {
+            TimelineKind::Search => "next_token",
+            _ => "pagination_token",
+        }
+    }
+}
+
+/// Limits on fetching more pages to fill a response, set in the config.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct FillConfig {
+    /// The most pages fetched for a request.
+    pub max_pages: usize,
+    /// Stop fetching when the remaining API calls in the rate-limit window drop to this, leaving them for other requests.
+    pub reserved_calls: usize,
+}
+
+impl Default for FillConfig {
+    fn default() -> Self {
+        Self {
+            max_pages: 5,
+            reserved_calls: 10,
+        }
+    }
+}
+
+/// When to stop fetching pages for a request.
+#[derive(Debug, Clone, Copy)]
+pub struct Fill {
+    min_results: usize,
+    max_pages: usize,
+    reserved_calls: usize,
+}
+
+impl Fill {
+    /// Limits for a request asking for `min_results` posts kept by the filters, in up to `max_pages` pages. Without `min_results`, only one page is fetched.
+    /// At least one page is fetched even if `max_pages` is 0, either in the request or in the config.
+    pub fn new(config: &FillConfig, min_results: Option<usize>, max_pages: Option<usize>) -> Self {
+        let max_pages = match min_results {
+            Some(_) => max_pages
+                .unwrap_or(config.max_pages)
+                .min(config.max_pages)
+                .max(1),
+            None => 1,
+        };
+        Self {
+            min_results: min_results.unwrap_or_default(),
+            max_pages,
+            reserved_calls: config.reserved_calls,
+        }
+    }
+
+    /// Whether to fetch another page, after `pages` pages have been fetched and `kept` posts have been kept in total. `remaining` is the API calls remaining in the rate-limit window.
+    pub fn wants_more(&self, pages: usize, kept: usize, remaining: usize) -> bool {
+        kept < self.min_results && pages < self.max_pages && remaining > self.reserved_calls
+    }
+}
+
+/// `includes` and `meta` of the pages fetched for a response, merged as if they were a single page.
+#[derive(Debug, Default)]
+pub struct Pages {
+    pub includes: Option<serde_json::Value>,
+    pub meta: serde_json::Value,
+    /// The number of pages fetched so far.
+    pub count: usize,
+}
+
+impl Pages {
+    /// Merges the next page. `meta` keeps `newest_id` and `previous_token` of the first page, and takes `oldest_id` and `next_token` of the last page so that the next request continues after the pages consumed.
+    pub fn push(&mut self, includes: Option<serde_json::Value>, meta: serde_json::Value) {
+        self.count += 1;
+        merge_includes(&mut self.includes, includes);
+        if self.count == 1 {
+            self.meta = meta;
+            return;
+        }
+
+        let Some(merged) = self.meta.as_object_mut() else {
+            self.meta = meta;
+            return;
+        };
+        let count = merged
+            .get("result_count")
+            .and_then(|c| c.as_u64())
+            .unwrap_or_default()
+            + meta["result_count"].as_u64().unwrap_or_default();
+        merged.insert("result_count".into(), count.into());
+        for key in ["oldest_id", "next_token"] {
+            match meta.get(key) {
+                Some(value) => merged.insert(key.into(), value.clone()),
+                None => merged.remove(key),
+            };
+        }
+    }
+}
+
+/// Appends the objects in the arrays of `includes` (`users`, `tweets`, `media` and so on), skipping the ones already there.
+pub fn merge_includes(merged: &mut Option<serde_json::Value>, includes: Option<serde_json::Value>) {
+    let Some(includes) = includes else {
+        return;
+    };
+    let Some(fields) = merged.as_mut().and_then(|m| m.as_object_mut()) else {
+        *merged = Some(includes);
+        return;
+    };
+    let serde_json::Value::Object(includes) = includes else {
+        return;
+    };
+
+    let key = |item: &serde_json::Value| {
+        item.get("id")
+            .or_else(|| item.get("media_key"))
+            .cloned()
+            .unwrap_or_else(|| item.clone())
+    };
+    for (field, items) in includes {
+        let serde_json::Value::Array(items) = items else {
+            continue;
+        };
+        let existing = fields
+            .entry(field)
+            .or_insert_with(|| serde_json::Value::Array(vec![]));
+        let Some(existing) = existing.as_array_mut() else {
+            continue;
+        };
+        for item in items {
+            if !existing.iter().any(|e| key(e) == key(&item)) {
+                existing.push(item);
+            }
+        }
+    }
+}
+
+/// Merges the timelines of several accounts, keyed by the account ids, into one ordered from the newest.
+/// A post seen by several accounts appears once with `binchotan.accounts` listing them, preferring the one not dropped by the filters of the account.
+pub fn merge_timelines(timelines: Vec<(String, Vec<Tweet>)>) -> Vec<Tweet> {
+    let mut merged: Vec<Tweet> = vec![];
+    let mut positions: HashMap<String, usize> = HashMap::new();
+    let mut seen_by: HashMap<String, Vec<String>> = HashMap::new();
+    for (account, posts) in timelines {
+        for post in posts {
+            let Some(id) = post.id().map(String::from) else {
+                merged.push(post);
+                continue;
+            };
+            let accounts = seen_by.entry(id.clone()).or_default();
+            if !accounts.contains(&account) {
+                accounts.push(account.clone());
+            }
+            match positions.get(&id) {
+                Some(&i) => {
+                    if merged[i].is_dropped() && !post.is_dropped() {
+                        merged[i] = post;
+                    }
+                }
+                None => {
+                    positions.insert(id, merged.len());
+                    merged.push(post);
+                }
+            }
+        }
+    }
+
+    for post in &mut merged {
+        if let Some(accounts) = post.id().and_then(|id| seen_by.remove(id)) {
+            post.mark_seen_by(accounts);
+        }
+    }
+    merged.sort_by(|a, b| compare_ids(b.id().unwrap_or_default(), a.id().unwrap_or_default()));
+    merged
+}
+
+// Tokens made by the backend to resume a timeline of a merged timeline before a post, which are passed to the API as `until_id`.
+const UNTIL_PREFIX: &str = "until_id:";
+
+/// A page of the timeline of an account, which is merged with the pages of the other accounts.
+pub struct AccountPage {
+    pub account: String,
+    pub posts: Vec<Tweet>,
+    /// `meta.oldest_id` of the page, which the posts cover down to including the ones dropped by the filters.
+    pub oldest_id: Option<String>,
+    /// Whether the API has the next page.
+    pub has_next: bool,
+}
+
+/// A page of a merged timeline, and where each account continues from on the next page.
+pub struct MergedPage {
+    pub posts: Vec<Tweet>,
+    /// Tokens to fetch the next pages of the accounts, keyed by the account ids.
+    pub tokens: HashMap<String, String>,
+    /// The accounts which have no more posts. They should not be fetched again, as they would start from the newest post.
+    pub exhausted: HashSet<String>,
+    /// The token resuming at the end of the page, for the accounts whose page was not merged (e.g. failed to fetch).
+    pub cut: Option<String>,
+}
+
+/// Merges the pages of the accounts with `merge_timelines`, and returns the tokens to fetch the next pages of the accounts.
+/// The pages may cover different periods of time, so the merged page ends at the newest `oldest_id` among the accounts having more posts. The posts older than it are left for the next pages, and each account resumes from there.
+pub fn merge_account_pages(pages: Vec<AccountPage>) -> MergedPage {
+    let cut = pages
+        .iter()
+        .filter(|page| page.has_next)
+        .filter_map(|page| page.oldest_id.clone())
+        .max_by(|a, b| compare_ids(a, b));
+    let older = |id: Option<&str>| match (&cut, id) {
+        (Some(cut), Some(id)) => compare_ids(id, cut).is_lt(),
+        _ => false,
+    };
+    let cut = cut.as_ref().map(|cut| format!("{}{}", UNTIL_PREFIX, cut));
+
+    let mut tokens = HashMap::new();
+    let mut exhausted = HashSet::new();
+    let mut timelines = vec![];
+    for page in pages {
+        let mut posts = page.posts;
+        posts.retain(|post| !older(post.id()));
+        match &cut {
+            Some(cut) if page.has_next || older(page.oldest_id.as_deref()) => {
+                tokens.insert(page.account.clone(), cut.clone());
+            }
+            _ if !page.has_next => {
+                exhausted.insert(page.account.clone());
+            }
+            _ => {}
+        }
+        timelines.push((page.account, posts));
+    }
+
+    MergedPage {
+        posts: merge_timelines(timelines),
+        tokens,
+        exhausted,
+        cut,
+    }
+}
+
+/// The token for an account which has no token in `tokens` given for the next page, e.g. added to the accounts after the previous page. It starts where the previous page ended, as the newer posts have been passed.
+pub fn start_token(tokens: &HashMap<String, String>) -> Option<String> {
+    tokens
+        .values()
+        .filter_map(|token| token.strip_prefix(UNTIL_PREFIX))
+        .max_by(|a, b| compare_ids(a, b))
+        .map(|cut| format!("{}{}", UNTIL_PREFIX, cut))
+}
+
+/// The parameter and its value which fetch the next page with a token returned by `merge_account_pages`, or by the API.
+pub fn resume_param(token: &str) -> (&'static str, &str) {
+    match token.strip_prefix(UNTIL_PREFIX) {
+        Some(id) => ("until_id", id),
+        None => ("pagination_token", token),
+    }
+}
+
+/// The endpoint of the home timeline.
+pub const HOME_ENDPOINT: &str = "users/:id/timelines/reverse_chronological";
+
+/// Endpoints returning tweets, along with the kinds of the timelines. `:x` matches any path segment.
+const ENDPOINTS: &[(&str, TimelineKind)] = &[
+    (HOME_ENDPOINT, TimelineKind::Home),
+    ("tweets/search/recent", TimelineKind::Search),
+    ("tweets/search/all", TimelineKind::Search),
+    ("lists/:id/tweets", TimelineKind::List),
+    ("users/:id/tweets", TimelineKind::User),
+    ("users/:id/mentions", TimelineKind::Mentions),
+    ("users/:id/liked_tweets", TimelineKind::Likes),
+    ("users/:id/bookmarks", TimelineKind::Bookmarks),
+    ("tweets/:id/quote_tweets", TimelineKind::Quotes),
+];
+
+/// What is being filtered, exposed to filters as `context`.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct TimelineContext {
+    pub kind: TimelineKind,
+    /// The endpoint the posts were fetched from, if any.
+    pub endpoint: Option<String>,
+}
+
+impl TimelineContext {
+    pub fn new(kind: TimelineKind) -> Self {
+        Self {
+            kind,
+            endpoint: None,
+        }
+    }
+
+    /// Tells the kind of the timeline from the endpoint path.
+    pub fn for_endpoint(endpoint: &str) -> Self {
+        let kind = ENDPOINTS
+            .iter()
+            .find(|(pattern, _)| filter_api::path_matches(pattern, endpoint))
+            .map(|(_, kind)| *kind)
+            .unwrap_or(TimelineKind::Other);
+        Self {
+            kind,
+            endpoint: Some(endpoint.trim_matches('/').to_owned()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tell_kinds_from_endpoints() {
+        let kind = |endpoint| TimelineContext::for_endpoint(endpoint).kind;
+        assert_eq!(kind("tweets/search/recent"), TimelineKind::Search);
+        assert_eq!(kind("/lists/123/tweets"), TimelineKind::List);
+        assert_eq!(kind("users/:id/mentions"), TimelineKind::Mentions);
+        assert_eq!(kind("users/123/bookmarks"), TimelineKind::Bookmarks);
+        assert_eq!(kind("tweets"), TimelineKind::Other);
+    }
+
+    // runs the fill loop over pages keeping the given numbers of posts, with plenty of API calls left unless `remaining` is given
+    fn fill(fill: Fill, kept: &[usize], remaining: Option<usize>) -> usize {
+        let (mut pages, mut total) = (0, 0);
+        for kept in kept {
+            pages += 1;
+            total += kept;
+            if !fill.wants_more(pages, total, remaining.unwrap_or(100)) {
+                break;
+            }
+        }
+        pages
+    }
+
+    #[test]
+    fn stop_filling_at_limits() {
+        let config = FillConfig::default();
+        let kept = [3, 0, 4, 5, 2, 8, 1];
+        assert_eq!(fill(Fill::new(&config, None, Some(3)), &kept, None), 1);
+        assert_eq!(fill(Fill::new(&config, Some(7), None), &kept, None), 3);
+        assert_eq!(fill(Fill::new(&config, Some(100), None), &kept, None), 5);
+        assert_eq!(fill(Fill::new(&config, Some(100), Some(2)), &kept, None), 2);
+        assert_eq!(
+            fill(Fill::new(&config, Some(100), Some(50)), &kept, None),
+            5
+        );
+        assert_eq!(
+            fill(Fill::new(&config, Some(100), None), &kept, Some(10)),
+            1
+        );
+
+        // a page is fetched even when no pages are allowed
+        assert_eq!(fill(Fill::new(&config, Some(100), Some(0)), &kept, None), 1);
+        let config = FillConfig {
+            max_pages: 0,
+            ..config
+        };
+        assert_eq!(fill(Fill::new(&config, Some(100), None), &kept, None), 1);
+        assert_eq!(fill(Fill::new(&config, Some(100), Some(3)), &kept, None), 1);
+    }
+
+    #[test]
+    fn merge_account_pages_overlapping_in_time() {
+        let page = |account: &str, ids: &[&str], has_next| AccountPage {
+            account: account.to_owned(),
+            posts: ids
+                .iter()
+                .map(|id| serde_json::from_value(serde_json::json!({ "id": id })).unwrap())
+                .collect(),
+            oldest_id: ids.last().map(|id| id.to_string()),
+            has_next,
+        };
+        let ids = |posts: &[Tweet]| -> Vec<String> {
+            posts
+                .iter()
+                .filter_map(|t| t.id())
+                .map(String::from)
+                .collect()
+        };
+
+        // alice follows few accounts, so her page goes back further than bob's
+        let first = merge_account_pages(vec![
+            page("alice", &["1000", "700", "500"], true),
+            page("bob", &["990", "950", "900"], true),
+        ]);
+        assert_eq!(ids(&first.posts), ["1000", "990", "950", "900"]);
+        assert_eq!(resume_param(&first.tokens["alice"]), ("until_id", "900"));
+        assert_eq!(resume_param(&first.tokens["bob"]), ("until_id", "900"));
+        assert_eq!(first.cut.as_deref(), Some("until_id:900"));
+
+        // the next pages start from there, so no post comes out of order or is skipped
+        let second = merge_account_pages(vec![
+            page("alice", &["700", "500", "300"], false),
+            page("bob", &["880", "600"], false),
+        ]);
+        assert_eq!(ids(&second.posts), ["880", "700", "600", "500", "300"]);
+        assert!(second.tokens.is_empty());
+        assert_eq!(second.exhausted.len(), 2);
+        assert_eq!(second.cut, None);
+
+        // an account with no more posts is cut as well
+        let merged = merge_account_pages(vec![
+            page("alice", &["1000", "700"], false),
+            page("bob", &["990", "900"], true),
+        ]);
+        assert_eq!(resume_param(&merged.tokens["alice"]), ("until_id", "900"));
+        assert_eq!(resume_param(&merged.tokens["bob"]), ("until_id", "900"));
+        let merged = merge_account_pages(vec![
+            page("alice", &["1000", "950"], false),
+            page("bob", &["990", "900"], true),
+        ]);
+        assert!(!merged.tokens.contains_key("alice"));
+        assert!(merged.exhausted.contains("alice"));
+
+        assert_eq!(resume_param("7140dibdnow9c7btw423x").0, "pagination_token");
+    }
+
+    #[test]
+    fn merge_account_pages_until_exhausted() {
+        // the home timelines of the accounts, newest first, and the number of posts in a page
+        let timelines: HashMap<&str, Vec<&str>> = HashMap::from([
+            ("alice", vec!["1000", "700", "500", "300", "250", "200"]),
+            ("bob", vec!["990", "950", "900", "880", "600"]),
+        ]);
+        let fetch = |account: &str, token: Option<&String>| {
+            let posts: Vec<&str> = timelines[account]
+                .iter()
+                .filter(|id| match token.map(|t| resume_param(t)) {
+                    Some(("until_id", until)) => compare_ids(id, until).is_lt(),
+                    _ => true,
+                })
+                .copied()
+                .collect();
+            let has_next = posts.len() > 3;
+            let posts = &posts[..posts.len().min(3)];
+            AccountPage {
+                account: account.to_owned(),
+                posts: posts
+                    .iter()
+                    .map(|id| serde_json::from_value(serde_json::json!({ "id": id })).unwrap())
+                    .collect(),
+                oldest_id: posts.last().map(|id| id.to_string()),
+                has_next,
+            }
+        };
+        // what a frontend does: passes the tokens back, and leaves out the accounts marked as exhausted
+        let mut tokens: HashMap<String, String> = HashMap::new();
+        let mut exhausted: HashSet<String> = HashSet::new();
+        let mut calls = vec![];
+        for _ in 0..3 {
+            let pages = ["alice", "bob"]
+                .into_iter()
+                .filter(|account| !exhausted.contains(*account))
+                .map(|account| fetch(account, tokens.get(account)))
+                .collect();
+            let page = merge_account_pages(pages);
+            tokens = page.tokens;
+            exhausted.extend(page.exhausted);
+            calls.push(
+                page.posts
+                    .iter()
+                    .filter_map(|t| t.id())
+                    .map(String::from)
+                    .collect::<Vec<_>>(),
+            );
+        }
+
+        assert_eq!(calls[0], ["1000", "990", "950", "900"]);
+        // bob runs out on the second page, and is not fetched from his newest post again
+        assert_eq!(calls[1], ["880", "700", "600", "500", "300"]);
+        assert_eq!(calls[2], ["250", "200"]);
+        assert!(tokens.is_empty());
+        assert_eq!(exhausted.len(), 2);
+    }
+
+    #[test]
+    fn start_added_accounts_at_the_cut() {
+        let tokens = HashMap::from([
+            ("alice".to_owned(), "until_id:900".to_owned()),
+            ("bob".to_owned(), "until_id:1200".to_owned()),
+            ("carol".to_owned(), "7140dibdnow9c7btw423x".to_owned()),
+        ]);
+        assert_eq!(start_token(&tokens).as_deref(), Some("until_id:1200"));
+        assert_eq!(start_token(&HashMap::new()), None);
+    }
+
+    #[test]
+    fn merge_timelines_of_accounts() {
+        let post = |value: serde_json::Value| -> Tweet { serde_json::from_value(value).unwrap() };
+        let alice = vec![
+            post(serde_json::json!({ "id": "120", "text": "both" })),
+            post(serde_json::json!({ "id": "99", "text": "alice" })),
+        ];
+        let bob = vec![
+            post(serde_json::json!({ "id": "1000", "text": "bob" })),
+            post(serde_json::json!({
+                "id": "120",
+                "text": "both",
+                "binchotan": { "dropped": { "filter": "mute_word", "reason": null } }
+            })),
+        ];
+        let carol = vec![post(serde_json::json!({
+            "id": "110",
+            "text": "carol",
+            "binchotan": { "dropped": { "filter": "mute_word", "reason": null } }
+        }))];
+
+        let merged = merge_timelines(vec![
+            ("bob".into(), bob),
+            ("alice".into(), alice),
+            ("carol".into(), carol),
+        ]);
+        let ids: Vec<_> = merged.iter().filter_map(|t| t.id()).collect();
+        assert_eq!(ids, ["1000", "120", "110", "99"]);
+        // the one kept by the filters of alice wins
+        assert!(!merged[1].is_dropped());
+        assert_eq!(
+            merged[1].as_value()["binchotan"]["accounts"],
+            serde_json::json!(["bob", "alice"])
+        );
+        assert!(merged[2].is_dropped());
+        assert_eq!(
+            merged[3].as_value()["binchotan"]["accounts"],
+            serde_json::json!(["alice"])
+        );
+    }
+
+    #[test]
+    fn merge_pages() {
+        let mut pages = Pages::default();
+        pages.push(
+            Some(serde_json::json!({ "users": [{ "id": "1" }, { "id": "2" }] })),
+            serde_json::json!({
+                "result_count": 2,
+                "newest_id": "20",
+                "oldest_id": "19",
+                "next_token": "a",
+                "previous_token": "z"
+            }),
+        );
+        pages.push(
+            Some(serde_json::json!({
+                "users": [{ "id": "2" }, { "id": "3" }],
+                "media": [{ "media_key": "3_1" }]
+            })),
+            serde_json::json!({ "result_count": 1, "newest_id": "18", "oldest_id": "18" }),
+        );
+
+        assert_eq!(pages.count, 2);
+        assert_eq!(
+            pages.includes,
+            Some(serde_json::json!({
+                "users": [{ "id": "1" }, { "id": "2" }, { "id": "3" }],
+                "media": [{ "media_key": "3_1" }]
+            }))
+        );
+        assert_eq!(
+            pages.meta,
+            serde_json::json!({
+                "result_count": 3,
+                "newest_id": "20",
+                "oldest_id": "18",
+                "previous_token": "z"
+            })
+        );
+    }
+}
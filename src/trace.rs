@@ -0,0 +1,134 @@
+use serde::Serialize;
+
+/// What a filter did to a post.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StepResult {
+    Kept,
+    Changed,
+    Dropped,
+    Failed,
+}
+
+/// A record of applying a filter on a post, used to explain why a post has been changed or dropped.
+#[derive(Debug, Clone, Serialize)]
+pub struct TraceStep {
+    pub filter: String,
+    pub result: StepResult,
+    // changes made by the filter
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub diff: Vec<DiffOp>,
+    // the reason given by the filter when dropped, or the error message when failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    // for batch scripts, the time taken for the whole list
+    pub elapsed_us: u64,
+    // lines printed by the script with `print`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub output: Vec<String>,
+}
+
+/// An operation in the manner of JSON Patch (RFC 6902).
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum DiffOp {
+    Add {
+        path: String,
+        value: serde_json::Value,
+    },
+    Remove {
+        path: String,
+    },
+    Replace {
+        path: String,
+        value: serde_json::Value,
+    },
+}
+
+/// Returns the operations which turn `before` into `after`. Arrays of different lengths are replaced as a whole.
+pub fn diff(before: &serde_json::Value, after: &serde_json::Value) -> Vec<DiffOp> {
+    let mut ops = vec![];
+    diff_inner("", before, after, &mut ops);
+    ops
+}
+
+fn diff_inner(
+    path: &str,
+    before: &serde_json::Value,
+    after: &serde_json::Value,
+    ops: &mut Vec<DiffOp>,
+) {
+    use serde_json::Value;
+
+    match (before, after) {
+        (Value::Object(b), Value::Object(a)) => {
+            for (key, bv) in b {
+                let child = format!("{}/{}", path, escape(key));
+                match a.get(key) {
+                    Some(av) => diff_inner(&child, bv, av, ops),
+                    None => ops.push(DiffOp::Remove { path: child }),
+                }
+            }
+            for (key, av) in a {
+                if !b.contains_key(key) {
+                    ops.push(DiffOp::Add {
+                        path: format!("{}/{}", path, escape(key)),
+                        value: av.clone(),
+                    });
+                }
+            }
+        }
+        (Value::Array(b), Value::Array(a)) if b.len() == a.len() => {
+            for (i, (bv, av)) in b.iter().zip(a).enumerate() {
+                diff_inner(&format!("{}/{}", path, i), bv, av, ops);
+            }
+        }
+        (b, a) if b != a => ops.push(DiffOp::Replace {
+            path: path.to_owned(),
+            value: a.clone(),
+        }),
+        _ => {}
+    }
+}
+
+// escapes a key as a JSON Pointer (RFC 6901) reference token
+fn escape(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn diff_objects() {
+        let before = json!({ "text": "foo", "lang": "ja", "a/b": [1, 2], "tags": [1] });
+        let after = json!({ "text": "bar", "a/b": [1, 3], "tags": [1, 2], "new": true });
+        assert_eq!(
+            diff(&before, &after),
+            vec![
+                DiffOp::Replace {
+                    path: "/a~1b/1".into(),
+                    value: json!(3)
+                },
+                DiffOp::Remove {
+                    path: "/lang".into()
+                },
+                DiffOp::Replace {
+                    path: "/tags".into(),
+                    value: json!([1, 2])
+                },
+                DiffOp::Replace {
+                    path: "/text".into(),
+                    value: json!("bar")
+                },
+                DiffOp::Add {
+                    path: "/new".into(),
+                    value: json!(true)
+                },
+            ]
+        );
+        assert!(diff(&before, &before).is_empty());
+    }
+}
@@ -1,5 +1,238 @@
 use serde::{Deserialize, Serialize};
+use std::{cmp::Ordering, collections::HashMap};
 
-#[derive(Serialize, Deserialize, Debug)]
+use crate::{annotation::Annotation, timeline::TimelineContext};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(transparent)]
 pub struct Tweet(serde_json::Value);
+
+impl Tweet {
+    pub fn id(&self) -> Option<&str> {
+        self.0["id"].as_str()
+    }
+
+    pub fn as_value(&self) -> &serde_json::Value {
+        &self.0
+    }
+
+    /// Replaces the `binchotan` field with that of `original`. The field is written only by the backend, so whatever a filter has put there is discarded.
+    pub fn restore_namespace(&mut self, original: &Tweet) {
+        let Some(obj) = self.0.as_object_mut() else {
+            return;
+        };
+        match original.0.get(NAMESPACE) {
+            Some(value) => obj.insert(NAMESPACE.into(), value.clone()),
+            None => obj.remove(NAMESPACE),
+        };
+    }
+
+    pub fn clear_namespace(&mut self) {
+        if let Some(obj) = self.0.as_object_mut() {
+            obj.remove(NAMESPACE);
+        }
+    }
+
+    /// Attaches the annotation given by the filter under `binchotan.annotations`.
+    pub fn annotate(&mut self, filter_id: &str, annotation: Annotation) {
+        if let Some(ns) = self.namespace_mut() {
+            // SAFETY: Annotation consists of serializable fields only
+            ns["annotations"][filter_id] = serde_json::to_value(annotation).unwrap();
+        }
+    }
+
+    /// Marks the post as dropped by the filter, so that frontends can tell why it is hidden.
+    pub fn mark_dropped(&mut self, filter_id: &str, reason: Option<String>) {
+        if let Some(ns) = self.namespace_mut() {
+            ns["dropped"] = serde_json::json!({ "filter": filter_id, "reason": reason });
+        }
+    }
+
+    /// Lists the accounts whose timelines contained the post under `binchotan.accounts`.
+    pub fn mark_seen_by(&mut self, accounts: Vec<String>) {
+        if let Some(ns) = self.namespace_mut() {
+            ns["accounts"] = accounts.into();
+        }
+    }
+
+    /// Whether the post has been marked as dropped by a filter.
+    pub fn is_dropped(&self) -> bool {
+        !self.0[NAMESPACE]["dropped"].is_null()
+    }
+
+    fn namespace_mut(&mut self) -> Option<&mut serde_json::Value> {
+        let obj = self.0.as_object_mut()?;
+        let ns = obj
+            .entry(NAMESPACE)
+            .or_insert_with(|| serde_json::json!({}));
+        if !ns.is_object() {
+            *ns = serde_json::json!({});
+        }
+        Some(ns)
+    }
+
+    /// Removes the fields added by `Page::hydrate`.
+    pub fn dehydrate(&mut self) {
+        if let Some(obj) = self.0.as_object_mut() {
+            for key in HYDRATED_KEYS {
+                obj.remove(*key);
+            }
+        }
+    }
+}
+
+/// Compares post ids, which are numbers too large for JSON numbers, as strings of digits. Newer posts have larger ids.
+pub fn compare_ids(a: &str, b: &str) -> Ordering {
+    a.len().cmp(&b.len()).then_with(|| a.cmp(b))
+}
+
+// The field in which the backend puts its own data, such as annotations.
+const NAMESPACE: &str = "binchotan";
+
+// Fields added to tweets given to filters. Twitter API v2 does not use these names for tweets.
+const HYDRATED_KEYS: &[&str] = &["author", "media", "referenced"];
+
+/// `includes` and `meta` of a response, which the tweets in `data` refer to, and what kind of timeline the response is.
+pub struct Page<'a> {
+    pub includes: Option<&'a serde_json::Value>,
+    pub meta: &'a serde_json::Value,
+    pub context: TimelineContext,
+    users: HashMap<&'a str, &'a serde_json::Value>,
+    media: HashMap<&'a str, &'a serde_json::Value>,
+    tweets: HashMap<&'a str, &'a serde_json::Value>,
+}
+
+impl<'a> Page<'a> {
+    pub fn new(includes: Option<&'a serde_json::Value>, meta: &'a serde_json::Value) -> Self {
+        let index = |kind: &str, key: &str| -> HashMap<&'a str, &'a serde_json::Value> {
+            includes
+                .and_then(|includes| includes[kind].as_array())
+                .map(|items| {
+                    items
+                        .iter()
+                        .filter_map(|item| Some((item[key].as_str()?, item)))
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+
+        Self {
+            includes,
+            meta,
+            context: TimelineContext::default(),
+            users: index("users", "id"),
+            media: index("media", "media_key"),
+            tweets: index("tweets", "id"),
+        }
+    }
+
+    pub fn with_context(mut self, context: TimelineContext) -> Self {
+        self.context = context;
+        self
+    }
+
+    /// Returns the tweet with its author (`author`), attached media (`media`) and referenced tweets (`referenced`) resolved from `includes`.
+    pub fn hydrate(&self, tweet: &Tweet) -> Tweet {
+        let mut hydrated = self.hydrate_value(&tweet.0);
+        if let Some(obj) = hydrated.as_object_mut() {
+            let referenced = tweet.0["referenced_tweets"]
+                .as_array()
+                .map(|refs| {
+                    refs.iter()
+                        .map(|r| {
+                            let mut referenced = serde_json::json!({
+                                "type": r["type"],
+                                "id": r["id"],
+                            });
+                            // left out rather than null so that it is nil in Lua
+                            if let Some(tweet) = r["id"].as_str().and_then(|id| self.tweets.get(id))
+                            {
+                                referenced["tweet"] = self.hydrate_value(tweet);
+                            }
+                            referenced
+                        })
+                        .collect()
+                })
+                .unwrap_or_default();
+            obj.insert("referenced".into(), serde_json::Value::Array(referenced));
+        }
+
+        Tweet(hydrated)
+    }
+
+    fn hydrate_value(&self, tweet: &serde_json::Value) -> serde_json::Value {
+        let mut hydrated = tweet.clone();
+        let Some(obj) = hydrated.as_object_mut() else {
+            return hydrated;
+        };
+
+        // a null author would be a truthy userdata in Lua, so the field is left out instead
+        match tweet["author_id"]
+            .as_str()
+            .and_then(|id| self.users.get(id))
+        {
+            Some(user) => obj.insert("author".into(), (*user).clone()),
+            None => obj.remove("author"),
+        };
+
+        let media = tweet["attachments"]["media_keys"]
+            .as_array()
+            .map(|keys| {
+                keys.iter()
+                    .filter_map(|key| self.media.get(key.as_str()?))
+                    .map(|media| (*media).clone())
+                    .collect()
+            })
+            .unwrap_or_default();
+        obj.insert("media".into(), serde_json::Value::Array(media));
+
+        hydrated
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn hydrate_and_dehydrate() {
+        let includes = json!({
+            "users": [
+                { "id": "10", "username": "alice" },
+                { "id": "20", "username": "bob" }
+            ],
+            "media": [{ "media_key": "3_1", "type": "photo" }],
+            "tweets": [{ "id": "2", "text": "quoted", "author_id": "20" }]
+        });
+        let meta = json!({ "result_count": 1 });
+        let original = json!({
+            "id": "1",
+            "text": "hello",
+            "author_id": "10",
+            "attachments": { "media_keys": ["3_1", "3_2"] },
+            "referenced_tweets": [
+                { "type": "quoted", "id": "2" },
+                { "type": "replied_to", "id": "3" }
+            ]
+        });
+        let tweet = Tweet(original.clone());
+
+        let page = Page::new(Some(&includes), &meta);
+        let mut hydrated = page.hydrate(&tweet);
+        assert_eq!(hydrated.0["author"]["username"], "alice");
+        assert_eq!(
+            hydrated.0["media"],
+            json!([{ "media_key": "3_1", "type": "photo" }])
+        );
+        assert_eq!(hydrated.0["referenced"][0]["type"], "quoted");
+        assert_eq!(
+            hydrated.0["referenced"][0]["tweet"]["author"]["username"],
+            "bob"
+        );
+        assert!(hydrated.0["referenced"][1].get("tweet").is_none());
+
+        hydrated.dehydrate();
+        assert_eq!(hydrated.0, original);
+    }
+}
@@ -0,0 +1,274 @@
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+use thiserror::Error;
+use wasmi::{
+    Caller, Config, Engine, Extern, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder,
+};
+
+use crate::{
+    filter::{Filter, FilterError, FilterRuntime, Outcome, Worker},
+    filter_api::{self, FilterApi},
+    tweet::{Page, Tweet},
+};
+
+/// Fuel given to each call of a module. Most instructions consume one unit.
+const FUEL: u64 = 100_000_000;
+
+/// The maximum size of the linear memory of a module, in bytes.
+const MEMORY_LIMIT: usize = 64 * 1024 * 1024;
+
+/// The module name of the functions provided to modules.
+const IMPORT_MODULE: &str = "binchotan";
+
+#[derive(Debug, Error)]
+pub enum WasmError {
+    #[error("the module does not export `{0}`")]
+    MissingExport(&'static str),
+    #[error("the module returned an invalid result: {0}")]
+    InvalidResult(String),
+    #[error("out of bounds memory access")]
+    OutOfBounds,
+    #[error(transparent)]
+    Runtime(#[from] wasmi::Error),
+}
+
+/// What the module decided for the post, as returned by `filter` in JSON.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+enum Verdict {
+    Keep,
+    Drop {
+        reason: Option<String>,
+    },
+    /// Replace the fields of the post with these.
+    Patch {
+        patch: serde_json::Map<String, serde_json::Value>,
+    },
+}
+
+// Data available to the functions provided to the module.
+struct State {
+    limits: StoreLimits,
+    api: Option<FilterApi>,
+    scopes: HashSet<String>,
+    output: Arc<Mutex<Vec<String>>>,
+}
+
+/// A WebAssembly module without WASI. The module exports `memory`, `alloc(len: i32) -> i32` and `filter(ptr: i32, len: i32) -> i64`.
+/// `filter` receives `{ "post": ..., "config": ..., "context": ... }` in JSON, written to the memory allocated with `alloc`, and returns the pointer and the length of the JSON of a `Verdict`, in the upper and the lower 32 bits respectively.
+/// Each call runs in a new instance with limited fuel and memory.
+#[derive(Debug)]
+pub struct WasmRuntime {
+    engine: Engine,
+    module: Module,
+}
+
+impl WasmRuntime {
+    /// Compiles the module, and ensures that it exports what the ABI requires.
+    pub fn new(wasm: &[u8]) -> Result<Self, WasmError> {
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config);
+        let module = Module::new(&engine, wasm)?;
+        for name in ["memory", "alloc", "filter"] {
+            if module.get_export(name).is_none() {
+                return Err(WasmError::MissingExport(name));
+            }
+        }
+
+        Ok(Self { engine, module })
+    }
+
+    // Instantiates the module, and calls `filter` with the input. Returns the output of `filter`.
+    fn call(
+        &self,
+        filter: &Filter,
+        input: &[u8],
+        output: Arc<Mutex<Vec<String>>>,
+    ) -> Result<Vec<u8>, WasmError> {
+        let state = State {
+            limits: StoreLimitsBuilder::new()
+                .memory_size(MEMORY_LIMIT)
+                .instances(1)
+                .build(),
+            api: filter.api.clone(),
+            scopes: filter.meta.scopes.clone(),
+            output,
+        };
+        let mut store = Store::new(&self.engine, state);
+        store.limiter(|state| &mut state.limits);
+        // SAFETY: fuel is enabled in the config
+        store.set_fuel(FUEL).unwrap();
+
+        let instance = Self::linker(&self.engine)?
+            .instantiate(&mut store, &self.module)?
+            .start(&mut store)?;
+        let memory = instance
+            .get_memory(&store, "memory")
+            .ok_or(WasmError::MissingExport("memory"))?;
+        let alloc = instance.get_typed_func::<i32, i32>(&store, "alloc")?;
+        let run = instance.get_typed_func::<(i32, i32), i64>(&store, "filter")?;
+
+        let ptr = alloc.call(&mut store, input.len() as i32)?;
+        memory
+            .write(&mut store, ptr as u32 as usize, input)
+            .map_err(|_| WasmError::OutOfBounds)?;
+        let ret = run.call(&mut store, (ptr, input.len() as i32))?;
+
+        let output = read(&memory, &store, (ret >> 32) as i32, ret as i32)?;
+        Ok(output.to_vec())
+    }
+
+    fn linker(engine: &Engine) -> Result<Linker<State>, wasmi::Error> {
+        let mut linker = Linker::new(engine);
+
+        // log(ptr, len): prints a line, as `print` does in Lua
+        linker.func_wrap(
+            IMPORT_MODULE,
+            "log",
+            |caller: Caller<State>, ptr: i32, len: i32| -> Result<(), wasmi::Error> {
+                let memory = exported_memory(&caller)?;
+                let line = String::from_utf8_lossy(read(&memory, &caller, ptr, len)?).into_owned();
+                caller.data().output.lock().unwrap().push(line);
+                Ok(())
+            },
+        )?;
+
+        // api_get(ptr, len) -> i64: calls a GET endpoint as `api.get` does in Lua.
+        // The request is `{ "path": ..., "params": ... }` and the response is either `{ "ok": ... }` or `{ "error": ... }`, returned in the same way as `filter`.
+        linker.func_wrap(
+            IMPORT_MODULE,
+            "api_get",
+            |mut caller: Caller<State>, ptr: i32, len: i32| -> Result<i64, wasmi::Error> {
+                let memory = exported_memory(&caller)?;
+                let request = read(&memory, &caller, ptr, len)?;
+                let response = match api_get(caller.data(), request) {
+                    Ok(value) => serde_json::json!({ "ok": value }),
+                    Err(err) => serde_json::json!({ "error": err }),
+                };
+                // SAFETY: the response consists of JSON values
+                let response = serde_json::to_vec(&response).unwrap();
+
+                let alloc = caller
+                    .get_export("alloc")
+                    .and_then(Extern::into_func)
+                    .ok_or(WasmError::MissingExport("alloc"))?
+                    .typed::<i32, i32>(&caller)?;
+                let ptr = alloc.call(&mut caller, response.len() as i32)?;
+                memory
+                    .write(&mut caller, ptr as u32 as usize, &response)
+                    .map_err(|_| WasmError::OutOfBounds)?;
+                Ok(((ptr as u32 as i64) << 32) | response.len() as i64)
+            },
+        )?;
+
+        Ok(linker)
+    }
+}
+
+impl FilterRuntime for WasmRuntime {
+    fn per_post(&self) -> bool {
+        true
+    }
+
+    fn run(
+        &self,
+        filter: &Filter,
+        tweet: &Tweet,
+        page: &Page,
+        worker: &mut Worker,
+    ) -> Result<Outcome, FilterError> {
+        let input = serde_json::json!({
+            "post": page.hydrate(tweet),
+            "config": filter.settings,
+            "context": page.context,
+        });
+        // SAFETY: the input consists of JSON values
+        let output = self.call(
+            filter,
+            &serde_json::to_vec(&input).unwrap(),
+            worker.output(),
+        )?;
+        let verdict: Verdict = serde_json::from_slice(&output)
+            .map_err(|err| WasmError::InvalidResult(err.to_string()))?;
+
+        Ok(match verdict {
+            Verdict::Keep => Outcome::Keep(tweet.clone()),
+            Verdict::Drop { reason } => Outcome::Drop(reason),
+            Verdict::Patch { patch } => {
+                let mut value = tweet.as_value().clone();
+                if let Some(fields) = value.as_object_mut() {
+                    fields.extend(patch);
+                }
+                let mut t: Tweet = serde_json::from_value(value)
+                    .map_err(|err| WasmError::InvalidResult(err.to_string()))?;
+                filter.finish(&mut t, Some(tweet), None);
+                Outcome::Keep(t)
+            }
+        })
+    }
+}
+
+impl From<WasmError> for wasmi::Error {
+    fn from(err: WasmError) -> Self {
+        match err {
+            WasmError::Runtime(err) => err,
+            err => wasmi::Error::new(err.to_string()),
+        }
+    }
+}
+
+fn exported_memory(caller: &Caller<State>) -> Result<Memory, WasmError> {
+    caller
+        .get_export("memory")
+        .and_then(Extern::into_memory)
+        .ok_or(WasmError::MissingExport("memory"))
+}
+
+fn read<'a>(
+    memory: &Memory,
+    store: impl Into<wasmi::StoreContext<'a, State>>,
+    ptr: i32,
+    len: i32,
+) -> Result<&'a [u8], WasmError> {
+    let (ptr, len) = (ptr as u32 as usize, len as u32 as usize);
+    memory
+        .data(store)
+        .get(ptr..ptr + len)
+        .ok_or(WasmError::OutOfBounds)
+}
+
+fn api_get(state: &State, request: &[u8]) -> Result<serde_json::Value, String> {
+    #[derive(Deserialize)]
+    struct Request {
+        path: String,
+        #[serde(default)]
+        params: HashMap<String, serde_json::Value>,
+    }
+
+    let request: Request = serde_json::from_slice(request).map_err(|err| err.to_string())?;
+    let params = request
+        .params
+        .into_iter()
+        .map(|(key, value)| match value {
+            serde_json::Value::String(s) => Ok((key, s)),
+            serde_json::Value::Number(_) | serde_json::Value::Bool(_) => {
+                Ok((key, value.to_string()))
+            }
+            _ => Err(format!(
+                "parameter `{}` must be a string, a number or a boolean",
+                key
+            )),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    filter_api::authorize(&state.scopes, &request.path).map_err(|err| err.to_string())?;
+    let api = state
+        .api
+        .as_ref()
+        .ok_or_else(|| "the API is not available here".to_owned())?;
+    api.get(&state.scopes, &request.path, params)
+        .map_err(|err| err.to_string())
+}